@@ -1,13 +1,19 @@
 use std::io::Result;
 
 fn main() -> Result<()> {
-    prost_build::compile_protos(
-        &[
-            "proto/common.proto",
-            "proto/client.proto",
-            "proto/server.proto",
-        ],
-        &["proto/"],
-    )?;
+    prost_build::Config::new()
+        // Relayed game payloads are shared between recipients instead of copied
+        .bytes([
+            ".game.client.GameMessage.payload",
+            ".game.server.GameMessage.payload",
+        ])
+        .compile_protos(
+            &[
+                "proto/common.proto",
+                "proto/client.proto",
+                "proto/server.proto",
+            ],
+            &["proto/"],
+        )?;
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,143 @@
+//! Admin API for operating a running relay, as HTTP/JSON on a local address.
+//! Every request must carry the configured token as a bearer token.
+
+use crate::http::{Request, Response};
+use crate::session::PlayerId;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub const CONTENT_TYPE: &str = "application/json";
+
+/// An operator action, parsed from a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminRequest {
+    /// `GET /rooms`
+    ListRooms,
+    /// `GET /sessions`
+    ListSessions,
+    /// `POST /players/{id}/kick`, with an optional `{"reason": ...}`
+    KickPlayer { player_id: PlayerId, reason: String },
+    /// `POST /rooms/{code}/close`, with an optional `{"reason": ...}`
+    CloseRoom { room_code: String, reason: String },
+    /// `POST /rooms/{code}/start`
+    StartGame { room_code: String },
+    /// `POST /notice` with `{"message": ...}`
+    Notice { message: String },
+    /// `PUT /log-filter` with `{"filter": ...}`
+    SetLogFilter { filter: String },
+    /// `POST /config/reload`
+    ReloadConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReasonBody {
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NoticeBody {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogFilterBody {
+    filter: String,
+}
+
+impl AdminRequest {
+    /// The action a request asks for, or the response refusing it
+    pub fn parse(request: &Request) -> Result<Self, Response> {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["rooms"]) => Ok(AdminRequest::ListRooms),
+            ("GET", ["sessions"]) => Ok(AdminRequest::ListSessions),
+            ("POST", ["players", player_id, "kick"]) => {
+                let player_id = player_id
+                    .parse()
+                    .map_err(|_| error(400, "player id must be a number"))?;
+                let body: ReasonBody = optional_body(request)?;
+                Ok(AdminRequest::KickPlayer {
+                    player_id,
+                    reason: body.reason,
+                })
+            }
+            ("POST", ["rooms", room_code, "close"]) => {
+                let body: ReasonBody = optional_body(request)?;
+                Ok(AdminRequest::CloseRoom {
+                    room_code: room_code.to_string(),
+                    reason: body.reason,
+                })
+            }
+            ("POST", ["rooms", room_code, "start"]) => Ok(AdminRequest::StartGame {
+                room_code: room_code.to_string(),
+            }),
+            ("POST", ["notice"]) => {
+                let body: NoticeBody = required_body(request)?;
+                if body.message.trim().is_empty() {
+                    return Err(error(400, "message must not be empty"));
+                }
+                Ok(AdminRequest::Notice {
+                    message: body.message,
+                })
+            }
+            ("PUT", ["log-filter"]) => {
+                let body: LogFilterBody = required_body(request)?;
+                Ok(AdminRequest::SetLogFilter {
+                    filter: body.filter,
+                })
+            }
+            ("POST", ["config", "reload"]) => Ok(AdminRequest::ReloadConfig),
+            (_, ["rooms" | "sessions"])
+            | (_, ["players", _, "kick"])
+            | (_, ["rooms", _, "close" | "start"])
+            | (_, ["notice" | "log-filter"])
+            | (_, ["config", "reload"]) => Err(error(405, "method not allowed")),
+            _ => Err(error(404, "no such admin endpoint")),
+        }
+    }
+}
+
+/// Whether the request carries `token` as its bearer token. An empty token
+/// authorizes nothing.
+pub fn authorized(request: &Request, token: &str) -> bool {
+    let Some(presented) = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    !token.is_empty() && constant_time_eq(presented.trim().as_bytes(), token.as_bytes())
+}
+
+/// Compare without returning early, so timing does not reveal how much of a
+/// guessed token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub fn json(status: u16, value: &impl Serialize) -> Response {
+    let body = serde_json::to_vec(value).expect("admin responses serialize to JSON");
+    Response::new(status, CONTENT_TYPE, body)
+}
+
+/// `{"error": message}`
+pub fn error(status: u16, message: impl Into<String>) -> Response {
+    json(status, &serde_json::json!({ "error": message.into() }))
+}
+
+/// The JSON body, or the defaults if there is none
+fn optional_body<T: DeserializeOwned + Default>(request: &Request) -> Result<T, Response> {
+    if request.body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    required_body(request)
+}
+
+fn required_body<T: DeserializeOwned>(request: &Request) -> Result<T, Response> {
+    serde_json::from_slice(&request.body)
+        .map_err(|e| error(400, format!("invalid request body: {}", e)))
+}
@@ -0,0 +1,341 @@
+//! Relay throughput benchmark. Fills rooms with simulated players that all
+//! stream game messages at a fixed rate, and reports how many relayed
+//! messages came back per second.
+//!
+//! Compare a server started with `--workers 1` against one with a worker per
+//! core: `cargo run --release --bin bench -- --clients 512 --seconds 10`
+
+use prost::Message;
+use prost::bytes::Bytes;
+use rust_server::config::SERVER_ADDR;
+use rust_server::network::cookie::MIN_HELLO_SIZE;
+use rust_server::network::fragment::MAX_DATAGRAM_SIZE;
+use rust_server::protocol::client::{
+    ClientMessage, GameMessage, Hello, JoinRoom, Ready, client_message::Payload,
+};
+use rust_server::protocol::common::DeliveryMode;
+use rust_server::protocol::server::{ServerMessage, server_message};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Barrier;
+
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to keep counting relayed messages after everyone stopped sending
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+struct Options {
+    addr: SocketAddr,
+    clients: usize,
+    room_size: usize,
+    /// Game messages each client sends per second
+    rate: u32,
+    seconds: u64,
+    payload_size: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Latest server sequence received and the bitfield of the 32 before it
+#[derive(Debug, Default)]
+struct Acks {
+    latest: u32,
+    bits: u32,
+}
+
+impl Acks {
+    fn record(&mut self, sequence: u32) {
+        if sequence > self.latest {
+            let advance = sequence - self.latest;
+            self.bits = if self.latest == 0 || advance > 32 {
+                0
+            } else {
+                self.bits.checked_shl(advance).unwrap_or(0) | (1 << (advance - 1))
+            };
+            self.latest = sequence;
+        } else if sequence < self.latest && self.latest - sequence <= 32 {
+            self.bits |= 1 << (self.latest - sequence - 1);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options();
+    let rooms = options.clients.div_ceil(options.room_size);
+    println!(
+        "Benchmarking {} with {} clients in {} rooms, {} msg/s each for {}s",
+        options.addr, options.clients, rooms, options.rate, options.seconds
+    );
+
+    let counters = Arc::new(Counters::default());
+    // Everyone joins before anyone readies, so no room starts half full
+    let joined = Arc::new(Barrier::new(options.clients));
+    let started = Arc::new(Barrier::new(options.clients));
+
+    let mut clients = Vec::with_capacity(options.clients);
+    for index in 0..options.clients {
+        let options = options.clone();
+        let counters = counters.clone();
+        let joined = joined.clone();
+        let started = started.clone();
+        clients.push(tokio::spawn(async move {
+            if let Err(e) = run_client(index, &options, &counters, &joined, &started).await {
+                counters.failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Client {} failed: {}", index, e);
+            }
+        }));
+    }
+
+    let began = Instant::now();
+    for client in clients {
+        let _ = client.await;
+    }
+    let elapsed = began.elapsed().as_secs_f64();
+
+    let sent = counters.sent.load(Ordering::Relaxed);
+    let received = counters.received.load(Ordering::Relaxed);
+    let expected = sent * (options.room_size as u64 - 1);
+    let seconds = options.seconds as f64;
+
+    println!("Finished in {:.1}s", elapsed);
+    println!(
+        "Failed clients:    {}",
+        counters.failed.load(Ordering::Relaxed)
+    );
+    println!(
+        "Sent:              {} ({:.0} msg/s)",
+        sent,
+        sent as f64 / seconds
+    );
+    println!(
+        "Relayed received:  {} ({:.0} msg/s)",
+        received,
+        received as f64 / seconds
+    );
+    if expected > 0 {
+        println!(
+            "Delivered:         {:.2}%",
+            received as f64 / expected as f64 * 100.0
+        );
+    }
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        addr: SERVER_ADDR.parse().unwrap(),
+        clients: 256,
+        room_size: 4,
+        rate: 30,
+        seconds: 10,
+        payload_size: 64,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage(&arg));
+        let parsed = match arg.as_str() {
+            "--addr" => value.parse().map(|addr| options.addr = addr).is_ok(),
+            "--clients" => value.parse().map(|n| options.clients = n).is_ok(),
+            "--room-size" => value.parse().map(|n| options.room_size = n).is_ok(),
+            "--rate" => value.parse().map(|n| options.rate = n).is_ok(),
+            "--seconds" => value.parse().map(|n| options.seconds = n).is_ok(),
+            "--payload" => value.parse().map(|n| options.payload_size = n).is_ok(),
+            _ => false,
+        };
+        if !parsed {
+            usage(&arg);
+        }
+    }
+
+    options.room_size = options.room_size.clamp(2, 4);
+    // Only full rooms, as a lone player's game never starts
+    options.clients = options.clients.max(2).div_ceil(options.room_size) * options.room_size;
+    options.rate = options.rate.max(1);
+    options
+}
+
+fn usage(arg: &str) -> ! {
+    eprintln!("Invalid argument: {}", arg);
+    eprintln!(
+        "Usage: bench [--addr ADDR] [--clients N] [--room-size 2-4] [--rate MSG_PER_SEC] \
+         [--seconds N] [--payload BYTES]"
+    );
+    std::process::exit(2);
+}
+
+async fn run_client(
+    index: usize,
+    options: &Options,
+    counters: &Counters,
+    joined: &Barrier,
+    started: &Barrier,
+) -> std::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(options.addr).await?;
+
+    let mut sequence = 0;
+    let mut acks = Acks::default();
+
+    let room_code = format!("BENCH{}", index / options.room_size);
+    let joined_room = setup(&socket, &room_code, &mut sequence, &mut acks).await;
+    joined.wait().await;
+    joined_room?;
+
+    send(&socket, &mut sequence, &acks, Payload::Ready(Ready {})).await?;
+    let game_started = wait_for(&socket, &mut acks, |payload| {
+        matches!(payload, server_message::Payload::GameStarting(_))
+    })
+    .await;
+    started.wait().await;
+    game_started?;
+
+    let deadline = Instant::now() + Duration::from_secs(options.seconds);
+    let mut send_interval = tokio::time::interval(Duration::from_secs(1) / options.rate);
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let payload = Bytes::from(vec![0xAB; options.payload_size]);
+
+    loop {
+        tokio::select! {
+            _ = send_interval.tick(), if Instant::now() < deadline => {
+                let message = Payload::GameMessage(GameMessage {
+                    payload: payload.clone(),
+                    delivery: DeliveryMode::Unreliable as i32,
+                });
+                send(&socket, &mut sequence, &acks, message).await?;
+                counters.sent.fetch_add(1, Ordering::Relaxed);
+            }
+
+            received = socket.recv(&mut buf) => {
+                let len = received?;
+                if let Ok(message) = ServerMessage::decode(&buf[..len]) {
+                    acks.record(message.sequence);
+                    if let Some(server_message::Payload::GameMessage(_)) = message.payload {
+                        counters.received.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+
+            _ = tokio::time::sleep_until((deadline + DRAIN_TIME).into()) => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Handshake and join `room_code`
+async fn setup(
+    socket: &UdpSocket,
+    room_code: &str,
+    sequence: &mut u32,
+    acks: &mut Acks,
+) -> std::io::Result<()> {
+    let hello = ClientMessage {
+        payload: Some(Payload::Hello(Hello {
+            padding: vec![0; MIN_HELLO_SIZE],
+        })),
+        ..Default::default()
+    };
+    socket.send(&hello.encode_to_vec()).await?;
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let challenge = tokio::time::timeout(SETUP_TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            if let Ok(ServerMessage {
+                payload: Some(server_message::Payload::Challenge(challenge)),
+                ..
+            }) = ServerMessage::decode(&buf[..len])
+            {
+                return Ok::<_, std::io::Error>(challenge);
+            }
+        }
+    })
+    .await
+    .map_err(|_| timed_out("Challenge"))??;
+
+    if !challenge.public_key.is_empty() {
+        return Err(std::io::Error::other(
+            "server requires encryption, which the benchmark does not support",
+        ));
+    }
+
+    *sequence += 1;
+    let join = ClientMessage {
+        payload: Some(Payload::JoinRoom(JoinRoom {
+            room_code: room_code.to_string(),
+            player_name: format!("bench-{}", room_code),
+            ..Default::default()
+        })),
+        sequence: *sequence,
+        cookie: challenge.cookie,
+        ..Default::default()
+    };
+    socket.send(&join.encode_to_vec()).await?;
+
+    wait_for(socket, acks, |payload| {
+        matches!(payload, server_message::Payload::RoomJoined(_))
+    })
+    .await
+}
+
+/// Read until a message matching `expected` arrives, failing on an Error
+async fn wait_for(
+    socket: &UdpSocket,
+    acks: &mut Acks,
+    expected: impl Fn(&server_message::Payload) -> bool,
+) -> std::io::Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    tokio::time::timeout(SETUP_TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            let Ok(message) = ServerMessage::decode(&buf[..len]) else {
+                continue;
+            };
+            acks.record(message.sequence);
+
+            match message.payload {
+                Some(server_message::Payload::Error(error)) => {
+                    return Err(std::io::Error::other(error.message));
+                }
+                Some(payload) if expected(&payload) => return Ok(()),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .map_err(|_| timed_out("server reply"))?
+}
+
+async fn send(
+    socket: &UdpSocket,
+    sequence: &mut u32,
+    acks: &Acks,
+    payload: Payload,
+) -> std::io::Result<()> {
+    *sequence += 1;
+    let message = ClientMessage {
+        payload: Some(payload),
+        sequence: *sequence,
+        ack: acks.latest,
+        ack_bits: acks.bits,
+        ..Default::default()
+    };
+    socket.send(&message.encode_to_vec()).await?;
+    Ok(())
+}
+
+fn timed_out(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("timed out waiting for {}", what),
+    )
+}
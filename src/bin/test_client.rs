@@ -1,13 +1,27 @@
 use prost::Message;
+use rust_server::clock::{ClockSync, current_timestamp_ms};
 use rust_server::config::SERVER_ADDR;
+use rust_server::network::cookie::MIN_HELLO_SIZE;
+use rust_server::network::crypto::{self, Role, SessionCipher};
+use rust_server::network::fragment::MAX_DATAGRAM_SIZE;
 use rust_server::protocol::client::{
-    client_message::Payload, ClientMessage, JoinRoom, Ping, Reconnect
+    client_message::Payload, ClientMessage, Hello, JoinRoom, MtuProbeAck, Ping, Reconnect
 };
 use rust_server::protocol::server::{server_message, ServerMessage};
 use std::io::Error;
 use std::net::UdpSocket;
+use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Latest server sequence received and the bitfield of the 32 before it
+static ACKS: Mutex<(u32, u32)> = Mutex::new((0, 0));
+/// server_time of the last Pong and when it arrived, echoed in the next Ping
+static LAST_PONG: Mutex<Option<(u64, Instant)>> = Mutex::new(None);
+/// Server clock offset estimate built from every Pong
+static CLOCK: Mutex<ClockSync> = Mutex::new(ClockSync::new());
+/// Session keys, set when the server's Challenge offered encryption
+static CIPHER: Mutex<Option<SessionCipher>> = Mutex::new(None);
 
 fn main() -> std::io::Result<()> {
     let socket = UdpSocket::bind("127.0.0.1:0")?;
@@ -39,14 +53,20 @@ fn main() -> std::io::Result<()> {
     let new_socket = UdpSocket::bind("127.0.0.1:0")?;
     new_socket.set_read_timeout(Some(Duration::from_secs(2)))?;
 
+    let (cookie, public_key) = handshake(&new_socket, SERVER_ADDR);
     let reconnect_msg = ClientMessage {
         sequence: next_seq(&mut send_seq),
+        cookie,
+        public_key,
+        ack: current_ack(),
+        ack_bits: current_ack_bits(),
+        reliable_sequence: 0,
         payload: Some(Payload::Reconnect(Reconnect {
             token: reconnect_token,
             player_name: "Player1_Reconnected".to_string(),
         })),
     };
-    new_socket.send_to(&reconnect_msg.encode_to_vec(), SERVER_ADDR)?;
+    new_socket.send_to(&encode(&reconnect_msg), SERVER_ADDR)?;
     println!("Sent: Reconnect");
     receive_response(&new_socket);
 
@@ -66,12 +86,12 @@ fn test_packet_loss_detection(socket: &UdpSocket, server_addr: &str) {
     // Send ping with sequence 100
     let msg1 = ClientMessage {
         sequence: 100,
-        payload: Some(Payload::Ping(Ping {
-            timestamp: current_timestamp_ms(),
-            sequence: 1,
-        })),
+        ack: current_ack(),
+        ack_bits: current_ack_bits(),
+        payload: Some(Payload::Ping(ping_with_echo(current_timestamp_ms(), 1))),
+        ..Default::default()
     };
-    socket.send_to(&msg1.encode_to_vec(), server_addr).unwrap();
+    socket.send_to(&encode(&msg1), server_addr).unwrap();
     println!("Sent: Ping with client sequence 100");
     receive_response(socket);
 
@@ -80,12 +100,12 @@ fn test_packet_loss_detection(socket: &UdpSocket, server_addr: &str) {
     // Send ping with sequence 105 (gap of 4)
     let msg2 = ClientMessage {
         sequence: 105,
-        payload: Some(Payload::Ping(Ping {
-            timestamp: current_timestamp_ms(),
-            sequence: 2,
-        })),
+        ack: current_ack(),
+        ack_bits: current_ack_bits(),
+        payload: Some(Payload::Ping(ping_with_echo(current_timestamp_ms(), 2))),
+        ..Default::default()
     };
-    socket.send_to(&msg2.encode_to_vec(), server_addr).unwrap();
+    socket.send_to(&encode(&msg2), server_addr).unwrap();
     println!("Sent: Ping with client sequence 105 (skipped 101-104)");
     receive_response(socket);
 
@@ -94,15 +114,15 @@ fn test_packet_loss_detection(socket: &UdpSocket, server_addr: &str) {
     // Send ping with sequence 103 (duplicate/old)
     let msg3 = ClientMessage {
         sequence: 103,
-        payload: Some(Payload::Ping(Ping {
-            timestamp: current_timestamp_ms(),
-            sequence: 3,
-        })),
+        ack: current_ack(),
+        ack_bits: current_ack_bits(),
+        payload: Some(Payload::Ping(ping_with_echo(current_timestamp_ms(), 3))),
+        ..Default::default()
     };
-    socket.send_to(&msg3.encode_to_vec(), server_addr).unwrap();
+    socket.send_to(&encode(&msg3), server_addr).unwrap();
     println!("Sent: Ping with client sequence 103 (old/duplicate)");
 
-    // This might timeout since server skips duplicates
+    // 103 was never received, so the server accepts it out of order
     receive_response(socket);
 }
 
@@ -110,11 +130,11 @@ fn test_packet_loss_detection(socket: &UdpSocket, server_addr: &str) {
 //     for i in 0..3 {
 //         let game_msg = ClientMessage {
 //             payload: Some(Payload::GameMessage(GameMessage {
-//                 payload: format!("Game data {}", i).into_bytes(),
+//                 payload: format!("Game data {}", i).into_bytes().into(),
 //             })),
 //         };
 //
-//         socket.send_to(&game_msg.encode_to_vec(), server_addr)?;
+//         socket.send_to(&encode(&game_msg), server_addr)?;
 //         println!("Sent: GameMessage {}", i);
 //         thread::sleep(Duration::from_millis(100));
 //     }
@@ -125,7 +145,7 @@ fn test_packet_loss_detection(socket: &UdpSocket, server_addr: &str) {
 //     let ready_msg = ClientMessage {
 //         payload: Some(Payload::Ready(Ready {})),
 //     };
-//     socket.send_to(&ready_msg.encode_to_vec(), server_addr)?;
+//     socket.send_to(&encode(&ready_msg), server_addr)?;
 //     println!("Sent: Ready");
 //
 //     receive_response(&socket);
@@ -134,15 +154,113 @@ fn test_packet_loss_detection(socket: &UdpSocket, server_addr: &str) {
 //     Ok(())
 // }
 
+/// Hello/Challenge exchange, returning the cookie to echo when joining and,
+/// if the server encrypts traffic, our public key for the key exchange
+fn handshake(socket: &UdpSocket, server_addr: &str) -> (Vec<u8>, Vec<u8>) {
+    let hello = ClientMessage {
+        payload: Some(Payload::Hello(Hello {
+            padding: vec![0; MIN_HELLO_SIZE],
+        })),
+        ..Default::default()
+    };
+    socket.send_to(&hello.encode_to_vec(), server_addr).unwrap();
+    println!("Sent: Hello");
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    while let Ok((len, _)) = socket.recv_from(&mut buf) {
+        if let Ok(ServerMessage {
+            payload: Some(server_message::Payload::Challenge(challenge)),
+            ..
+        }) = ServerMessage::decode(&buf[..len])
+        {
+            println!("Received: Challenge");
+            if challenge.public_key.is_empty() {
+                *CIPHER.lock().unwrap() = None;
+                return (challenge.cookie, Vec::new());
+            }
+
+            let secret = crypto::generate_secret();
+            let cipher = SessionCipher::new(
+                Role::Client,
+                &secret,
+                &challenge.public_key,
+                &challenge.cookie,
+            )
+            .expect("server sent an invalid public key");
+            *CIPHER.lock().unwrap() = Some(cipher);
+            println!("Traffic will be encrypted");
+            return (challenge.cookie, crypto::public_key(&secret).to_vec());
+        }
+    }
+
+    println!("No challenge received");
+    (Vec::new(), Vec::new())
+}
+
+/// Encode a message, sealing it once a key has been negotiated. The cookie and
+/// public key stay outside so the server can derive the key for a join.
+fn encode(message: &ClientMessage) -> Vec<u8> {
+    let mut cipher = CIPHER.lock().unwrap();
+    let Some(cipher) = cipher.as_mut() else {
+        return message.encode_to_vec();
+    };
+    if let Some(Payload::MtuProbeAck(_)) = message.payload {
+        return message.encode_to_vec();
+    }
+
+    let inner = ClientMessage {
+        cookie: Vec::new(),
+        public_key: Vec::new(),
+        ..message.clone()
+    };
+    ClientMessage {
+        payload: Some(Payload::Sealed(
+            cipher.seal(message.sequence, &inner.encode_to_vec()),
+        )),
+        cookie: message.cookie.clone(),
+        public_key: message.public_key.clone(),
+        ..Default::default()
+    }
+    .encode_to_vec()
+}
+
+/// Decode a datagram from the server, opening it if it is sealed
+fn decode(data: &[u8]) -> Option<ServerMessage> {
+    let message = ServerMessage::decode(data).ok()?;
+    let Some(server_message::Payload::Sealed(sealed)) = &message.payload else {
+        return Some(message);
+    };
+
+    let cipher = CIPHER.lock().unwrap();
+    match cipher.as_ref().map(|c| c.open(sealed)) {
+        Some(Ok(plaintext)) => ServerMessage::decode(&plaintext[..]).ok(),
+        Some(Err(e)) => {
+            println!("Failed to open sealed message: {:?}", e);
+            None
+        }
+        None => {
+            println!("Sealed message without a negotiated key");
+            None
+        }
+    }
+}
+
 fn send_join_room(socket: &UdpSocket, server_addr: &str, seq: &mut u32) -> Result<(), Error> {
+    let (cookie, public_key) = handshake(socket, server_addr);
     let join_msg = ClientMessage {
         sequence: next_seq(seq),
+        cookie,
+        public_key,
+        ack: current_ack(),
+        ack_bits: current_ack_bits(),
+        reliable_sequence: 0,
         payload: Some(Payload::JoinRoom(JoinRoom {
             room_code: "TEST".to_string(),
             player_name: "Player1".to_string(),
+            ..Default::default()
         })),
     };
-    socket.send_to(&join_msg.encode_to_vec(), server_addr)?;
+    socket.send_to(&encode(&join_msg), server_addr)?;
     println!("Sent: JoinRoom");
 
     // receive_response(&socket);
@@ -156,22 +274,23 @@ fn send_ping(socket: &UdpSocket, server_addr: &str, sequence: u32, seq: &mut u32
 
     let ping_message = ClientMessage {
         sequence: next_seq(seq),
-        payload: Some(Payload::Ping(Ping {
-            timestamp: ping_timestamp,
-            sequence: sequence,
-        })),
+        ack: current_ack(),
+        ack_bits: current_ack_bits(),
+        payload: Some(Payload::Ping(ping_with_echo(ping_timestamp, sequence))),
+        ..Default::default()
     };
 
     socket
-        .send_to(&ping_message.encode_to_vec(), server_addr)
+        .send_to(&encode(&ping_message), server_addr)
         .unwrap();
     println!("Sent: Ping (seq={})", sequence);
 
-    let mut buffer = [0u8; 1024];
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
     match socket.recv_from(&mut buffer) {
         Ok((len, _)) => {
             let now = current_timestamp_ms();
-            if let Ok(response) = ServerMessage::decode(&buffer[..len]) {
+            if let Some(response) = decode(&buffer[..len]) {
+                on_server_message(socket, &response);
                 println!("Received response: {:?}", response);
                 println!("Round trip latency: {} ms", now - ping_timestamp);
             }
@@ -182,10 +301,11 @@ fn send_ping(socket: &UdpSocket, server_addr: &str, sequence: u32, seq: &mut u32
     }
 }
 fn receive_response(socket: &UdpSocket) {
-    let mut buf = [0u8; 1024];
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
     match socket.recv_from(&mut buf) {
         Ok((len, _)) => {
-            if let Ok(response) = ServerMessage::decode(&buf[..len]) {
+            if let Some(response) = decode(&buf[..len]) {
+                on_server_message(socket, &response);
                 println!("Received: {:?}", response);
             } else {
                 println!("Received {} bytes (failed to decode)", len);
@@ -195,19 +315,12 @@ fn receive_response(socket: &UdpSocket) {
     }
 }
 
-// TODO: I have the same function in main.rs. Remember about DRY
-fn current_timestamp_ms() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64
-}
-
 fn receive_and_extract_token(socket: &UdpSocket) -> String {
-    let mut buf = [0u8; 1024];
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
     match socket.recv_from(&mut buf) {
         Ok((len, _)) => {
-            if let Ok(response) = ServerMessage::decode(&buf[..len]) {
+            if let Some(response) = decode(&buf[..len]) {
+                on_server_message(socket, &response);
                 println!("Received: {:?}", response);
                 if let Some(server_message::Payload::RoomJoined(joined)) = response.payload {
                     return joined.reconnect_token;
@@ -222,4 +335,91 @@ fn receive_and_extract_token(socket: &UdpSocket) -> String {
 fn next_seq(seq: &mut u32) -> u32 {
     *seq += 1;
     *seq
-}
\ No newline at end of file
+}
+
+/// Bookkeeping every received message needs: acks and path MTU probe replies
+fn on_server_message(socket: &UdpSocket, message: &ServerMessage) {
+    record_received(message.sequence);
+
+    if let Some(server_message::Payload::Pong(pong)) = &message.payload {
+        *LAST_PONG.lock().unwrap() = Some((pong.server_time, Instant::now()));
+
+        let mut clock = CLOCK.lock().unwrap();
+        clock.add_pong(pong, current_timestamp_ms());
+        if let Some(offset) = clock.offset_ms() {
+            println!(
+                "Server clock offset: {} ms ({} samples)",
+                offset,
+                clock.sample_count()
+            );
+        }
+    }
+
+    if let Some(server_message::Payload::GameStarting(starting)) = &message.payload {
+        match CLOCK.lock().unwrap().server_to_local(starting.start_server_time) {
+            Some(local_start) => println!(
+                "Game starts in {} ms (local time {})",
+                local_start as i64 - current_timestamp_ms() as i64,
+                local_start
+            ),
+            None => println!("Game starting, but clock is not synchronized yet"),
+        }
+    }
+
+    if let Some(server_message::Payload::MtuProbe(probe)) = &message.payload {
+        let ack = ClientMessage {
+            sequence: 0,
+            ack: current_ack(),
+            ack_bits: current_ack_bits(),
+            payload: Some(Payload::MtuProbeAck(MtuProbeAck {
+                probe_id: probe.probe_id,
+            })),
+            ..Default::default()
+        };
+        let _ = socket.send_to(&encode(&ack), SERVER_ADDR);
+        println!("Acked MTU probe {} ({} bytes)", probe.probe_id, message.encoded_len());
+    }
+}
+
+/// Build a ping echoing the last pong, so the server can measure round trip time
+fn ping_with_echo(timestamp: u64, sequence: u32) -> Ping {
+    let (pong_server_time, pong_delay_ms) = match *LAST_PONG.lock().unwrap() {
+        Some((server_time, received_at)) => {
+            (server_time, received_at.elapsed().as_millis() as u32)
+        }
+        None => (0, 0),
+    };
+
+    Ping {
+        timestamp,
+        sequence,
+        pong_server_time,
+        pong_delay_ms,
+    }
+}
+
+fn record_received(sequence: u32) {
+    if sequence == 0 {
+        return;
+    }
+
+    let mut acks = ACKS.lock().unwrap();
+    let (latest, bits) = *acks;
+
+    if sequence > latest {
+        let advance = sequence - latest;
+        let shifted = bits.checked_shl(advance).unwrap_or(0);
+        let previous = 1u32.checked_shl(advance - 1).unwrap_or(0);
+        *acks = (sequence, if latest == 0 { 0 } else { shifted | previous });
+    } else if sequence < latest {
+        acks.1 |= 1u32.checked_shl(latest - sequence - 1).unwrap_or(0);
+    }
+}
+
+fn current_ack() -> u32 {
+    ACKS.lock().unwrap().0
+}
+
+fn current_ack_bits() -> u32 {
+    ACKS.lock().unwrap().1
+}
@@ -0,0 +1,139 @@
+use crate::protocol::server::Pong;
+use std::collections::VecDeque;
+
+/// Number of recent samples the estimator keeps
+const MAX_SAMPLES: usize = 16;
+/// Samples further than this many median absolute deviations away are outliers
+const OUTLIER_MADS: i64 = 3;
+
+/// Milliseconds since the Unix epoch on the local clock
+pub fn current_timestamp_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// One Ping/Pong exchange reduced to NTP's offset and delay
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSample {
+    /// Server clock minus client clock
+    pub offset_ms: i64,
+    /// Round trip time excluding server processing
+    pub delay_ms: i64,
+}
+
+impl ClockSample {
+    /// `t0` client send, `t1` server receive, `t2` server send, `t3` client receive
+    pub fn new(t0: u64, t1: u64, t2: u64, t3: u64) -> Self {
+        let (t0, t1, t2, t3) = (t0 as i64, t1 as i64, t2 as i64, t3 as i64);
+        Self {
+            offset_ms: ((t1 - t0) + (t2 - t3)) / 2,
+            delay_ms: (t3 - t0) - (t2 - t1),
+        }
+    }
+}
+
+/// NTP-style estimate of the server clock offset from several Ping/Pong samples
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+}
+
+impl ClockSync {
+    pub const fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn add_sample(&mut self, sample: ClockSample) {
+        if sample.delay_ms < 0 {
+            // Only possible if a clock stepped mid exchange
+            return;
+        }
+
+        self.samples.push_back(sample);
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Add the sample carried by a Pong received at `received_ms` local time
+    pub fn add_pong(&mut self, pong: &Pong, received_ms: u64) {
+        let server_receive_time = if pong.server_receive_time != 0 {
+            pong.server_receive_time
+        } else {
+            pong.server_time
+        };
+
+        self.add_sample(ClockSample::new(
+            pong.timestamp,
+            server_receive_time,
+            pong.server_time,
+            received_ms,
+        ));
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Estimated server clock minus local clock. Samples with unusually high
+    /// delay are discarded first, since their offset is the least trustworthy,
+    /// then offsets far from the median are rejected and the rest averaged.
+    pub fn offset_ms(&self) -> Option<i64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let delays: Vec<i64> = self.samples.iter().map(|s| s.delay_ms).collect();
+        let delay_limit = upper_bound(&delays);
+
+        let offsets: Vec<i64> = self
+            .samples
+            .iter()
+            .filter(|s| s.delay_ms <= delay_limit)
+            .map(|s| s.offset_ms)
+            .collect();
+
+        let center = median(&offsets);
+        let spread = median_absolute_deviation(&offsets, center).max(1);
+        let kept: Vec<i64> = offsets
+            .into_iter()
+            .filter(|offset| (offset - center).abs() <= OUTLIER_MADS * spread)
+            .collect();
+
+        Some(kept.iter().sum::<i64>() / kept.len() as i64)
+    }
+
+    /// Convert a server timestamp to the local clock
+    pub fn server_to_local(&self, server_ms: u64) -> Option<u64> {
+        let offset = self.offset_ms()?;
+        Some((server_ms as i64 - offset).max(0) as u64)
+    }
+
+    /// Current time on the server clock
+    pub fn server_now(&self) -> Option<u64> {
+        let offset = self.offset_ms()?;
+        Some((current_timestamp_ms() as i64 + offset).max(0) as u64)
+    }
+}
+
+fn median(values: &[i64]) -> i64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted[sorted.len() / 2]
+}
+
+fn median_absolute_deviation(values: &[i64], center: i64) -> i64 {
+    let deviations: Vec<i64> = values.iter().map(|v| (v - center).abs()).collect();
+    median(&deviations)
+}
+
+/// Largest value that is not an outlier on the high side
+fn upper_bound(values: &[i64]) -> i64 {
+    let center = median(values);
+    let spread = median_absolute_deviation(values, center).max(1);
+    center + OUTLIER_MADS * spread
+}
@@ -1,2 +0,0 @@
-pub const GRACE_PLAYER_TIME_SECONDS: usize = 60;
-pub const SERVER_ADDR: &str = "127.0.0.1:9000";
\ No newline at end of file
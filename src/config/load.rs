@@ -0,0 +1,207 @@
+//! Builds a [`ServerConfig`] from its sources: the defaults, then a TOML file,
+//! then `RELAY_*` environment variables, then command line flags.
+
+use crate::config::{ConfigError, ServerConfig};
+use toml::{Table, Value};
+
+/// Environment variable naming the config file, if `--config` is not given
+pub const CONFIG_PATH_VAR: &str = "RELAY_CONFIG";
+const ENV_PREFIX: &str = "RELAY_";
+
+pub const USAGE: &str = "\
+Usage: rust-server [OPTIONS]
+
+Options:
+  -c, --config PATH       TOML config file (default: $RELAY_CONFIG)
+      --bind ADDR         Address to listen on (network.bind_addr)
+      --workers N         Receive workers (network.receive_workers)
+      --log-filter FILTER tracing filter directives (log_filter)
+      --set KEY=VALUE     Set any setting, e.g. --set room.max_players=8
+      --print-config      Print the effective configuration and exit
+  -h, --help              Print this help and exit
+
+Every setting can also be set from the environment as RELAY_<KEY>, with dots
+replaced by underscores, e.g. RELAY_SESSION_TIMEOUT_SECONDS=45.
+Flags take precedence over the environment, which takes precedence over the file.";
+
+/// What the command line asked the server to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Startup {
+    Run(ServerConfig),
+    PrintConfig(ServerConfig),
+    Help,
+}
+
+/// Load and validate the configuration from `args` (without the program name)
+/// and the environment variables `env` looks up
+pub fn from_sources(
+    args: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Startup, ConfigError> {
+    let mut config_path = env(CONFIG_PATH_VAR);
+    let mut flag_overrides = Vec::new();
+    let mut print_only = false;
+
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .ok_or_else(|| ConfigError::BadFlag(format!("{} needs a value", flag)))
+        };
+
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Startup::Help),
+            "--print-config" => print_only = true,
+            "-c" | "--config" => config_path = Some(value(&flag)?),
+            "--bind" => flag_overrides.push(("network.bind_addr".to_string(), value(&flag)?)),
+            "--workers" => {
+                flag_overrides.push(("network.receive_workers".to_string(), value(&flag)?))
+            }
+            "--log-filter" => flag_overrides.push(("log_filter".to_string(), value(&flag)?)),
+            "--set" => {
+                let setting = value(&flag)?;
+                let Some((key, raw)) = setting.split_once('=') else {
+                    return Err(ConfigError::BadFlag(format!(
+                        "--set expects KEY=VALUE, got `{}`",
+                        setting
+                    )));
+                };
+                flag_overrides.push((key.trim().to_string(), raw.trim().to_string()));
+            }
+            _ => {
+                return Err(ConfigError::BadFlag(format!(
+                    "unknown flag `{}`, see --help",
+                    flag
+                )));
+            }
+        }
+    }
+
+    let config = match &config_path {
+        Some(path) => read_file(path)?,
+        None => ServerConfig::default(),
+    };
+    let mut table = to_table(&config);
+
+    for key in setting_keys(&table) {
+        if let Some(raw) = env(&env_var(&key)) {
+            set(&mut table, &key, &raw, &env_var(&key))?;
+        }
+    }
+    for (key, raw) in &flag_overrides {
+        set(&mut table, key, raw, "command line")?;
+    }
+
+    let config: ServerConfig = table.try_into().map_err(|e| ConfigError::Parse {
+        source: "overrides".to_string(),
+        reason: e.to_string(),
+    })?;
+    config.validate()?;
+
+    if print_only {
+        Ok(Startup::PrintConfig(config))
+    } else {
+        Ok(Startup::Run(config))
+    }
+}
+
+/// Read a config file. Settings it leaves out keep their defaults.
+pub fn read_file(path: &str) -> Result<ServerConfig, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Read {
+        path: path.to_string(),
+        reason: e.to_string(),
+    })?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse {
+        source: path.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// The configuration as TOML, as printed by `--print-config`
+pub fn to_toml(config: &ServerConfig) -> String {
+    toml::to_string(config).expect("config serializes to TOML")
+}
+
+fn to_table(config: &ServerConfig) -> Table {
+    Table::try_from(config).expect("config serializes to a TOML table")
+}
+
+/// Dotted keys of every setting, e.g. `room.max_players`
+fn setting_keys(table: &Table) -> Vec<String> {
+    let mut keys = Vec::new();
+    for (name, value) in table {
+        match value {
+            Value::Table(section) => {
+                keys.extend(section.keys().map(|key| format!("{}.{}", name, key)));
+            }
+            _ => keys.push(name.clone()),
+        }
+    }
+    keys
+}
+
+fn setting<'a>(table: &'a Table, key: &str) -> Option<&'a Value> {
+    match key.split_once('.') {
+        Some((section, name)) => table.get(section)?.as_table()?.get(name),
+        None => table.get(key).filter(|value| !value.is_table()),
+    }
+}
+
+fn setting_mut<'a>(table: &'a mut Table, key: &str) -> Option<&'a mut Value> {
+    match key.split_once('.') {
+        Some((section, name)) => table.get_mut(section)?.as_table_mut()?.get_mut(name),
+        None => table.get_mut(key).filter(|value| !value.is_table()),
+    }
+}
+
+/// Dotted keys of the settings that differ between `old` and `new`
+pub fn changed_settings(old: &ServerConfig, new: &ServerConfig) -> Vec<String> {
+    let (old, new) = (to_table(old), to_table(new));
+    setting_keys(&new)
+        .into_iter()
+        .filter(|key| setting(&old, key) != setting(&new, key))
+        .collect()
+}
+
+/// `new` with the settings named by `keys` put back to their values in `old`
+pub fn keep_settings(new: &ServerConfig, old: &ServerConfig, keys: &[String]) -> ServerConfig {
+    let old = to_table(old);
+    let mut table = to_table(new);
+    for key in keys {
+        if let (Some(slot), Some(value)) = (setting_mut(&mut table, key), setting(&old, key)) {
+            *slot = value.clone();
+        }
+    }
+    table
+        .try_into()
+        .expect("settings of two valid configs combine")
+}
+
+/// Environment variable overriding `key`, e.g. RELAY_ROOM_MAX_PLAYERS
+fn env_var(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+/// Override one setting, parsing `raw` as the type the setting already has
+fn set(table: &mut Table, key: &str, raw: &str, source: &str) -> Result<(), ConfigError> {
+    let unknown = || ConfigError::UnknownSetting {
+        source: source.to_string(),
+        key: key.to_string(),
+    };
+
+    let slot = setting_mut(table, key).ok_or_else(unknown)?;
+
+    let parsed = match slot {
+        Value::String(_) => Some(Value::String(raw.to_string())),
+        Value::Boolean(_) => raw.parse().ok().map(Value::Boolean),
+        Value::Integer(_) => raw.parse().ok().map(Value::Integer),
+        Value::Float(_) => raw.parse().ok().map(Value::Float),
+        _ => None,
+    };
+
+    *slot = parsed.ok_or_else(|| ConfigError::Parse {
+        source: source.to_string(),
+        reason: format!("`{}` expects {}, got `{}`", key, slot.type_str(), raw),
+    })?;
+    Ok(())
+}
@@ -0,0 +1,562 @@
+pub mod load;
+pub mod reload;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+pub const GRACE_PLAYER_TIME_SECONDS: usize = 60;
+pub const SERVER_ADDR: &str = "127.0.0.1:9000";
+pub const NETWORK_STATS_INTERVAL_SECONDS: u64 = 2;
+pub const RECONNECT_TOKEN_LIFETIME_SECONDS: u64 = 24 * 60 * 60;
+pub const HANDSHAKE_COOKIE_LIFETIME_SECONDS: u64 = 30;
+/// Require every session to negotiate a key and seal its traffic
+pub const ENCRYPT_TRANSPORT: bool = false;
+pub const ADDRESS_RATE_LIMIT_PER_SECOND: f64 = 200.0;
+pub const ADDRESS_RATE_LIMIT_BURST: f64 = 400.0;
+pub const CONTROL_RATE_LIMIT_PER_SECOND: f64 = 20.0;
+pub const CONTROL_RATE_LIMIT_BURST: f64 = 40.0;
+pub const GAME_RATE_LIMIT_PER_SECOND: f64 = 120.0;
+pub const GAME_RATE_LIMIT_BURST: f64 = 240.0;
+/// Over budget messages within a penalty window before the client gets an Error
+pub const RATE_LIMIT_WARN_AFTER: u32 = 20;
+/// Over budget messages within a penalty window before the session is disconnected
+pub const RATE_LIMIT_DISCONNECT_AFTER: u32 = 200;
+/// Sockets bound to SERVER_ADDR with SO_REUSEPORT, each read by its own worker
+pub const RECEIVE_WORKERS: usize = 1;
+/// Move datagrams with recvmmsg/sendmmsg and UDP GSO where the platform has them
+pub const BATCHED_IO: bool = true;
+pub const SESSION_TIMEOUT_SECONDS: u64 = 30;
+pub const SESSION_CLEANUP_INTERVAL_SECONDS: u64 = 5;
+pub const RETRANSMIT_INTERVAL_MS: u64 = 50;
+pub const MTU_PROBE_INTERVAL_MS: u64 = 250;
+pub const MAX_PLAYERS_PER_ROOM: usize = 4;
+/// Largest room a CreateRoom may ask for
+pub const MAX_PLAYERS_LIMIT: usize = 16;
+pub const MIN_PLAYERS_TO_START: usize = 2;
+pub const GAME_COUNTDOWN_SECONDS: u32 = 3;
+/// Longest countdown a room may be set up with
+pub const MAX_COUNTDOWN_SECONDS: u32 = 60;
+/// How often the matchmaker tries to form matches and updates queued players
+pub const MATCHMAKING_INTERVAL_MS: u64 = 1000;
+/// Rating difference accepted as soon as a player queues
+pub const INITIAL_RATING_WINDOW: u32 = 100;
+pub const RATING_WINDOW_GROWTH_PER_SECOND: u32 = 20;
+pub const MAX_RATING_WINDOW: u32 = 1000;
+/// Largest difference in round trip time between players matched together
+pub const MAX_RTT_DIFFERENCE_MS: u32 = 80;
+pub const LOG_FILTER: &str = "rust_server=debug";
+/// Service name spans are exported under
+pub const SERVICE_NAME: &str = "rust-server";
+/// Local HTTP address serving Prometheus metrics at /metrics
+pub const METRICS_ADDR: &str = "127.0.0.1:9464";
+/// Local HTTP address of the admin API
+pub const ADMIN_ADDR: &str = "127.0.0.1:9465";
+/// Shortest admin token accepted, so it cannot be guessed
+pub const MIN_ADMIN_TOKEN_LENGTH: usize = 16;
+/// How long rooms get to finish after a shutdown signal before the server exits
+pub const SHUTDOWN_DRAIN_SECONDS: u64 = 10;
+
+/// Every tunable of the relay. Built from these defaults, a TOML file,
+/// `RELAY_*` environment variables and command line flags, in that order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// tracing filter directives, e.g. `rust_server=info,rust_server::room=debug`
+    pub log_filter: String,
+    pub log_format: LogFormat,
+    pub network: NetworkConfig,
+    pub session: SessionConfig,
+    pub room: RoomConfig,
+    pub matchmaking: MatchmakingConfig,
+    pub rate_limit: RateLimitConfig,
+    pub shutdown: ShutdownConfig,
+    pub metrics: MetricsConfig,
+    pub admin: AdminConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+/// How log lines are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, with the fields of every enclosing span
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub bind_addr: String,
+    /// Sockets bound with SO_REUSEPORT, each read by its own worker
+    pub receive_workers: usize,
+    /// recvmmsg/sendmmsg and UDP GSO on Linux
+    pub batched_io: bool,
+    /// Require every session to negotiate a key and seal its traffic
+    pub encrypt_transport: bool,
+    pub handshake_cookie_lifetime_seconds: u64,
+    pub retransmit_interval_ms: u64,
+    pub mtu_probe_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Silence after which a player is marked disconnected
+    pub timeout_seconds: u64,
+    /// How long a disconnected player may reconnect before being removed
+    pub grace_period_seconds: u64,
+    /// Counted from when the token was issued, at join or the last reconnect,
+    /// not from the disconnect. This caps how long a session can last and
+    /// still be resumed; a player who drops after that cannot reconnect.
+    pub reconnect_token_lifetime_seconds: u64,
+    pub cleanup_interval_seconds: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomConfig {
+    /// Defaults for rooms created without settings of their own
+    pub max_players: usize,
+    pub min_players_to_start: usize,
+    pub countdown_seconds: u32,
+    /// Largest max_players a CreateRoom may ask for
+    pub max_players_limit: usize,
+    pub network_stats_interval_seconds: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchmakingConfig {
+    /// Size of the rooms matches are made for
+    pub players_per_match: usize,
+    pub interval_ms: u64,
+    pub initial_rating_window: u32,
+    /// The rating window widens by this much for every second a player waits
+    pub rating_window_growth_per_second: u32,
+    pub max_rating_window: u32,
+    pub max_rtt_difference_ms: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub address_per_second: f64,
+    pub address_burst: f64,
+    pub control_per_second: f64,
+    pub control_burst: f64,
+    pub game_per_second: f64,
+    pub game_burst: f64,
+    /// Over budget messages within a penalty window before the client gets an Error
+    pub warn_after: u32,
+    /// Over budget messages within a penalty window before the session is disconnected
+    pub disconnect_after: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Time rooms get to finish after SIGINT/SIGTERM, cut short once all are closed
+    pub drain_seconds: u64,
+    /// Server address clients are told to move to, empty for none
+    pub reconnect_hint: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Address of the HTTP endpoint serving `/metrics`
+    pub bind_addr: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    /// Address of the HTTP/JSON admin API, meant to stay on localhost
+    pub bind_addr: String,
+    /// Bearer token every admin request must carry
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP traces endpoint, e.g. http://127.0.0.1:4318/v1/traces, empty
+    /// to export nothing. Needs a build with the `otel` feature.
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            log_filter: LOG_FILTER.to_string(),
+            log_format: LogFormat::Text,
+            network: NetworkConfig::default(),
+            session: SessionConfig::default(),
+            room: RoomConfig::default(),
+            matchmaking: MatchmakingConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            metrics: MetricsConfig::default(),
+            admin: AdminConfig::default(),
+            telemetry: TelemetryConfig::default(),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: SERVER_ADDR.to_string(),
+            receive_workers: RECEIVE_WORKERS,
+            batched_io: BATCHED_IO,
+            encrypt_transport: ENCRYPT_TRANSPORT,
+            handshake_cookie_lifetime_seconds: HANDSHAKE_COOKIE_LIFETIME_SECONDS,
+            retransmit_interval_ms: RETRANSMIT_INTERVAL_MS,
+            mtu_probe_interval_ms: MTU_PROBE_INTERVAL_MS,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            timeout_seconds: SESSION_TIMEOUT_SECONDS,
+            grace_period_seconds: GRACE_PLAYER_TIME_SECONDS as u64,
+            reconnect_token_lifetime_seconds: RECONNECT_TOKEN_LIFETIME_SECONDS,
+            cleanup_interval_seconds: SESSION_CLEANUP_INTERVAL_SECONDS,
+        }
+    }
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            max_players: MAX_PLAYERS_PER_ROOM,
+            min_players_to_start: MIN_PLAYERS_TO_START,
+            countdown_seconds: GAME_COUNTDOWN_SECONDS,
+            max_players_limit: MAX_PLAYERS_LIMIT,
+            network_stats_interval_seconds: NETWORK_STATS_INTERVAL_SECONDS,
+        }
+    }
+}
+
+impl Default for MatchmakingConfig {
+    fn default() -> Self {
+        Self {
+            players_per_match: MAX_PLAYERS_PER_ROOM,
+            interval_ms: MATCHMAKING_INTERVAL_MS,
+            initial_rating_window: INITIAL_RATING_WINDOW,
+            rating_window_growth_per_second: RATING_WINDOW_GROWTH_PER_SECOND,
+            max_rating_window: MAX_RATING_WINDOW,
+            max_rtt_difference_ms: MAX_RTT_DIFFERENCE_MS,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            address_per_second: ADDRESS_RATE_LIMIT_PER_SECOND,
+            address_burst: ADDRESS_RATE_LIMIT_BURST,
+            control_per_second: CONTROL_RATE_LIMIT_PER_SECOND,
+            control_burst: CONTROL_RATE_LIMIT_BURST,
+            game_per_second: GAME_RATE_LIMIT_PER_SECOND,
+            game_burst: GAME_RATE_LIMIT_BURST,
+            warn_after: RATE_LIMIT_WARN_AFTER,
+            disconnect_after: RATE_LIMIT_DISCONNECT_AFTER,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_seconds: SHUTDOWN_DRAIN_SECONDS,
+            reconnect_hint: String::new(),
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bind_addr: METRICS_ADDR.to_string(),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind_addr: ADMIN_ADDR.to_string(),
+            token: String::new(),
+        }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: String::new(),
+            service_name: SERVICE_NAME.to_string(),
+        }
+    }
+}
+
+/// Why a configuration was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The config file could not be read
+    Read { path: String, reason: String },
+    /// The config file is not valid TOML or does not match the schema
+    Parse { source: String, reason: String },
+    /// An override names a setting that does not exist
+    UnknownSetting { source: String, key: String },
+    /// A command line flag is unknown or lacks its value
+    BadFlag(String),
+    /// A setting has a well formed but unacceptable value
+    Invalid { key: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, reason } => write!(f, "cannot read {}: {}", path, reason),
+            ConfigError::Parse { source, reason } => write!(f, "{}: {}", source, reason),
+            ConfigError::UnknownSetting { source, key } => {
+                write!(f, "{}: unknown setting `{}`", source, key)
+            }
+            ConfigError::BadFlag(reason) => write!(f, "{}", reason),
+            ConfigError::Invalid { key, reason } => write!(f, "`{}` {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    /// Check every value, so a bad setting fails at startup rather than at first use
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        fn invalid(key: &'static str, reason: impl Into<String>) -> Result<(), ConfigError> {
+            Err(ConfigError::Invalid {
+                key,
+                reason: reason.into(),
+            })
+        }
+
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_filter) {
+            return invalid("log_filter", format!("is not a valid filter: {}", e));
+        }
+
+        let network = &self.network;
+        if network.bind_addr.parse::<SocketAddr>().is_err() {
+            return invalid(
+                "network.bind_addr",
+                "must be an IP address and port, e.g. 0.0.0.0:9000",
+            );
+        }
+        if !(1..=1024).contains(&network.receive_workers) {
+            return invalid("network.receive_workers", "must be between 1 and 1024");
+        }
+        if network.handshake_cookie_lifetime_seconds == 0 {
+            return invalid(
+                "network.handshake_cookie_lifetime_seconds",
+                "must be at least 1",
+            );
+        }
+        if network.retransmit_interval_ms == 0 {
+            return invalid("network.retransmit_interval_ms", "must be at least 1");
+        }
+        if network.mtu_probe_interval_ms == 0 {
+            return invalid("network.mtu_probe_interval_ms", "must be at least 1");
+        }
+
+        let session = &self.session;
+        if session.timeout_seconds == 0 {
+            return invalid("session.timeout_seconds", "must be at least 1");
+        }
+        if session.cleanup_interval_seconds == 0 {
+            return invalid("session.cleanup_interval_seconds", "must be at least 1");
+        }
+        if session.reconnect_token_lifetime_seconds < session.grace_period_seconds {
+            return invalid(
+                "session.reconnect_token_lifetime_seconds",
+                "must be at least session.grace_period_seconds, since tokens count from join and not from the disconnect",
+            );
+        }
+
+        let room = &self.room;
+        if room.max_players < 2 {
+            return invalid("room.max_players", "must be at least 2");
+        }
+        if room.min_players_to_start == 0 || room.min_players_to_start > room.max_players {
+            return invalid(
+                "room.min_players_to_start",
+                "must be between 1 and room.max_players",
+            );
+        }
+        if room.countdown_seconds > MAX_COUNTDOWN_SECONDS {
+            return invalid(
+                "room.countdown_seconds",
+                format!("must be at most {}", MAX_COUNTDOWN_SECONDS),
+            );
+        }
+        if room.max_players_limit < room.max_players {
+            return invalid(
+                "room.max_players_limit",
+                "must be at least room.max_players",
+            );
+        }
+        if room.network_stats_interval_seconds == 0 {
+            return invalid("room.network_stats_interval_seconds", "must be at least 1");
+        }
+
+        let matchmaking = &self.matchmaking;
+        if matchmaking.players_per_match < 2
+            || matchmaking.players_per_match > room.max_players_limit
+        {
+            return invalid(
+                "matchmaking.players_per_match",
+                "must be between 2 and room.max_players_limit",
+            );
+        }
+        if matchmaking.interval_ms < 100 {
+            return invalid("matchmaking.interval_ms", "must be at least 100");
+        }
+        if matchmaking.max_rating_window < matchmaking.initial_rating_window {
+            return invalid(
+                "matchmaking.max_rating_window",
+                "must be at least matchmaking.initial_rating_window",
+            );
+        }
+
+        let limits = &self.rate_limit;
+        let buckets = [
+            (
+                "rate_limit.address_per_second",
+                limits.address_per_second,
+                0.0,
+            ),
+            ("rate_limit.address_burst", limits.address_burst, 1.0),
+            (
+                "rate_limit.control_per_second",
+                limits.control_per_second,
+                0.0,
+            ),
+            ("rate_limit.control_burst", limits.control_burst, 1.0),
+            ("rate_limit.game_per_second", limits.game_per_second, 0.0),
+            ("rate_limit.game_burst", limits.game_burst, 1.0),
+        ];
+        for (key, value, minimum) in buckets {
+            if !value.is_finite() || value <= 0.0 || value < minimum {
+                return invalid(
+                    key,
+                    format!("must be a positive number of at least {}", minimum),
+                );
+            }
+        }
+        if limits.warn_after == 0 || limits.warn_after >= limits.disconnect_after {
+            return invalid(
+                "rate_limit.warn_after",
+                "must be at least 1 and below rate_limit.disconnect_after",
+            );
+        }
+
+        if self.metrics.enabled && self.metrics.bind_addr.parse::<SocketAddr>().is_err() {
+            return invalid(
+                "metrics.bind_addr",
+                "must be an IP address and port, e.g. 127.0.0.1:9464",
+            );
+        }
+        if self.admin.enabled {
+            if self.admin.bind_addr.parse::<SocketAddr>().is_err() {
+                return invalid(
+                    "admin.bind_addr",
+                    "must be an IP address and port, e.g. 127.0.0.1:9465",
+                );
+            }
+            if self.admin.token.len() < MIN_ADMIN_TOKEN_LENGTH {
+                return invalid(
+                    "admin.token",
+                    format!(
+                        "must be at least {} characters when the admin API is enabled",
+                        MIN_ADMIN_TOKEN_LENGTH
+                    ),
+                );
+            }
+        }
+        let telemetry = &self.telemetry;
+        if !telemetry.otlp_endpoint.is_empty() {
+            if !cfg!(feature = "otel") {
+                return invalid(
+                    "telemetry.otlp_endpoint",
+                    "needs a server built with the `otel` feature",
+                );
+            }
+            if !telemetry.otlp_endpoint.starts_with("http://")
+                && !telemetry.otlp_endpoint.starts_with("https://")
+            {
+                return invalid(
+                    "telemetry.otlp_endpoint",
+                    "must be an http:// or https:// URL",
+                );
+            }
+            if telemetry.service_name.is_empty() {
+                return invalid("telemetry.service_name", "must not be empty");
+            }
+        }
+        if self.shutdown.drain_seconds > 3600 {
+            return invalid("shutdown.drain_seconds", "must be at most 3600");
+        }
+
+        Ok(())
+    }
+}
+
+impl MatchmakingConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    /// Rating difference accepted from a player who has waited `waited`
+    pub fn rating_window(&self, waited: Duration) -> u32 {
+        let growth = self
+            .rating_window_growth_per_second
+            .saturating_mul(waited.as_secs() as u32);
+        self.initial_rating_window
+            .saturating_add(growth)
+            .min(self.max_rating_window)
+    }
+}
+
+impl NetworkConfig {
+    pub fn retransmit_interval(&self) -> Duration {
+        Duration::from_millis(self.retransmit_interval_ms)
+    }
+
+    pub fn mtu_probe_interval(&self) -> Duration {
+        Duration::from_millis(self.mtu_probe_interval_ms)
+    }
+}
+
+impl SessionConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_seconds)
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_seconds)
+    }
+
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_secs(self.cleanup_interval_seconds)
+    }
+}
@@ -0,0 +1,107 @@
+//! Reloading the configuration while the server runs. Workers watch the
+//! current config and apply what changed without touching live sessions or rooms.
+
+use crate::config::load::{self, Startup};
+use crate::config::{ConfigError, ServerConfig};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Settings only read at startup. A reload that changes them keeps the
+/// running value and reports them instead.
+pub const RESTART_REQUIRED: &[&str] = &[
+    "log_format",
+    "network.bind_addr",
+    "network.receive_workers",
+    "network.batched_io",
+    "network.encrypt_transport",
+    "network.handshake_cookie_lifetime_seconds",
+    "session.reconnect_token_lifetime_seconds",
+    "metrics.enabled",
+    "metrics.bind_addr",
+    "admin.enabled",
+    "admin.bind_addr",
+    "telemetry.otlp_endpoint",
+    "telemetry.service_name",
+];
+
+/// Outcome of a reload that produced a valid configuration
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReloadReport {
+    /// Settings now in effect with their new values
+    pub applied: Vec<String>,
+    /// Settings that changed but keep their running value until a restart
+    pub restart_required: Vec<String>,
+}
+
+/// Re-reads the config sources the server started with and publishes the result
+pub struct ConfigReloader {
+    /// Command line the server was started with, so its flags still take
+    /// precedence over the file
+    args: Vec<String>,
+    config: watch::Sender<Arc<ServerConfig>>,
+}
+
+impl ConfigReloader {
+    pub fn new(args: Vec<String>, config: Arc<ServerConfig>) -> Self {
+        Self {
+            args,
+            config: watch::Sender::new(config),
+        }
+    }
+
+    /// Receiver that sees every applied reload
+    pub fn subscribe(&self) -> watch::Receiver<Arc<ServerConfig>> {
+        self.config.subscribe()
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.config.borrow().clone()
+    }
+
+    /// Load the config again and apply what can change at runtime. On error
+    /// the running configuration is kept as it is.
+    pub fn reload(&self) -> Result<ReloadReport, ConfigError> {
+        let result = load::from_sources(self.args.iter().cloned(), |name| std::env::var(name).ok());
+        let loaded = match result {
+            Ok(Startup::Run(config)) | Ok(Startup::PrintConfig(config)) => config,
+            Ok(Startup::Help) => return Ok(ReloadReport::default()),
+            Err(e) => {
+                tracing::error!("Config reload failed, keeping the running config: {}", e);
+                return Err(e);
+            }
+        };
+
+        let current = self.current();
+        let (restart_required, applied): (Vec<String>, Vec<String>) =
+            load::changed_settings(&current, &loaded)
+                .into_iter()
+                .partition(|key| RESTART_REQUIRED.contains(&key.as_str()));
+
+        for key in &restart_required {
+            tracing::warn!(
+                "Config reload: `{}` changed but needs a restart to apply",
+                key
+            );
+        }
+
+        if applied.is_empty() {
+            tracing::info!("Config reloaded, nothing to apply");
+        } else {
+            let config = load::keep_settings(&loaded, &current, &restart_required);
+            // The kept values can conflict with the new ones, e.g. a longer
+            // grace period than the running token lifetime
+            if let Err(e) = config.validate() {
+                tracing::error!("Config reload failed, keeping the running config: {}", e);
+                return Err(e);
+            }
+            tracing::info!("Config reloaded, applied {}", applied.join(", "));
+            self.config.send_replace(Arc::new(config));
+        }
+
+        Ok(ReloadReport {
+            applied,
+            restart_required,
+        })
+    }
+}
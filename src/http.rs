@@ -0,0 +1,212 @@
+//! Minimal HTTP/1.1 server for the local operator endpoints. Each connection
+//! carries one request and is closed after the response.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest request head plus body accepted
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+/// A client gets this long to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    pub query: Option<String>,
+    /// Header names lowercased
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", body.into())
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "not found\n")
+    }
+}
+
+/// Accept connections on `addr` and answer each request with `handler`
+pub async fn serve<H, F>(addr: &str, handler: H) -> std::io::Result<()>
+where
+    H: Fn(Request) -> F + Send + Sync + 'static,
+    F: Future<Output = Response> + Send,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("HTTP accept failed: {}", e);
+                    continue;
+                }
+            };
+
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, peer, &*handler).await {
+                    tracing::debug!("HTTP connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle_connection<H, F>(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    handler: &H,
+) -> std::io::Result<()>
+where
+    H: Fn(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out"))?;
+
+    let response = match request {
+        Ok(request) => {
+            tracing::trace!("HTTP {} {} from {}", request.method, request.path, peer);
+            handler(request).await
+        }
+        Err(e) => Response::text(400, format!("{}\n", e)),
+    };
+
+    stream.write_all(&encode_response(&response)).await?;
+    stream.shutdown().await
+}
+
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Request> {
+    let mut data = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 4096];
+
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_REQUEST_SIZE {
+            return Err(invalid("request head too large"));
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(invalid("connection closed mid request"));
+        }
+        data.extend_from_slice(&chunk[..read]);
+    };
+
+    let mut body = data.split_off(head_end + 4);
+    let head =
+        std::str::from_utf8(&data[..head_end]).map_err(|_| invalid("request head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .map(|(_, value)| {
+            value
+                .parse::<usize>()
+                .map_err(|_| invalid("bad Content-Length"))
+        })
+        .transpose()?
+        .unwrap_or(0);
+    if content_length > MAX_REQUEST_SIZE {
+        return Err(invalid("request body too large"));
+    }
+
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(invalid("connection closed mid body"));
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(content_length);
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+
+    Ok(Request {
+        method: method.to_string(),
+        path,
+        query,
+        headers,
+        body,
+    })
+}
+
+fn encode_response(response: &Response) -> Vec<u8> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+
+    let mut out = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    )
+    .into_bytes();
+    out.extend_from_slice(&response.body);
+    out
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_string())
+}
@@ -2,4 +2,9 @@ pub mod protocol;
 pub mod network;
 pub mod room;
 pub mod session;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod clock;
+pub mod http;
+pub mod metrics;
+pub mod admin;
+pub mod telemetry;
\ No newline at end of file
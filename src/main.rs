@@ -1,285 +1,1705 @@
 use prost::Message;
-use rust_server::config::SERVER_ADDR;
-use rust_server::network::udp::UdpServer;
-use rust_server::protocol::client::{ClientMessage, Ping, Reconnect, client_message::Payload};
+use rust_server::admin::{self, AdminRequest};
+use rust_server::clock::current_timestamp_ms;
+use rust_server::config::ServerConfig;
+use rust_server::config::load::{self, Startup};
+use rust_server::config::reload::ConfigReloader;
+use rust_server::http::{self, Request, Response};
+use rust_server::metrics::prometheus::{self, Exposition};
+use rust_server::metrics::{self, DecodeStage};
+use rust_server::network::cookie::{CookieSigner, MIN_HELLO_SIZE};
+use rust_server::network::crypto::{self, Role, SessionCipher};
+use rust_server::network::fragment::Reassembler;
+use rust_server::network::ratelimit::{
+    AddressRateLimiter, MessageClass, RateLimitCounters, RateLimitSnapshot, RateLimitVerdict,
+};
+use rust_server::network::udp::{IoSnapshot, Outbox, UdpServer};
+use rust_server::protocol::client::{
+    ClientMessage, FindMatch, GameMessage, JoinIntent, JoinRoom, ListRooms, Ping, Reconnect,
+    client_message::Payload,
+};
+use rust_server::protocol::common::{DeliveryMode, RoomSettings};
 use rust_server::protocol::server::{
-    Error, GameMessage as ServerGameMessage, GameStarting, PlayerDisconnected, PlayerInfo,
-    PlayerLeft, PlayerReconnected, Pong, RoomJoined, RoomUpdate, ServerMessage, server_message,
+    Challenge, Error, ErrorCode, MatchmakingState, Pong, RoomJoined, ServerMessage, ServerNotice,
+    ServerShuttingDown, server_message,
+};
+use rust_server::room::actor::{RoomCommand, RoomEvent};
+use rust_server::room::lobby::{self, Subscription};
+use rust_server::room::{PlayerRoute, RoomDirectory, RoomError, RoomState};
+use rust_server::session::{
+    PlayerId, SequenceCheck, Session, SessionCounts, SessionManager, SessionSummary,
 };
-use rust_server::room::{RoomManager, RoomState};
-use rust_server::session::{SequenceCheck, SessionManager};
+use rust_server::telemetry::{self, LogFilterHandle};
 
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+/// State the dispatcher needs to vet a datagram before it reaches a session
+struct Ingress {
+    /// Require sealed traffic, see `network.encrypt_transport`
+    encrypt_transport: bool,
+    cookies: CookieSigner,
+    reassembler: Reassembler,
+    address_limiter: AddressRateLimiter,
+    rate_limit_counters: Arc<RateLimitCounters>,
+    /// Set once the server is shutting down, sent in reply to any join
+    shutting_down: Option<ServerShuttingDown>,
+}
+
+/// Sessions that live on one worker and the room each is routed to, moved
+/// to the worker a disconnected player reconnected through
+struct Handover {
+    session: Session,
+    route: Option<PlayerRoute>,
+}
+
+/// Requests between receive workers
+enum WorkerCommand {
+    /// Give up a disconnected player's session, if this worker holds it
+    Release {
+        player_id: PlayerId,
+        reply: oneshot::Sender<Option<Handover>>,
+    },
+    /// Finish a reconnect that waited on the other workers to hand the session over
+    ResumeReconnect {
+        addr: SocketAddr,
+        reconnect: Reconnect,
+        handover: Option<Box<Handover>>,
+        /// Span of the Reconnect message, so the reconnect finishes within it
+        span: tracing::Span,
+    },
+    /// Stop accepting joins and tell every session the server is going away
+    Shutdown { notice: ServerShuttingDown },
+    /// Report how many sessions the worker holds, for metrics
+    CountSessions {
+        reply: oneshot::Sender<SessionCounts>,
+    },
+    /// Describe every session the worker holds, for the admin API
+    ListSessions {
+        reply: oneshot::Sender<Vec<SessionSummary>>,
+    },
+    /// Remove a player from their room and the server, if this worker holds them
+    Kick {
+        player_id: PlayerId,
+        reason: String,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Take this worker's players out of a room, replying with how many
+    CloseRoom {
+        room_code: String,
+        reason: String,
+        reply: oneshot::Sender<usize>,
+    },
+    /// Send a notice to every connected session, replying with how many
+    Notice {
+        message: String,
+        reply: oneshot::Sender<usize>,
+    },
+}
+
+/// Ask every worker the same question and collect the answers of those still running
+async fn ask_workers<T>(
+    workers: &[mpsc::UnboundedSender<WorkerCommand>],
+    command: impl Fn(oneshot::Sender<T>) -> WorkerCommand,
+) -> Vec<T> {
+    let mut answers = Vec::with_capacity(workers.len());
+    for worker in workers {
+        let (reply, answer) = oneshot::channel();
+        if worker.send(command(reply)).is_ok()
+            && let Ok(answer) = answer.await
+        {
+            answers.push(answer);
+        }
+    }
+    answers
+}
+
+/// Channels to every receive worker, indexed by worker id
+struct Peers {
+    id: usize,
+    workers: Vec<mpsc::UnboundedSender<WorkerCommand>>,
+}
+
+impl Peers {
+    /// Ask every other worker for a player's session and resume the reconnect
+    /// on this one once they have all answered. This runs as its own task, as
+    /// two workers waiting on each other directly could deadlock.
+    fn request_handover(&self, player_id: PlayerId, addr: SocketAddr, reconnect: Reconnect) {
+        let others: Vec<_> = self
+            .workers
+            .iter()
+            .enumerate()
+            .filter(|(id, _)| *id != self.id)
+            .map(|(_, worker)| worker.clone())
+            .collect();
+        let own = self.workers[self.id].clone();
+        let span = tracing::Span::current();
+
+        tokio::spawn(async move {
+            let mut handover = None;
+            for worker in others {
+                let (reply, response) = oneshot::channel();
+                if worker
+                    .send(WorkerCommand::Release { player_id, reply })
+                    .is_ok()
+                    && let Ok(Some(released)) = response.await
+                {
+                    handover = Some(Box::new(released));
+                    break;
+                }
+            }
+
+            let _ = own.send(WorkerCommand::ResumeReconnect {
+                addr,
+                reconnect,
+                handover,
+                span,
+            });
+        });
+    }
+}
+
+/// One receive socket and the sessions the kernel routes to it. Rooms are
+/// shared with the other workers.
+struct Worker {
+    server: Arc<UdpServer>,
+    sessions: SessionManager,
+    rooms: RoomDirectory,
+    ingress: Ingress,
+    peers: Peers,
+    /// Current configuration, updated in place by a reload
+    config: watch::Receiver<Arc<ServerConfig>>,
+}
+
+impl Worker {
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<WorkerCommand>,
+        mut room_events: mpsc::UnboundedReceiver<RoomEvent>,
+    ) {
+        let mut applied = self.config.borrow_and_update().clone();
+        let mut cleanup_interval = tokio::time::interval(applied.session.cleanup_interval());
+        let mut retransmit_interval = tokio::time::interval(applied.network.retransmit_interval());
+        let mut probe_interval = tokio::time::interval(applied.network.mtu_probe_interval());
+        let mut lobby_changes = self.rooms.lobby().subscribe();
+        let mut lobby_interval = tokio::time::interval(lobby::PUSH_INTERVAL);
+        lobby_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut lobby_changed = false;
+
+        let server = self.server.clone();
+        let sessions = &mut self.sessions;
+        let rooms = &mut self.rooms;
+
+        loop {
+            tokio::select! {
+                received = server.recv_batch() => match received {
+                    Ok(datagrams) => {
+                        for (data, addr) in datagrams {
+                            handle_datagram(
+                                &server,
+                                sessions,
+                                rooms,
+                                &mut self.ingress,
+                                &self.peers,
+                                data,
+                                addr,
+                            )
+                            .await;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::debug!("recv error - sent to closed port. Ignoring. Error: {}", e);
+                    }
+                },
+
+                Some(command) = commands.recv() => {
+                    handle_worker_command(&server, sessions, rooms, &mut self.ingress, command).await;
+                }
+
+                Some(event) = room_events.recv() => {
+                    handle_room_event(&server, sessions, rooms, &self.ingress, event).await;
+                }
+
+                Ok(()) = self.config.changed() => {
+                    let config = self.config.borrow_and_update().clone();
+                    apply_config(sessions, rooms, &mut self.ingress, &config);
+
+                    if config.session.cleanup_interval() != applied.session.cleanup_interval() {
+                        cleanup_interval = tokio::time::interval(config.session.cleanup_interval());
+                    }
+                    if config.network.retransmit_interval() != applied.network.retransmit_interval() {
+                        retransmit_interval =
+                            tokio::time::interval(config.network.retransmit_interval());
+                    }
+                    if config.network.mtu_probe_interval() != applied.network.mtu_probe_interval() {
+                        probe_interval = tokio::time::interval(config.network.mtu_probe_interval());
+                    }
+                    applied = config;
+                }
+
+                _ = cleanup_interval.tick() => {
+                    cleanup_sessions(sessions, rooms).await;
+                }
+
+                _ = retransmit_interval.tick() => {
+                    let mut outbox = Outbox::default();
+                    let retransmissions = sessions.collect_retransmissions();
+                    metrics::global().record_retransmissions(retransmissions.len());
+                    for (addr, mtu, message) in retransmissions {
+                        tracing::debug!(
+                            "Retransmitting reliable message seq={} to {}",
+                            message.sequence,
+                            addr
+                        );
+                        server.queue_message(&mut outbox, &message, addr, mtu);
+                    }
+                    server.flush(&mut outbox).await;
+                }
+
+                Ok(()) = lobby_changes.changed() => {
+                    lobby_changes.borrow_and_update();
+                    lobby_changed = true;
+                }
+
+                _ = lobby_interval.tick(), if lobby_changed => {
+                    lobby_changed = false;
+                    push_room_lists(&server, sessions, rooms).await;
+                }
+
+                _ = probe_interval.tick() => {
+                    // Probes are padded to an exact size, so they must never be fragmented
+                    for (addr, probe) in sessions.collect_mtu_probes() {
+                        metrics::global().record_sent(&probe.payload);
+                        let _ = server.send(&probe.encode_to_vec(), addr).await;
+                    }
+                }
+            }
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("rust_server=debug".parse().unwrap()),
-        )
-        .init();
+    let config = match load::from_sources(std::env::args().skip(1), |name| std::env::var(name).ok())
+    {
+        Ok(Startup::Run(config)) => Arc::new(config),
+        Ok(Startup::PrintConfig(config)) => {
+            print!("{}", load::to_toml(&config));
+            return Ok(());
+        }
+        Ok(Startup::Help) => {
+            println!("{}", load::USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let telemetry = telemetry::init(&config)?;
+    let log_filter_handle = telemetry.log_filter.clone();
+    if !config.telemetry.otlp_endpoint.is_empty() {
+        tracing::info!("Exporting spans to {}", config.telemetry.otlp_endpoint);
+    }
+
+    let reloader = Arc::new(ConfigReloader::new(
+        std::env::args().skip(1).collect(),
+        config.clone(),
+    ));
+    #[cfg(unix)]
+    reload_on_hangup(reloader.clone())?;
+
+    // Only a changed setting is applied, so a reload does not undo a filter
+    // set through the admin API
+    let mut log_config = reloader.subscribe();
+    let config_filter_handle = log_filter_handle.clone();
+    tokio::spawn(async move {
+        let mut current = log_config.borrow().log_filter.clone();
+        while log_config.changed().await.is_ok() {
+            let filter = log_config.borrow_and_update().log_filter.clone();
+            if filter == current {
+                continue;
+            }
+            if let Err(e) = config_filter_handle.reload(EnvFilter::new(&filter)) {
+                tracing::warn!("Failed to apply log filter {}: {}", filter, e);
+            }
+            current = filter;
+        }
+    });
+
+    let network = &config.network;
+    let sockets: Vec<Arc<UdpServer>> = UdpServer::bind_reuseport(
+        &network.bind_addr,
+        network.receive_workers,
+        network.batched_io,
+    )
+    .await?
+    .into_iter()
+    .map(Arc::new)
+    .collect();
+    tracing::info!(
+        "Relay server started with {} receive workers",
+        sockets.len()
+    );
+
+    // Each worker owns the sessions on its socket outright. Rooms run as their
+    // own tasks, shared by all workers, and report back to the worker that
+    // routed the player.
+    let (command_senders, command_receivers): (Vec<_>, Vec<_>) =
+        sockets.iter().map(|_| mpsc::unbounded_channel()).unzip();
+    let sessions = SessionManager::new(&config.session, config.rate_limit.clone());
+    let (room_events_tx, room_events) = mpsc::unbounded_channel();
+    let rooms = RoomDirectory::new(
+        config.room.clone(),
+        config.matchmaking.clone(),
+        sockets[0].clone(),
+        room_events_tx,
+    );
+    let cookies = CookieSigner::new(Duration::from_secs(
+        network.handshake_cookie_lifetime_seconds,
+    ));
+    let rate_limit_counters = Arc::new(RateLimitCounters::default());
+
+    let io_sockets = sockets.clone();
+    // Never routes anyone, only watches the shared rooms drain on shutdown
+    let (unused_events, _) = mpsc::unbounded_channel();
+    let room_view = Arc::new(rooms.sibling(sockets[0].clone(), unused_events));
+
+    // Rooms send through the socket of the worker that started them, so
+    // their fan-out is spread over the sockets like the receive side
+    let mut siblings = Vec::new();
+    for socket in &sockets[1..] {
+        let (room_events_tx, room_events) = mpsc::unbounded_channel();
+        siblings.push((
+            sessions.sibling(),
+            rooms.sibling(socket.clone(), room_events_tx),
+            room_events,
+        ));
+    }
+
+    let workers = std::iter::once((sessions, rooms, room_events)).chain(siblings);
+    for (id, ((server, commands), (sessions, rooms, room_events))) in sockets
+        .into_iter()
+        .zip(command_receivers)
+        .zip(workers)
+        .enumerate()
+    {
+        let worker = Worker {
+            server,
+            sessions,
+            rooms,
+            ingress: Ingress {
+                encrypt_transport: network.encrypt_transport,
+                cookies: cookies.clone(),
+                reassembler: Reassembler::default(),
+                address_limiter: AddressRateLimiter::new(
+                    config.rate_limit.address_per_second,
+                    config.rate_limit.address_burst,
+                ),
+                rate_limit_counters: rate_limit_counters.clone(),
+                shutting_down: None,
+            },
+            peers: Peers {
+                id,
+                workers: command_senders.clone(),
+            },
+            config: reloader.subscribe(),
+        };
+        tokio::spawn(
+            worker
+                .run(commands, room_events)
+                .instrument(tracing::info_span!("worker", id)),
+        );
+    }
+
+    let mut stats_interval = tokio::time::interval(Duration::from_secs(
+        config.room.network_stats_interval_seconds,
+    ));
+    let mut last_rate_limits = RateLimitSnapshot::default();
+    let mut last_io = vec![IoSnapshot::default(); io_sockets.len()];
+
+    if config.metrics.enabled {
+        let sources = Arc::new(MetricsSources {
+            rate_limits: rate_limit_counters.clone(),
+            sockets: io_sockets.clone(),
+            workers: command_senders.clone(),
+            rooms: room_view.clone(),
+        });
+        http::serve(&config.metrics.bind_addr, move |request| {
+            let sources = sources.clone();
+            async move { serve_metrics(&sources, request).await }
+        })
+        .await?;
+        tracing::info!(
+            "Serving metrics on http://{}/metrics",
+            config.metrics.bind_addr
+        );
+    }
+
+    if config.admin.enabled {
+        let context = Arc::new(AdminContext {
+            workers: command_senders.clone(),
+            rooms: room_view.clone(),
+            reloader: reloader.clone(),
+            log_filter: log_filter_handle,
+        });
+        http::serve(&config.admin.bind_addr, move |request| {
+            let context = context.clone();
+            async move { serve_admin(&context, request).await }
+        })
+        .await?;
+        tracing::info!("Serving the admin API on http://{}", config.admin.bind_addr);
+    }
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = stats_interval.tick() => {}
+            _ = &mut shutdown => break,
+        }
+
+        let rate_limits = rate_limit_counters.snapshot();
+        if rate_limits != last_rate_limits {
+            tracing::info!("Rate limiting: {:?}", rate_limits);
+            last_rate_limits = rate_limits;
+        }
 
-    let server = Arc::new(UdpServer::bind(SERVER_ADDR).await?);
-    tracing::info!("Relay server started");
+        for (id, (socket, last)) in io_sockets.iter().zip(last_io.iter_mut()).enumerate() {
+            let io = socket.io_stats();
+            if io != *last {
+                tracing::debug!("Worker {} I/O: {:?}", id, io);
+                *last = io;
+            }
+        }
+    }
+
+    drain(&reloader.current(), &command_senders, &room_view).await;
+    tracing::info!("Relay server stopped");
+    telemetry.shutdown();
+    Ok(())
+}
+
+/// Resolves on SIGINT, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => tracing::warn!("Cannot listen for SIGTERM: {}", e),
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Tell every worker to refuse joins and notify its sessions, then give the
+/// rooms until the drain window ends, or everyone has left, to finish. A
+/// second signal cuts the wait short.
+async fn drain(
+    config: &ServerConfig,
+    workers: &[mpsc::UnboundedSender<WorkerCommand>],
+    rooms: &RoomDirectory,
+) {
+    let drain_seconds = config.shutdown.drain_seconds;
+    tracing::info!(
+        "Shutting down, draining {} rooms for up to {}s",
+        rooms.room_count(),
+        drain_seconds
+    );
+
+    let notice = ServerShuttingDown {
+        reason: "Server is shutting down".to_string(),
+        drain_seconds: drain_seconds as u32,
+        reconnect_hint: config.shutdown.reconnect_hint.clone(),
+    };
+    for worker in workers {
+        let _ = worker.send(WorkerCommand::Shutdown {
+            notice: notice.clone(),
+        });
+    }
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(drain_seconds);
+    let mut check_interval = tokio::time::interval(Duration::from_millis(250));
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => {
+                tracing::info!("Drain window over with {} rooms open", rooms.room_count());
+                return;
+            }
+            _ = &mut shutdown => {
+                tracing::warn!("Second shutdown signal, exiting without draining");
+                return;
+            }
+            _ = check_interval.tick() => {
+                if rooms.room_count() == 0 {
+                    tracing::info!("All rooms drained");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Everything a metrics scrape reads besides the process wide counters
+struct MetricsSources {
+    rate_limits: Arc<RateLimitCounters>,
+    sockets: Vec<Arc<UdpServer>>,
+    workers: Vec<mpsc::UnboundedSender<WorkerCommand>>,
+    rooms: Arc<RoomDirectory>,
+}
+
+async fn serve_metrics(sources: &MetricsSources, request: Request) -> Response {
+    if request.path != "/metrics" {
+        return Response::not_found();
+    }
+    if request.method != "GET" {
+        return Response::text(405, "only GET is supported\n");
+    }
+
+    let mut out = Exposition::default();
+    metrics::global().encode(&mut out);
+
+    let io: Vec<IoSnapshot> = sources.sockets.iter().map(|s| s.io_stats()).collect();
+    let ids: Vec<String> = (0..io.len()).map(|id| id.to_string()).collect();
+    let per_worker = |value: fn(&IoSnapshot) -> u64| {
+        ids.iter()
+            .zip(&io)
+            .map(move |(id, io)| (vec![("worker", id.as_str())], value(io)))
+    };
+    out.counter(
+        "relay_datagrams_received_total",
+        "Datagrams read from the socket",
+        per_worker(|io| io.datagrams_received),
+    );
+    out.counter(
+        "relay_bytes_received_total",
+        "Bytes read from the socket",
+        per_worker(|io| io.bytes_received),
+    );
+    out.counter(
+        "relay_datagrams_sent_total",
+        "Datagrams written to the socket",
+        per_worker(|io| io.datagrams_sent),
+    );
+    out.counter(
+        "relay_bytes_sent_total",
+        "Bytes written to the socket",
+        per_worker(|io| io.bytes_sent),
+    );
+    out.counter(
+        "relay_recv_calls_total",
+        "Receive syscalls",
+        per_worker(|io| io.recv_calls),
+    );
+    out.counter(
+        "relay_send_calls_total",
+        "Send syscalls",
+        per_worker(|io| io.send_calls),
+    );
+
+    let limits = sources.rate_limits.snapshot();
+    out.counter(
+        "relay_rate_limited_total",
+        "Datagrams and messages dropped for exceeding a rate limit, by budget",
+        [
+            (vec![("budget", "address")], limits.address_dropped),
+            (vec![("budget", "control")], limits.control_dropped),
+            (vec![("budget", "game")], limits.game_dropped),
+        ],
+    );
+    out.counter(
+        "relay_rate_limit_warnings_total",
+        "Sessions warned for exceeding a rate limit",
+        [(vec![], limits.warnings)],
+    );
+    out.counter(
+        "relay_rate_limit_disconnects_total",
+        "Sessions disconnected for exceeding a rate limit",
+        [(vec![], limits.disconnects)],
+    );
+
+    let mut sessions = SessionCounts::default();
+    for counts in ask_workers(&sources.workers, |reply| WorkerCommand::CountSessions {
+        reply,
+    })
+    .await
+    {
+        sessions.connected += counts.connected;
+        sessions.disconnected += counts.disconnected;
+    }
+    out.gauge(
+        "relay_sessions",
+        "Sessions by connection state",
+        [
+            (vec![("state", "connected")], sessions.connected as u64),
+            (
+                vec![("state", "disconnected")],
+                sessions.disconnected as u64,
+            ),
+        ],
+    );
+
+    let rooms = sources.rooms.describe_rooms().await;
+    let count = |state: RoomState| rooms.iter().filter(|room| room.state == state).count() as u64;
+    out.gauge(
+        "relay_rooms",
+        "Rooms by state",
+        [
+            (vec![("state", "waiting")], count(RoomState::Waiting)),
+            (vec![("state", "playing")], count(RoomState::Playing)),
+            (vec![("state", "ended")], count(RoomState::Ended)),
+        ],
+    );
+    out.gauge(
+        "relay_room_players",
+        "Players in rooms, including those within their reconnect grace period",
+        [(
+            vec![],
+            rooms.iter().map(|room| room.players.len() as u64).sum(),
+        )],
+    );
+
+    Response::new(200, prometheus::CONTENT_TYPE, out.finish())
+}
+
+/// What admin requests act on
+struct AdminContext {
+    workers: Vec<mpsc::UnboundedSender<WorkerCommand>>,
+    rooms: Arc<RoomDirectory>,
+    /// Also the source of the admin token, so a reload can rotate it
+    reloader: Arc<ConfigReloader>,
+    log_filter: LogFilterHandle,
+}
+
+async fn serve_admin(context: &AdminContext, request: Request) -> Response {
+    if !admin::authorized(&request, &context.reloader.current().admin.token) {
+        tracing::warn!(
+            "Refused admin {} {}: bad token",
+            request.method,
+            request.path
+        );
+        return admin::error(401, "missing or wrong admin token");
+    }
+    let action = match AdminRequest::parse(&request) {
+        Ok(action) => action,
+        Err(response) => return response,
+    };
+    tracing::info!("Admin request: {:?}", action);
+
+    match action {
+        AdminRequest::ListRooms => {
+            let mut rooms = context.rooms.describe_rooms().await;
+            rooms.sort_by(|a, b| a.code.cmp(&b.code));
+            admin::json(200, &rooms)
+        }
+
+        AdminRequest::ListSessions => {
+            let mut sessions: Vec<SessionSummary> = ask_workers(&context.workers, |reply| {
+                WorkerCommand::ListSessions { reply }
+            })
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+            sessions.sort_by_key(|session| session.player_id);
+            admin::json(200, &sessions)
+        }
+
+        AdminRequest::KickPlayer { player_id, reason } => {
+            let kicked = ask_workers(&context.workers, |reply| WorkerCommand::Kick {
+                player_id,
+                reason: reason.clone(),
+                reply,
+            })
+            .await;
+            if kicked.contains(&true) {
+                admin::json(200, &serde_json::json!({ "kicked": player_id }))
+            } else {
+                admin::error(404, format!("no session for player {}", player_id))
+            }
+        }
 
-    let sessions = Arc::new(Mutex::new(SessionManager::new(30)));
-    let rooms = Arc::new(Mutex::new(RoomManager::new(4)));
+        AdminRequest::CloseRoom { room_code, reason } => {
+            if context.rooms.room(&room_code).is_none() {
+                return admin::error(404, format!("no room {}", room_code));
+            }
+            let removed: usize = ask_workers(&context.workers, |reply| WorkerCommand::CloseRoom {
+                room_code: room_code.clone(),
+                reason: reason.clone(),
+                reply,
+            })
+            .await
+            .into_iter()
+            .sum();
+            admin::json(
+                200,
+                &serde_json::json!({ "closed": room_code, "players_removed": removed }),
+            )
+        }
+
+        AdminRequest::StartGame { room_code } => {
+            let Some(room) = context.rooms.room(&room_code) else {
+                return admin::error(404, format!("no room {}", room_code));
+            };
+            let (reply, result) = oneshot::channel();
+            if !room.send(RoomCommand::ForceStart { reply }).await {
+                return admin::error(404, format!("no room {}", room_code));
+            }
+            match result.await {
+                Ok(Ok(())) => admin::json(200, &serde_json::json!({ "started": room_code })),
+                Ok(Err(RoomError::GameInProgress)) => {
+                    admin::error(409, format!("room {} is not waiting to start", room_code))
+                }
+                Ok(Err(e)) => admin::error(409, format!("{:?}", e)),
+                Err(_) => admin::error(404, format!("no room {}", room_code)),
+            }
+        }
+
+        AdminRequest::Notice { message } => {
+            let notified: usize = ask_workers(&context.workers, |reply| WorkerCommand::Notice {
+                message: message.clone(),
+                reply,
+            })
+            .await
+            .into_iter()
+            .sum();
+            admin::json(200, &serde_json::json!({ "notified": notified }))
+        }
 
-    // Cleanup task for timed-out sessions
-    let sessions_cleanup = sessions.clone();
-    let rooms_cleanup = rooms.clone();
-    let server_cleanup = server.clone();
+        AdminRequest::SetLogFilter { filter } => {
+            let parsed = match EnvFilter::try_new(&filter) {
+                Ok(parsed) => parsed,
+                Err(e) => return admin::error(400, format!("invalid log filter: {}", e)),
+            };
+            match context.log_filter.reload(parsed) {
+                Ok(()) => {
+                    tracing::info!("Log filter set to {} by admin request", filter);
+                    admin::json(200, &serde_json::json!({ "log_filter": filter }))
+                }
+                Err(e) => admin::error(500, format!("cannot apply log filter: {}", e)),
+            }
+        }
+
+        AdminRequest::ReloadConfig => match context.reloader.reload() {
+            Ok(report) => admin::json(200, &report),
+            Err(e) => admin::error(400, e.to_string()),
+        },
+    }
+}
+
+/// Reload the configuration each time the process receives SIGHUP
+#[cfg(unix)]
+fn reload_on_hangup(reloader: Arc<ConfigReloader>) -> std::io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = signal(SignalKind::hangup())?;
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(5));
-        loop {
-            interval.tick().await;
-            let mut sessions = sessions_cleanup.lock().await;
-            let mut rooms = rooms_cleanup.lock().await;
+        while hangups.recv().await.is_some() {
+            tracing::info!("SIGHUP received, reloading config");
+            let _ = reloader.reload();
+        }
+    });
+    Ok(())
+}
 
-            let disconnected_players = sessions.mark_timed_out_as_disconnected();
-            let grace_period_seconds = sessions.grace_period_seconds();
+/// Apply a reloaded configuration to a worker's live state
+fn apply_config(
+    sessions: &mut SessionManager,
+    rooms: &mut RoomDirectory,
+    ingress: &mut Ingress,
+    config: &ServerConfig,
+) {
+    sessions.reconfigure(&config.session, &config.rate_limit);
+    rooms.reconfigure(config.room.clone());
+    rooms.reconfigure_matchmaking(config.matchmaking.clone());
+    ingress.address_limiter.reconfigure(
+        config.rate_limit.address_per_second,
+        config.rate_limit.address_burst,
+    );
+}
+
+/// Vet one datagram and route it to the session's handler or room task
+async fn handle_datagram(
+    server: &UdpServer,
+    sessions: &mut SessionManager,
+    rooms: &mut RoomDirectory,
+    ingress: &mut Ingress,
+    peers: &Peers,
+    data: Vec<u8>,
+    addr: SocketAddr,
+) {
+    let received_at_ms = current_timestamp_ms();
+    let received_at = Instant::now();
+
+    // Cheap check before decoding anything, so a flood from one address
+    // cannot starve everyone else
+    if !ingress.address_limiter.allow(addr, received_at) {
+        ingress
+            .rate_limit_counters
+            .address_dropped
+            .fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let mut msg = match ClientMessage::decode(&data[..]) {
+        Ok(msg) => msg,
+        Err(e) => {
+            tracing::warn!("Failed to decode from {}: {}", addr, e);
+            metrics::global().record_decode_failure(DecodeStage::Datagram);
+            return;
+        }
+    };
+
+    if let Some(Payload::Hello(_)) = msg.payload {
+        metrics::global().record_received(&msg.payload);
+        handle_hello(server, ingress, addr, data.len()).await;
+        return;
+    }
+
+    // Nothing from an address without a session is processed or buffered
+    // until it proves it can receive there by echoing a handshake cookie
+    let has_session = sessions.get_by_addr(&addr).is_some();
+    if !has_session {
+        let joining = matches!(msg.payload, Some(Payload::Fragment(_))) || opens_session(&msg);
+        if !joining || !ingress.cookies.verify(&addr, &msg.cookie) {
+            tracing::debug!("Dropping message from {} without a valid handshake", addr);
+            return;
+        }
+    }
+
+    if let Some(Payload::Fragment(fragment)) = msg.payload {
+        let data = match ingress.reassembler.insert(addr, fragment) {
+            Ok(Some(data)) => data,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("Dropping fragment from {}: {:?}", addr, e);
+                return;
+            }
+        };
+
+        msg = match ClientMessage::decode(&data[..]) {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::warn!("Failed to decode reassembled message from {}: {}", addr, e);
+                metrics::global().record_decode_failure(DecodeStage::Reassembled);
+                return;
+            }
+        };
+
+        // A message too large for one datagram before a session exists, such
+        // as a CreateRoom with metadata, passes the same gate once whole
+        if !has_session && (!opens_session(&msg) || !ingress.cookies.verify(&addr, &msg.cookie)) {
+            tracing::debug!(
+                "Dropping reassembled message from {} without a valid handshake",
+                addr
+            );
+            return;
+        }
+    }
+
+    let mut sealed = false;
+    if let Some(Payload::Sealed(envelope)) =
+        msg.payload.take_if(|p| matches!(p, Payload::Sealed(_)))
+    {
+        sealed = true;
+
+        // The key exchange a sealed join starts, named before the envelope is
+        // replaced by the message it carries
+        let handshake = (!has_session).then(|| {
+            (
+                [msg.cookie.as_slice(), msg.public_key.as_slice()].concat(),
+                ingress.cookies.remaining_lifetime(&msg.cookie),
+            )
+        });
+
+        let cipher = if has_session {
+            sessions
+                .link(&addr)
+                .and_then(|link| link.lock().unwrap().cipher.clone())
+        } else if ingress.encrypt_transport {
+            // Sealed join: derive the key from the cookie it echoed
+            let secret = ingress.cookies.handshake_secret(&msg.cookie);
+            match SessionCipher::new(Role::Server, &secret, &msg.public_key, &msg.cookie) {
+                Ok(cipher) => Some(cipher),
+                Err(e) => {
+                    tracing::warn!("Key exchange with {} failed: {:?}", addr, e);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        let Some(cipher) = cipher else {
+            tracing::warn!("Sealed message from {} without a negotiated key", addr);
+            return;
+        };
+
+        msg = match cipher
+            .open(&envelope)
+            .map(|data| ClientMessage::decode(&data[..]))
+        {
+            Ok(Ok(inner)) => inner,
+            Ok(Err(e)) => {
+                tracing::warn!("Failed to decode sealed message from {}: {}", addr, e);
+                metrics::global().record_decode_failure(DecodeStage::Sealed);
+                return;
+            }
+            Err(e) => {
+                tracing::warn!("Dropping sealed message from {}: {:?}", addr, e);
+                metrics::global().record_decode_failure(DecodeStage::Sealed);
+                return;
+            }
+        };
 
-            for player_id in disconnected_players {
-                let session_data = sessions
-                    .get_by_player_id(player_id)
-                    .map(|s| (s.addr, s.room_code.clone()));
+        // Binding the nonce to a nonzero sequence lets the duplicate check reject replays
+        if msg.sequence == 0 || msg.sequence != crypto::nonce_sequence(envelope.nonce) {
+            tracing::warn!("Sealed message from {} has a mismatched sequence", addr);
+            return;
+        }
+
+        if !has_session {
+            if !matches!(
+                msg.payload,
+                Some(Payload::JoinRoom(_))
+                    | Some(Payload::CreateRoom(_))
+                    | Some(Payload::ListRooms(_))
+                    | Some(Payload::FindMatch(_))
+                    | Some(Payload::Reconnect(_))
+            ) {
+                tracing::debug!("Dropping sealed message from {} without a session", addr);
+                return;
+            }
+            if let Some((handshake, lifetime)) = handshake
+                && !sessions.stage_cipher(addr, handshake, cipher, lifetime)
+            {
+                tracing::debug!(
+                    "Dropping sealed message from {}: its handshake already started a session",
+                    addr
+                );
+                return;
+            }
+        }
+    }
+
+    // Probe acks carry nothing but a probe id, so they stay plaintext to
+    // keep probe sizes exact
+    let exempt = matches!(msg.payload, Some(Payload::MtuProbeAck(_)));
+    if sealed != ingress.encrypt_transport && !exempt {
+        tracing::debug!(
+            "Dropping {} message from {}",
+            if sealed { "sealed" } else { "plaintext" },
+            addr
+        );
+        return;
+    }
+
+    metrics::global().record_received(&msg.payload);
+
+    // Everything from here on logs within the span of this message
+    let span = message_span(peers.id, &msg.payload, addr, sessions.get_by_addr(&addr));
+    async move {
+        let class = MessageClass::of(&msg.payload);
+        let verdict = sessions.rate_limit(&addr, class);
+        ingress.rate_limit_counters.record(class, &verdict);
+        match verdict {
+            RateLimitVerdict::Allow => {}
+            RateLimitVerdict::Drop => return,
+            RateLimitVerdict::Warn => {
+                tracing::warn!("{:?} rate limit exceeded by {}", class, addr);
+                send_reliable(
+                    server,
+                    sessions,
+                    addr,
+                    server_message::Payload::Error(Error {
+                        message: "Rate limit exceeded, messages are being dropped".to_string(),
+                        ..Default::default()
+                    }),
+                )
+                .await;
+                return;
+            }
+            RateLimitVerdict::Disconnect => {
+                tracing::warn!("Disconnecting {} for exceeding the rate limit", addr);
+                send_unreliable(
+                    server,
+                    sessions,
+                    addr,
+                    server_message::Payload::Error(Error {
+                        message: "Disconnected: rate limit exceeded".to_string(),
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .ok();
+                remove_session(sessions, rooms, addr).await;
+                return;
+            }
+        }
+
+        sessions.process_acks(&addr, msg.ack, msg.ack_bits);
+
+        let sequence_check = sessions.check_sequence(&addr, msg.sequence);
+        metrics::global().record_sequence_check(&sequence_check);
+
+        match sequence_check {
+            SequenceCheck::Valid => {}
+            SequenceCheck::Gap(gap) => {
+                tracing::warn!("Server detected packet loss ({gap} packets)");
+            }
+            SequenceCheck::OutOfOrder => {
+                tracing::debug!("Server accepted out of order packet");
+            }
+            // A retransmit may fall outside the ack window; ordered game
+            // messages are deduplicated by their reliable sequence instead
+            SequenceCheck::Duplicate if is_ordered_game_message(&msg) => {}
+            SequenceCheck::Duplicate => {
+                tracing::warn!("Server detected duplicate packet");
+                return;
+            }
+            SequenceCheck::Invalid => {
+                tracing::warn!("Server detected invalid packet");
+            }
+        }
 
-                let Some((session_addr, Some(room_code))) = session_data else {
-                    continue;
+        match msg.payload {
+            Some(Payload::JoinRoom(join)) => {
+                handle_join_room(server, sessions, rooms, ingress, addr, join, None).await;
+            }
+
+            Some(Payload::CreateRoom(create)) => {
+                let settings = create.settings.unwrap_or_default();
+                let join = JoinRoom {
+                    room_code: create.room_code,
+                    player_name: create.player_name,
+                    password: create.password,
+                    intent: JoinIntent::Create as i32,
+                    private: create.private,
                 };
+                handle_join_room(server, sessions, rooms, ingress, addr, join, Some(settings))
+                    .await;
+            }
+
+            Some(Payload::ListRooms(query)) => {
+                handle_list_rooms(server, sessions, rooms, addr, query).await;
+            }
+
+            Some(Payload::FindMatch(request)) => {
+                handle_find_match(server, sessions, rooms, ingress, addr, request).await;
+            }
+
+            Some(Payload::CancelMatchmaking(_)) => {
+                if let Some(session) = sessions.get_by_addr(&addr) {
+                    rooms.cancel_match(session.player_id);
+                }
+            }
+
+            Some(Payload::LeaveRoom(_)) => {
+                handle_leave_room(sessions, rooms, addr).await;
+            }
+
+            Some(Payload::Ready(_)) => {
+                handle_ready(sessions, rooms, addr).await;
+            }
+
+            Some(Payload::GameMessage(game_msg)) => {
+                handle_game_message(
+                    sessions,
+                    rooms,
+                    addr,
+                    game_msg,
+                    msg.reliable_sequence,
+                    sequence_check,
+                    received_at,
+                );
+            }
+
+            Some(Payload::Ping(ping)) => {
+                handle_ping(server, sessions, addr, ping, received_at_ms).await;
+            }
+
+            Some(Payload::Reconnect(reconnect)) => {
+                handle_reconnect(server, sessions, rooms, peers, addr, reconnect).await;
+            }
+
+            Some(Payload::KickPlayer(kick)) => {
+                handle_host_action(sessions, rooms, addr, |player_id| RoomCommand::Kick {
+                    player_id,
+                    target: kick.player_id,
+                })
+                .await;
+            }
+
+            Some(Payload::LockRoom(_)) => {
+                handle_host_action(sessions, rooms, addr, |player_id| RoomCommand::SetLocked {
+                    player_id,
+                    locked: true,
+                })
+                .await;
+            }
+
+            Some(Payload::UnlockRoom(_)) => {
+                handle_host_action(sessions, rooms, addr, |player_id| RoomCommand::SetLocked {
+                    player_id,
+                    locked: false,
+                })
+                .await;
+            }
 
-                let player_ids = rooms.get_room_player_ids(&room_code);
-
-                for pid in player_ids {
-                    if pid != player_id {
-                        let other_addr = sessions.get_by_player_id(pid).map(|s| s.addr);
-
-                        let Some(other_addr) = other_addr else {
-                            continue;
-                        };
-
-                        let msg = ServerMessage {
-                            sequence: sessions.next_send_sequence(&other_addr),
-                            payload: Some(server_message::Payload::PlayerDisconnected(
-                                PlayerDisconnected {
-                                    player_id,
-                                    grace_period_seconds,
-                                },
-                            )),
-                        };
-                        let _ = server_cleanup.send(&msg.encode_to_vec(), other_addr).await;
+            Some(Payload::TransferHost(transfer)) => {
+                handle_host_action(sessions, rooms, addr, |player_id| {
+                    RoomCommand::TransferHost {
+                        player_id,
+                        target: transfer.player_id,
                     }
+                })
+                .await;
+            }
+
+            Some(Payload::MtuProbeAck(ack)) => {
+                sessions.acknowledge_mtu_probe(&addr, ack.probe_id);
+            }
+
+            Some(Payload::Fragment(_)) => {
+                tracing::warn!("Nested fragment from {}", addr);
+            }
+
+            Some(Payload::Hello(_)) => {
+                tracing::warn!("Hello inside a fragmented message from {}", addr);
+            }
+
+            Some(Payload::Sealed(_)) => {
+                tracing::warn!("Nested sealed message from {}", addr);
+            }
+
+            None => {
+                tracing::warn!("Empty message from {}", addr);
+            }
+        }
+    }
+    .instrument(span)
+    .await;
+}
+
+/// Span a client message is handled in, carrying its kind, address and the
+/// player's id and room as far as they are known. Game messages get a trace
+/// level span, so the relay hot path only pays for it when tracing at that level.
+fn message_span(
+    worker: usize,
+    payload: &Option<Payload>,
+    addr: SocketAddr,
+    session: Option<&Session>,
+) -> tracing::Span {
+    use tracing::field::Empty;
+
+    let kind = metrics::CLIENT_KINDS[metrics::client_kind(payload)];
+    let span = if matches!(payload, Some(Payload::GameMessage(_))) {
+        tracing::trace_span!(parent: None, "message", worker, kind, %addr, player_id = Empty, room_code = Empty)
+    } else {
+        tracing::info_span!(parent: None, "message", worker, kind, %addr, player_id = Empty, room_code = Empty)
+    };
+
+    if let Some(session) = session {
+        span.record("player_id", session.player_id);
+        if let Some(room_code) = &session.room_code {
+            span.record("room_code", room_code.as_str());
+        }
+    }
+    span
+}
+
+async fn handle_room_event(
+    server: &UdpServer,
+    sessions: &mut SessionManager,
+    rooms: &mut RoomDirectory,
+    ingress: &Ingress,
+    event: RoomEvent,
+) {
+    match event {
+        RoomEvent::JoinRejected {
+            player_id,
+            room_code,
+        }
+        | RoomEvent::Kicked {
+            player_id,
+            room_code,
+        } => {
+            rooms.drop_route(player_id, &room_code);
+
+            let addr = sessions.get_by_player_id(player_id).map(|s| s.addr);
+            if let Some(session) = addr.and_then(|addr| sessions.get_by_addr_mut(&addr))
+                && session.room_code.as_deref() == Some(room_code.as_str())
+            {
+                session.room_code = None;
+            }
+        }
+        RoomEvent::MatchStatus { player_id, status } => {
+            let Some(addr) = sessions.get_by_player_id(player_id).map(|s| s.addr) else {
+                return;
+            };
+            // Each search update is superseded by the next, so only the end of
+            // the search needs to arrive
+            let searching = status.state() == MatchmakingState::Searching;
+            let payload = server_message::Payload::MatchmakingStatus(status);
+            if searching {
+                let _ = send_unreliable(server, sessions, addr, payload).await;
+            } else {
+                send_reliable(server, sessions, addr, payload).await;
+            }
+        }
+        RoomEvent::MatchFound {
+            player_id,
+            status,
+            password,
+        } => {
+            let room_code = status.room_code.clone();
+            let span = session_span(player_id, Some(&room_code));
+            async {
+                // A player who joined a room of their own meanwhile stays there
+                if let Some(session) = sessions.get_by_player_id(player_id)
+                    && session.room_code.is_none()
+                {
+                    let addr = session.addr;
+                    // The matchmaker already started the room, so the join
+                    // must find it rather than create one
+                    let join = JoinRoom {
+                        room_code: room_code.clone(),
+                        player_name: session.player_name.clone(),
+                        password,
+                        intent: JoinIntent::Join as i32,
+                        private: true,
+                    };
+
+                    tracing::info!("Player {} matched into room {}", player_id, room_code);
+                    send_reliable(
+                        server,
+                        sessions,
+                        addr,
+                        server_message::Payload::MatchmakingStatus(status),
+                    )
+                    .await;
+                    handle_join_room(server, sessions, rooms, ingress, addr, join, None).await;
                 }
+
+                // Unless the player made it in, the room stops holding their place
+                rooms.release_reservation(player_id, &room_code);
+            }
+            .instrument(span)
+            .await;
+        }
+        RoomEvent::MatchRefused { player_id, error } => {
+            let Some(addr) = sessions.get_by_player_id(player_id).map(|s| s.addr) else {
+                return;
+            };
+            send_reliable(
+                server,
+                sessions,
+                addr,
+                server_message::Payload::Error(Error {
+                    message: format!("Failed to queue for a match: {:?}", error),
+                    code: error.code() as i32,
+                }),
+            )
+            .await;
+        }
+    }
+}
+
+/// Mark silent sessions as disconnected, drop those whose grace period ran out
+/// and forget sealed handshakes that can no longer be used
+async fn cleanup_sessions(sessions: &mut SessionManager, rooms: &mut RoomDirectory) {
+    sessions.expire_staged_ciphers();
+
+    let disconnected_players = sessions.mark_timed_out_as_disconnected();
+    let grace_period_seconds = sessions.grace_period_seconds();
+
+    for player_id in disconnected_players {
+        rooms.cancel_match(player_id);
+        let Some(room_code) = rooms.get_player_room_code(player_id).map(str::to_string) else {
+            continue;
+        };
+
+        let span = session_span(player_id, Some(&room_code));
+        async {
+            rooms
+                .send(
+                    player_id,
+                    RoomCommand::Disconnected {
+                        player_id,
+                        grace_period_seconds,
+                    },
+                )
+                .await;
+            tracing::info!(
+                "Player {player_id} disconnected from room {room_code} (grace period: {grace_period_seconds}s)"
+            );
+        }
+        .instrument(span)
+        .await;
+    }
+
+    let expired_sessions = sessions.cleanup_expired_disconnected();
+
+    for session in expired_sessions {
+        let span = session_span(session.player_id, session.room_code.as_deref());
+        async {
+            if let Some(room_code) = rooms.leave_room(session.player_id).await {
                 tracing::info!(
-                    "Player {player_id} disconnected from room {room_code} (grace period: {grace_period_seconds}s)"
+                    "Player {} permanently removed from room {}",
+                    session.player_id,
+                    room_code
                 );
             }
+        }
+        .instrument(span)
+        .await;
+    }
+}
 
-            let expired_sessions = sessions.cleanup_expired_disconnected();
+/// Span of work on a player's session that no message of theirs started,
+/// such as a timeout or an admin kick
+fn session_span(player_id: PlayerId, room_code: Option<&str>) -> tracing::Span {
+    tracing::info_span!(parent: None, "session", player_id, room_code)
+}
+
+/// Send a lobby or lifecycle message on the reliable channel
+async fn send_reliable(
+    server: &UdpServer,
+    sessions: &mut SessionManager,
+    addr: SocketAddr,
+    payload: server_message::Payload,
+) {
+    let message = sessions.next_reliable_message(&addr, payload);
+    let message = sessions.seal(&addr, message);
+    let _ = server
+        .send_message(&message, addr, sessions.mtu(&addr))
+        .await;
+}
 
-            for session in expired_sessions {
-                if let Some(room_code) = &session.room_code {
-                    let player_ids = rooms.get_room_player_ids(room_code);
-                    rooms.leave_room(session.player_id);
+/// Send a message without retransmission
+async fn send_unreliable(
+    server: &UdpServer,
+    sessions: &mut SessionManager,
+    addr: SocketAddr,
+    payload: server_message::Payload,
+) -> std::io::Result<()> {
+    let message = sessions.next_message(&addr, payload);
+    let message = sessions.seal(&addr, message);
+    server
+        .send_message(&message, addr, sessions.mtu(&addr))
+        .await
+}
 
-                    let msg = ServerMessage {
-                        sequence: sessions.next_send_sequence(&session.addr),
-                        payload: Some(server_message::Payload::PlayerLeft(PlayerLeft {
-                            player_id: session.player_id,
-                        })),
-                    };
+/// Answer a Hello with a stateless cookie the client must echo to join
+async fn handle_hello(server: &UdpServer, ingress: &Ingress, addr: SocketAddr, size: usize) {
+    if size < MIN_HELLO_SIZE {
+        tracing::debug!("Ignoring unpadded Hello from {} ({} bytes)", addr, size);
+        return;
+    }
 
-                    let bytes = msg.encode_to_vec();
+    let cookie = ingress.cookies.issue(&addr);
+    let public_key = if ingress.encrypt_transport {
+        crypto::public_key(&ingress.cookies.handshake_secret(&cookie)).to_vec()
+    } else {
+        Vec::new()
+    };
 
-                    for pid in player_ids {
-                        if pid != session.player_id {
-                            if let Some(other) = sessions.get_by_player_id(pid) {
-                                let _ = server_cleanup.send(&bytes, other.addr).await;
-                            }
-                        }
-                    }
+    let challenge = ServerMessage {
+        payload: Some(server_message::Payload::Challenge(Challenge {
+            cookie,
+            public_key,
+        })),
+        ..Default::default()
+    };
+    metrics::global().record_sent(&challenge.payload);
+    let _ = server.send(&challenge.encode_to_vec(), addr).await;
+    tracing::debug!("Sent Challenge to {}", addr);
+}
 
-                    tracing::info!(
-                        "Player {} permanently removed from room {}",
-                        session.player_id,
-                        room_code
-                    );
-                }
+/// Apply a request from another receive worker
+async fn handle_worker_command(
+    server: &UdpServer,
+    sessions: &mut SessionManager,
+    rooms: &mut RoomDirectory,
+    ingress: &mut Ingress,
+    command: WorkerCommand,
+) {
+    match command {
+        WorkerCommand::Release { player_id, reply } => {
+            let handover = sessions.release(player_id).map(|session| Handover {
+                session,
+                route: rooms.detach(player_id),
+            });
+            if handover.is_some() {
+                tracing::debug!("Handing player {} over to another worker", player_id);
             }
+            let _ = reply.send(handover);
         }
-    });
-
-    // Main receive loop
-    loop {
-        let (data, addr) = match server.recv().await {
-            Ok(result) => result,
-            Err(e) => {
-                tracing::debug!("recv error - sent to closed port. Ignoring. Error: {}", e);
-                continue;
-            }
-        };
-
-        let msg = match ClientMessage::decode(&data[..]) {
-            Ok(msg) => msg,
-            Err(e) => {
-                tracing::warn!("Failed to decode from {}: {}", addr, e);
-                continue;
-            }
-        };
-
-        let mut sessions = sessions.lock().await;
-        let mut rooms = rooms.lock().await;
 
-        match sessions.check_sequence(&addr, msg.sequence) {
-            SequenceCheck::Valid => {}
-            SequenceCheck::Gap(gap) => {
-                tracing::warn!("Server detected packet loss");
-            }
-            SequenceCheck::Duplicate => {
-                tracing::warn!("Server detected duplicate packet");
-                continue;
-            }
-            SequenceCheck::Invalid => {
-                tracing::warn!("Server detected invalid packet");
+        WorkerCommand::ResumeReconnect {
+            addr,
+            reconnect,
+            handover,
+            span,
+        } => {
+            if let Some(handover) = handover {
+                let Handover { session, route } = *handover;
+                let player_id = session.player_id;
+                sessions.adopt(session);
+                if let Some(route) = route {
+                    rooms.attach(player_id, route);
+                }
             }
+            finish_reconnect(server, sessions, rooms, addr, reconnect)
+                .instrument(span)
+                .await;
         }
 
-        match msg.payload {
-            Some(Payload::JoinRoom(join)) => {
-                handle_join_room(&server, &mut sessions, &mut rooms, addr, join).await;
-            }
+        WorkerCommand::CountSessions { reply } => {
+            let _ = reply.send(sessions.counts());
+        }
 
-            Some(Payload::LeaveRoom(_)) => {
-                handle_leave_room(&server, &mut sessions, &mut rooms, addr).await;
-            }
+        WorkerCommand::ListSessions { reply } => {
+            let _ = reply.send(sessions.summaries());
+        }
 
-            Some(Payload::Ready(_)) => {
-                handle_ready(&server, &mut sessions, &mut rooms, addr).await;
+        WorkerCommand::Kick {
+            player_id,
+            reason,
+            reply,
+        } => {
+            let Some(session) = sessions.get_by_player_id(player_id) else {
+                let _ = reply.send(false);
+                return;
+            };
+            let addr = session.addr;
+            let span = session_span(player_id, session.room_code.as_deref());
+
+            async {
+                let _ = send_unreliable(
+                    server,
+                    sessions,
+                    addr,
+                    server_message::Payload::Error(Error {
+                        message: with_reason("Kicked from the server", &reason),
+                        ..Default::default()
+                    }),
+                )
+                .await;
+                remove_session(sessions, rooms, addr).await;
+                tracing::info!("Player {} kicked by admin request", player_id);
             }
+            .instrument(span)
+            .await;
+            let _ = reply.send(true);
+        }
 
-            Some(Payload::GameMessage(game_msg)) => {
-                handle_game_message(&server, &mut sessions, &rooms, addr, game_msg.payload).await;
+        WorkerCommand::CloseRoom {
+            room_code,
+            reason,
+            reply,
+        } => {
+            let players = rooms.players_in(&room_code);
+            for &player_id in &players {
+                if let Some(addr) = sessions.get_by_player_id(player_id).map(|s| s.addr) {
+                    send_reliable(
+                        server,
+                        sessions,
+                        addr,
+                        server_message::Payload::Error(Error {
+                            message: with_reason("Room closed by the server", &reason),
+                            ..Default::default()
+                        }),
+                    )
+                    .await;
+                    if let Some(session) = sessions.get_by_addr_mut(&addr) {
+                        session.room_code = None;
+                    }
+                }
+                rooms.leave_room(player_id).await;
             }
-
-            Some(Payload::Ping(ping)) => {
-                handle_ping(&server, &mut sessions, addr, ping).await;
+            if !players.is_empty() {
+                tracing::info!(
+                    "Removed {} players from room {} by admin request",
+                    players.len(),
+                    room_code
+                );
             }
+            let _ = reply.send(players.len());
+        }
 
-            Some(Payload::Reconnect(reconnect)) => {
-                handle_reconnect(&server, &mut sessions, &mut rooms, addr, reconnect).await;
+        WorkerCommand::Notice { message, reply } => {
+            let addrs = sessions.connected_addrs();
+            for &addr in &addrs {
+                send_reliable(
+                    server,
+                    sessions,
+                    addr,
+                    server_message::Payload::ServerNotice(ServerNotice {
+                        message: message.clone(),
+                    }),
+                )
+                .await;
             }
+            let _ = reply.send(addrs.len());
+        }
 
-            None => {
-                tracing::warn!("Empty message from {}", addr);
+        WorkerCommand::Shutdown { notice } => {
+            let addrs = sessions.connected_addrs();
+            tracing::info!("Notifying {} sessions of the shutdown", addrs.len());
+            for addr in addrs {
+                send_reliable(
+                    server,
+                    sessions,
+                    addr,
+                    server_message::Payload::ServerShuttingDown(notice.clone()),
+                )
+                .await;
             }
+            ingress.shutting_down = Some(notice);
         }
     }
 }
 
+/// `message`, followed by the operator's reason if they gave one
+fn with_reason(message: &str, reason: &str) -> String {
+    if reason.is_empty() {
+        message.to_string()
+    } else {
+        format!("{}: {}", message, reason)
+    }
+}
+
 async fn handle_reconnect(
-    server: &Arc<UdpServer>,
+    server: &UdpServer,
+    sessions: &mut SessionManager,
+    rooms: &mut RoomDirectory,
+    peers: &Peers,
+    addr: SocketAddr,
+    reconnect: Reconnect,
+) {
+    // A new address may hash to a different worker than the one holding the
+    // session, which then has to be handed over first
+    if peers.workers.len() > 1
+        && let Some(player_id) = sessions.token_player(&reconnect.token)
+        && sessions.get_by_player_id(player_id).is_none()
+    {
+        peers.request_handover(player_id, addr, reconnect);
+        return;
+    }
+
+    finish_reconnect(server, sessions, rooms, addr, reconnect).await;
+}
+
+async fn finish_reconnect(
+    server: &UdpServer,
     sessions: &mut SessionManager,
-    rooms: &mut RoomManager,
+    rooms: &mut RoomDirectory,
     addr: SocketAddr,
     reconnect: Reconnect,
 ) {
-    let Some(session) =
+    let Some((session, displaced)) =
         sessions.reconnected_by_token(&reconnect.token, addr, reconnect.player_name.clone())
     else {
-        let response = ServerMessage {
-            sequence: sessions.next_send_sequence(&addr),
-            payload: Some(server_message::Payload::Error(Error {
+        send_reliable(
+            server,
+            sessions,
+            addr,
+            server_message::Payload::Error(Error {
                 message: "Reconnection failed: invalid token or grace period expired".to_string(),
-            })),
-        };
-        let _ = server.send(&response.encode_to_vec(), addr).await;
+                ..Default::default()
+            }),
+        )
+        .await;
         tracing::warn!("Failed reconnection attempt from {addr}");
+        metrics::global().record_reconnect(false);
         return;
     };
+    metrics::global().record_reconnect(true);
 
     let player_id = session.player_id;
     let reconnect_token = session.reconnect_token.clone();
+    let in_room = session.room_code.is_some();
+    let span = tracing::Span::current();
+    span.record("player_id", player_id);
+    if let Some(room_code) = &session.room_code {
+        span.record("room_code", room_code.as_str());
+    }
+
+    if let Some(displaced) = displaced {
+        rooms.cancel_match(displaced.player_id);
+        if let Some(room_code) = rooms.leave_room(displaced.player_id).await {
+            tracing::info!(
+                "Player {} removed from room {}: their address reconnected as player {}",
+                displaced.player_id,
+                room_code,
+                player_id
+            );
+        }
+    }
 
-    let Some(room_code) = session.room_code.clone() else {
-        let response = ServerMessage {
-            sequence: sessions.next_send_sequence(&addr),
-            payload: Some(server_message::Payload::RoomJoined(RoomJoined {
+    if !in_room {
+        send_reliable(
+            server,
+            sessions,
+            addr,
+            server_message::Payload::RoomJoined(RoomJoined {
                 player_id,
                 room_code: String::new(),
                 players: vec![],
                 reconnect_token,
-            })),
-        };
-        let _ = server.send(&response.encode_to_vec(), addr).await;
+                ..Default::default()
+            }),
+        )
+        .await;
         tracing::info!("Player {} reconnected (no room)", player_id);
         return;
-    };
+    }
 
-    let Some(room) = rooms.get_room(&room_code) else {
-        let response = ServerMessage {
-            sequence: sessions.next_send_sequence(&addr),
-            payload: Some(server_message::Payload::Error(Error {
-                message: "Room no longer exists".to_string(),
-            })),
-        };
+    // The room task replies with the player list and tells everyone else
+    let delivered = rooms.reconnected(player_id, reconnect_token).await;
 
-        let _ = server.send(&response.encode_to_vec(), addr).await;
+    if !delivered {
+        send_reliable(
+            server,
+            sessions,
+            addr,
+            server_message::Payload::Error(Error {
+                message: "Room no longer exists".to_string(),
+                code: ErrorCode::RoomNotFound as i32,
+            }),
+        )
+        .await;
 
         if let Some(session) = sessions.get_by_addr_mut(&addr) {
             session.room_code = None;
         }
         return;
-    };
-
-    let players: Vec<PlayerInfo> = room
-        .players
-        .values()
-        .map(|p| PlayerInfo {
-            player_id: p.player_id,
-            name: p.name.clone(),
-            ready: p.ready,
-        })
-        .collect();
-
-    let player_ids = room.get_player_ids();
-
-    let response = ServerMessage {
-        sequence: sessions.next_send_sequence(&addr),
-        payload: Some(server_message::Payload::RoomJoined(RoomJoined {
-            player_id,
-            room_code: room_code.clone(),
-            players: players.clone(),
-            reconnect_token,
-        })),
-    };
-
-    let _ = server.send(&response.encode_to_vec(), addr).await;
-
-    let reconnect_msg = ServerMessage {
-        sequence: sessions.next_send_sequence(&addr),
-        payload: Some(server_message::Payload::PlayerReconnected(
-            PlayerReconnected { player_id },
-        )),
-    };
-
-    let reconnect_msg_bytes = reconnect_msg.encode_to_vec();
-
-    for pid in player_ids {
-        if pid != player_id {
-            if let Some(other) = sessions.get_by_player_id(pid) {
-                let _ = server.send(&reconnect_msg_bytes, other.addr).await;
-            }
-        }
     }
 
     tracing::info!(
-        "Player {} ({}) reconnected to room {}",
+        "Player {} ({}) reconnected",
         player_id,
-        reconnect.player_name,
-        room_code
+        reconnect.player_name
     );
 }
 
@@ -288,8 +1708,9 @@ async fn handle_ping(
     sessions: &mut SessionManager,
     addr: std::net::SocketAddr,
     ping: Ping,
+    received_at_ms: u64,
 ) {
-    sessions.ping(&addr);
+    sessions.ping(&addr, &ping, received_at_ms);
 
     if let Some(session) = sessions.get_by_addr(&addr) {
         tracing::trace!(
@@ -302,285 +1723,336 @@ async fn handle_ping(
         tracing::warn!("Ping from unknown address {}", addr);
     }
 
-    let current_server_timestamp = current_timestamp_ms();
-
-    let pong_message = ServerMessage {
-        sequence: sessions.next_send_sequence(&addr),
-        payload: Some(server_message::Payload::Pong(Pong {
-            timestamp: ping.timestamp,
-            sequence: ping.sequence,
-            server_time: current_server_timestamp,
-        })),
-    };
+    let pong = server_message::Payload::Pong(Pong {
+        timestamp: ping.timestamp,
+        sequence: ping.sequence,
+        server_time: current_timestamp_ms(),
+        server_receive_time: received_at_ms,
+    });
 
     tracing::debug!("Sending Pong to {}", addr);
-    match server.send(&pong_message.encode_to_vec(), addr).await {
-        Ok(msg) => msg,
-        Err(e) => {
-            tracing::warn!("Failed to send pong: {}", e);
-        }
-    };
+    if let Err(e) = send_unreliable(server, sessions, addr, pong).await {
+        tracing::warn!("Failed to send pong: {}", e);
+    }
     tracing::debug!("Sent Pong");
-
 }
 
+/// Join or create a room, with `settings` if the client asked for its own
 async fn handle_join_room(
     server: &UdpServer,
     sessions: &mut SessionManager,
-    rooms: &mut RoomManager,
+    rooms: &mut RoomDirectory,
+    ingress: &Ingress,
     addr: std::net::SocketAddr,
-    join: rust_server::protocol::client::JoinRoom,
+    join: JoinRoom,
+    settings: Option<RoomSettings>,
 ) {
+    if let Some(notice) = ingress.shutting_down.clone() {
+        tracing::debug!("Refusing join from {} while shutting down", addr);
+        send_reliable(
+            server,
+            sessions,
+            addr,
+            server_message::Payload::ServerShuttingDown(notice),
+        )
+        .await;
+        return;
+    }
+
     let session = sessions.register(addr, join.player_name.clone());
     let player_id = session.player_id;
+    let span = tracing::Span::current();
+    span.record("player_id", player_id);
     let reconnect_token = session.reconnect_token.clone();
-
-    match rooms.join_room(&join.room_code, player_id, join.player_name.clone()) {
-        Ok(room) => {
-            let room_code = room.code.clone();
-            let player_count = room.player_count();
-            let player_ids: Vec<u32> = room.get_player_ids();
-
-            // Update session with room code
+    let link = session.link.clone();
+    // Joining a room yourself takes you out of the matchmaking queue
+    rooms.cancel_match(player_id);
+
+    // The room task replies with RoomJoined or an Error, and reports a
+    // rejection back so the session can be updated
+    match rooms
+        .join_room(join, settings, player_id, link, reconnect_token)
+        .await
+    {
+        Ok(room_code) => {
+            span.record("room_code", room_code.as_str());
             if let Some(session) = sessions.get_by_addr_mut(&addr) {
-                session.room_code = Some(room_code.clone());
-            }
-
-            // Build player list
-            let players: Vec<PlayerInfo> = room
-                .players
-                .values()
-                .map(|p| PlayerInfo {
-                    player_id: p.player_id,
-                    name: p.name.clone(),
-                    ready: p.ready,
-                })
-                .collect();
-
-            // Send RoomJoined to the joining player
-            let response = ServerMessage {
-                sequence: sessions.next_send_sequence(&addr),
-                payload: Some(server_message::Payload::RoomJoined(RoomJoined {
-                    player_id,
-                    room_code: room_code.clone(),
-                    players: players.clone(),
-                    reconnect_token,
-                })),
-            };
-
-            tracing::debug!("Sending RoomJoined to {}", addr);
-            let _ = server.send(&response.encode_to_vec(), addr).await;
-            tracing::debug!("Sent RoomJoined");
-
-            for pid in &player_ids {
-                if *pid != player_id {
-                    let other_addr = sessions.get_by_player_id(*pid).map(|s| s.addr);
-
-                    let Some(other_addr) = other_addr else {
-                        continue;
-                    };
-                    let update = ServerMessage {
-                        sequence: sessions.next_send_sequence(&other_addr),
-                        payload: Some(server_message::Payload::RoomUpdate(RoomUpdate {
-                            players: players.clone(),
-                        })),
-                    };
-                    let _ = server.send(&update.encode_to_vec(), other_addr).await;
-                }
+                session.room_code = Some(room_code);
             }
-
-            tracing::info!(
-                "Player {} ({}) joined room '{}' ({} players)",
-                player_id,
-                join.player_name,
-                room_code,
-                player_count
-            );
         }
         Err(e) => {
-            let response = ServerMessage {
-                sequence: sessions.next_send_sequence(&addr),
-                payload: Some(server_message::Payload::Error(Error {
+            tracing::debug!("Join of player {} refused: {:?}", player_id, e);
+            // The player may have left their previous room on the way
+            let room_code = rooms.get_player_room_code(player_id).map(str::to_string);
+            if let Some(session) = sessions.get_by_addr_mut(&addr) {
+                session.room_code = room_code;
+            }
+            send_reliable(
+                server,
+                sessions,
+                addr,
+                server_message::Payload::Error(Error {
                     message: format!("Failed to join room: {:?}", e),
-                })),
-            };
-            let _ = server.send(&response.encode_to_vec(), addr).await;
+                    code: e.code() as i32,
+                }),
+            )
+            .await;
         }
     }
 }
 
-async fn handle_leave_room(
+/// Answer a room browser query, keeping the player subscribed to it if asked.
+/// Browsing opens a session if there is none, so the answer and later pushes
+/// are sequenced and acked like any other reply.
+async fn handle_list_rooms(
     server: &UdpServer,
     sessions: &mut SessionManager,
-    rooms: &mut RoomManager,
+    rooms: &RoomDirectory,
     addr: SocketAddr,
+    query: ListRooms,
 ) {
-    if let Some(session) = sessions.get_by_addr(&addr) {
-        let player_id = session.player_id;
+    if sessions.get_by_addr(&addr).is_none() {
+        sessions.register(addr, String::new());
+    }
+    let page = rooms.lobby().list(&query);
+    if let Some(session) = sessions.get_by_addr_mut(&addr) {
+        tracing::Span::current().record("player_id", session.player_id);
+        session.room_browser = query.subscribe.then(|| Subscription {
+            query,
+            last_sent: page.clone(),
+        });
+    }
 
-        if let Some(room_code) = rooms.leave_room(player_id) {
-            // Update session
-            if let Some(session) = sessions.get_by_addr_mut(&addr) {
-                session.room_code = None;
-            }
+    send_reliable(
+        server,
+        sessions,
+        addr,
+        server_message::Payload::RoomList(page),
+    )
+    .await;
+}
 
-            // Notify others
-            let remaining = rooms.get_room_player_ids(&room_code);
-            let msg = ServerMessage {
-                sequence: sessions.next_send_sequence(&addr),
-                payload: Some(server_message::Payload::PlayerLeft(PlayerLeft {
-                    player_id,
-                })),
-            };
-            let bytes = msg.encode_to_vec();
+/// Queue the player for a match. Queueing opens a session if there is none,
+/// so status updates reach the player like any other reply.
+async fn handle_find_match(
+    server: &UdpServer,
+    sessions: &mut SessionManager,
+    rooms: &RoomDirectory,
+    ingress: &Ingress,
+    addr: SocketAddr,
+    request: FindMatch,
+) {
+    if let Some(notice) = ingress.shutting_down.clone() {
+        tracing::debug!("Refusing matchmaking from {} while shutting down", addr);
+        send_reliable(
+            server,
+            sessions,
+            addr,
+            server_message::Payload::ServerShuttingDown(notice),
+        )
+        .await;
+        return;
+    }
 
-            for pid in remaining {
-                if let Some(other) = sessions.get_by_player_id(pid) {
-                    let _ = server.send(&bytes, other.addr).await;
-                }
-            }
+    let session = sessions.register(addr, request.player_name.clone());
+    let player_id = session.player_id;
+    let link = session.link.clone();
+    tracing::Span::current().record("player_id", player_id);
+
+    if session.room_code.is_some() {
+        tracing::debug!("Player {} asked for a match while in a room", player_id);
+        send_reliable(
+            server,
+            sessions,
+            addr,
+            server_message::Payload::Error(Error {
+                message: format!(
+                    "Failed to queue for a match: {:?}",
+                    RoomError::AlreadyInRoom
+                ),
+                code: RoomError::AlreadyInRoom.code() as i32,
+            }),
+        )
+        .await;
+        return;
+    }
+
+    rooms.find_match(player_id, request, link);
+}
 
-            tracing::info!("Player {} left room {}", player_id, room_code);
+/// Send subscribed players their room browser page again, where it changed
+async fn push_room_lists(server: &UdpServer, sessions: &mut SessionManager, rooms: &RoomDirectory) {
+    let mut pages = Vec::new();
+    for session in sessions.room_browsers() {
+        let Some(subscription) = &mut session.room_browser else {
+            continue;
+        };
+        let page = rooms.lobby().list(&subscription.query);
+        if page != subscription.last_sent {
+            subscription.last_sent = page.clone();
+            pages.push((session.addr, page));
         }
     }
+
+    for (addr, page) in pages {
+        send_reliable(
+            server,
+            sessions,
+            addr,
+            server_message::Payload::RoomList(page),
+        )
+        .await;
+    }
+}
+
+/// Drop a session for good, taking its player out of the matchmaking queue
+/// and their room first
+async fn remove_session(
+    sessions: &mut SessionManager,
+    rooms: &mut RoomDirectory,
+    addr: SocketAddr,
+) {
+    if let Some(session) = sessions.get_by_addr(&addr) {
+        rooms.cancel_match(session.player_id);
+    }
+    handle_leave_room(sessions, rooms, addr).await;
+    sessions.remove_player(&addr);
+}
+
+async fn handle_leave_room(
+    sessions: &mut SessionManager,
+    rooms: &mut RoomDirectory,
+    addr: SocketAddr,
+) {
+    if let Some(session) = sessions.get_by_addr_mut(&addr) {
+        let player_id = session.player_id;
+        session.room_code = None;
+
+        rooms.leave_room(player_id).await;
+    }
 }
 
 async fn handle_ready(
-    server: &UdpServer,
     sessions: &mut SessionManager,
-    rooms: &mut RoomManager,
+    rooms: &mut RoomDirectory,
     addr: std::net::SocketAddr,
 ) {
     sessions.update_last_seen(&addr);
 
     if let Some(session) = sessions.get_by_addr(&addr) {
         let player_id = session.player_id;
+        rooms
+            .send(player_id, RoomCommand::Ready { player_id })
+            .await;
+    }
+}
 
-        if let Ok(room) = rooms.set_ready(player_id, true) {
-            let room_code = room.code.clone();
-            let all_ready = room.all_ready();
-            let player_count = room.player_count();
-            let player_ids = room.get_player_ids();
-
-            // Build updated player list
-            let players: Vec<PlayerInfo> = room
-                .players
-                .values()
-                .map(|p| PlayerInfo {
-                    player_id: p.player_id,
-                    name: p.name.clone(),
-                    ready: p.ready,
-                })
-                .collect();
-
-            tracing::info!(
-                "Player {} ready in room {} ({}/{})",
-                player_id,
-                room_code,
-                room.players.values().filter(|p| p.ready).count(),
-                player_count
-            );
-
-            // Notify all players of updated ready status
-            let update = ServerMessage {
-                sequence: sessions.next_send_sequence(&addr),
-                payload: Some(server_message::Payload::RoomUpdate(RoomUpdate { players })),
-            };
-            let update_bytes = update.encode_to_vec();
-
-            for pid in &player_ids {
-                if let Some(other) = sessions.get_by_player_id(*pid) {
-                    let _ = server.send(&update_bytes, other.addr).await;
-                }
-            }
-
-            // Check if game should start
-            if all_ready && player_count >= 2 {
-                // Update room state
-                if let Some(room) = rooms.get_player_room_mut(player_id) {
-                    room.state = RoomState::Playing;
-                }
+/// Route a host only action to the player's room, which checks they are host
+async fn handle_host_action(
+    sessions: &mut SessionManager,
+    rooms: &RoomDirectory,
+    addr: SocketAddr,
+    command: impl FnOnce(PlayerId) -> RoomCommand,
+) {
+    sessions.update_last_seen(&addr);
 
-                // Notify all players game is starting
-                let starting = ServerMessage {
-                    sequence: sessions.next_send_sequence(&addr),
-                    payload: Some(server_message::Payload::GameStarting(GameStarting {
-                        countdown_seconds: 3,
-                    })),
-                };
-                let starting_bytes = starting.encode_to_vec();
+    let Some(player_id) = sessions.get_by_addr(&addr).map(|s| s.player_id) else {
+        return;
+    };
+    if !rooms.send(player_id, command(player_id)).await {
+        tracing::debug!(
+            "Ignoring host action from player {}: not in a room",
+            player_id
+        );
+    }
+}
 
-                for pid in &player_ids {
-                    if let Some(other) = sessions.get_by_player_id(*pid) {
-                        let _ = server.send(&starting_bytes, other.addr).await;
-                    }
-                }
+/// Whether the message may come from an address without a session, given a
+/// valid handshake cookie
+fn opens_session(msg: &ClientMessage) -> bool {
+    matches!(
+        msg.payload,
+        Some(Payload::JoinRoom(_))
+            | Some(Payload::CreateRoom(_))
+            | Some(Payload::ListRooms(_))
+            | Some(Payload::FindMatch(_))
+            | Some(Payload::Reconnect(_))
+            | Some(Payload::Sealed(_))
+    )
+}
 
-                tracing::info!("Room {} starting game!", room_code);
-            }
-        }
-    }
+/// A reliable-ordered GameMessage carrying its ordering number, which the
+/// link's reorder buffer deduplicates
+fn is_ordered_game_message(msg: &ClientMessage) -> bool {
+    msg.reliable_sequence != 0
+        && matches!(
+            &msg.payload,
+            Some(Payload::GameMessage(game_msg))
+                if game_msg.delivery() == DeliveryMode::ReliableOrdered
+        )
 }
 
-async fn handle_game_message(
-    server: &UdpServer,
+fn handle_game_message(
     sessions: &mut SessionManager,
-    rooms: &RoomManager,
+    rooms: &RoomDirectory,
     addr: std::net::SocketAddr,
-    payload: Vec<u8>,
+    game_msg: GameMessage,
+    reliable_sequence: u32,
+    sequence_check: SequenceCheck,
+    received_at: Instant,
 ) {
     sessions.update_last_seen(&addr);
 
-    let session_data = sessions.get_by_addr(&addr).map(|s| {
-        (s.player_id, s.room_code.clone())
-    });
-
-    let Some((player_id, Some(room_code))) = session_data else {
-        tracing::warn!("GameMessage from unknown address or player not in room: {}", addr);
+    // Sequenced payloads are only useful if they are the newest we have seen
+    if game_msg.delivery() == DeliveryMode::UnreliableSequenced
+        && sequence_check == SequenceCheck::OutOfOrder
+    {
+        tracing::trace!("Dropping stale sequenced GameMessage from {}", addr);
         return;
-    };
+    }
 
-    let Some(room) = rooms.get_room(&room_code) else {
-        tracing::warn!("Room {} not found", room_code);
+    let Some(session) = sessions.get_by_addr(&addr) else {
+        tracing::warn!("GameMessage from unknown address: {}", addr);
         return;
     };
+    let player_id = session.player_id;
 
-    if room.state != RoomState::Playing {
-        tracing::debug!("Ignoring GameMessage - room not playing");
-        return;
-    }
-
-    let player_ids = room.get_player_ids();
-
-    for pid in player_ids {
-        if pid != player_id {
-            let other_addr = sessions.get_by_player_id(pid).map(|s| s.addr);
-
-            let Some(other_addr) = other_addr else {
-                continue;
-            };
+    // Ordered messages wait in the link until every earlier one has arrived
+    let messages = if game_msg.delivery() == DeliveryMode::ReliableOrdered && reliable_sequence != 0
+    {
+        let ready = session
+            .link
+            .lock()
+            .unwrap()
+            .ordered_recv
+            .insert(reliable_sequence, game_msg);
+        if ready.is_empty() {
+            tracing::trace!(
+                "Holding ordered GameMessage {} from player {}",
+                reliable_sequence,
+                player_id
+            );
+        }
+        ready
+    } else {
+        vec![game_msg]
+    };
 
-            let relay_msg = ServerMessage {
-                sequence: sessions.next_send_sequence(&other_addr),
-                payload: Some(server_message::Payload::GameMessage(ServerGameMessage {
-                    from_player_id: player_id,
-                    payload: payload.clone(),
-                })),
-            };
-            let _ = server.send(&relay_msg.encode_to_vec(), other_addr).await;
+    for message in messages {
+        // Game traffic is shed rather than queued when a room falls behind
+        let routed = rooms.try_send(
+            player_id,
+            RoomCommand::Game {
+                player_id,
+                message,
+                received_at,
+            },
+        );
+        if !routed {
+            tracing::debug!(
+                "Dropping GameMessage from player {}: not in a room or room backed up",
+                player_id
+            );
         }
     }
-
-    tracing::trace!(
-        "Relayed message from player {} to room {}",
-        player_id,
-        room_code
-    );
-}
-
-fn current_timestamp_ms() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64
 }
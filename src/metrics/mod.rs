@@ -0,0 +1,333 @@
+//! Process wide counters and histograms, exposed in Prometheus text format.
+//! They are recorded from sockets, workers and room tasks alike, so they live
+//! in one static rather than being threaded through each of them.
+
+pub mod prometheus;
+
+use crate::protocol::client::client_message;
+use crate::protocol::server::server_message;
+use crate::session::SequenceCheck;
+use prometheus::Exposition;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+static METRICS: Metrics = Metrics::new();
+
+/// The metrics of this process
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+/// Label of each client message type, indexed by [`client_kind`]
+pub const CLIENT_KINDS: [&str; 19] = [
+    "none",
+    "join_room",
+    "leave_room",
+    "ready",
+    "game_message",
+    "ping",
+    "reconnect",
+    "fragment",
+    "mtu_probe_ack",
+    "hello",
+    "sealed",
+    "kick_player",
+    "lock_room",
+    "unlock_room",
+    "transfer_host",
+    "create_room",
+    "list_rooms",
+    "find_match",
+    "cancel_matchmaking",
+];
+
+/// Label of each server message type, indexed by [`server_kind`]
+pub const SERVER_KINDS: [&str; 21] = [
+    "room_joined",
+    "room_update",
+    "game_starting",
+    "game_message",
+    "game_ended",
+    "player_left",
+    "error",
+    "pong",
+    "player_disconnected",
+    "player_reconnected",
+    "fragment",
+    "mtu_probe",
+    "network_stats",
+    "challenge",
+    "sealed",
+    "server_shutting_down",
+    "server_notice",
+    "player_kicked",
+    "room_list",
+    "matchmaking_status",
+    "none",
+];
+
+const SEQUENCE_CHECKS: [&str; 5] = ["valid", "gap", "out_of_order", "duplicate", "invalid"];
+
+/// Where in the receive path a datagram failed to decode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeStage {
+    Datagram,
+    Reassembled,
+    Sealed,
+}
+
+const DECODE_STAGES: [&str; 3] = ["datagram", "reassembled", "sealed"];
+
+/// Upper bounds of the relay fan-out latency buckets, in seconds
+const FANOUT_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+pub fn client_kind(payload: &Option<client_message::Payload>) -> usize {
+    use client_message::Payload;
+
+    match payload {
+        None => 0,
+        Some(Payload::JoinRoom(_)) => 1,
+        Some(Payload::LeaveRoom(_)) => 2,
+        Some(Payload::Ready(_)) => 3,
+        Some(Payload::GameMessage(_)) => 4,
+        Some(Payload::Ping(_)) => 5,
+        Some(Payload::Reconnect(_)) => 6,
+        Some(Payload::Fragment(_)) => 7,
+        Some(Payload::MtuProbeAck(_)) => 8,
+        Some(Payload::Hello(_)) => 9,
+        Some(Payload::Sealed(_)) => 10,
+        Some(Payload::KickPlayer(_)) => 11,
+        Some(Payload::LockRoom(_)) => 12,
+        Some(Payload::UnlockRoom(_)) => 13,
+        Some(Payload::TransferHost(_)) => 14,
+        Some(Payload::CreateRoom(_)) => 15,
+        Some(Payload::ListRooms(_)) => 16,
+        Some(Payload::FindMatch(_)) => 17,
+        Some(Payload::CancelMatchmaking(_)) => 18,
+    }
+}
+
+pub fn server_kind(payload: &Option<server_message::Payload>) -> usize {
+    use server_message::Payload;
+
+    match payload {
+        Some(Payload::RoomJoined(_)) => 0,
+        Some(Payload::RoomUpdate(_)) => 1,
+        Some(Payload::GameStarting(_)) => 2,
+        Some(Payload::GameMessage(_)) => 3,
+        Some(Payload::GameEnded(_)) => 4,
+        Some(Payload::PlayerLeft(_)) => 5,
+        Some(Payload::Error(_)) => 6,
+        Some(Payload::Pong(_)) => 7,
+        Some(Payload::PlayerDisconnected(_)) => 8,
+        Some(Payload::PlayerReconnected(_)) => 9,
+        Some(Payload::Fragment(_)) => 10,
+        Some(Payload::MtuProbe(_)) => 11,
+        Some(Payload::NetworkStats(_)) => 12,
+        Some(Payload::Challenge(_)) => 13,
+        Some(Payload::Sealed(_)) => 14,
+        Some(Payload::ServerShuttingDown(_)) => 15,
+        Some(Payload::ServerNotice(_)) => 16,
+        Some(Payload::PlayerKicked(_)) => 17,
+        Some(Payload::RoomList(_)) => 18,
+        Some(Payload::MatchmakingStatus(_)) => 19,
+        None => 20,
+    }
+}
+
+/// Counts of observations at or below each bucket bound, plus their sum
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    buckets: [AtomicU64; FANOUT_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64; FANOUT_BUCKETS.len()]) -> Self {
+        Self {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; FANOUT_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Message, decode, sequencing and reconnect counters, and relay latency
+#[derive(Debug)]
+pub struct Metrics {
+    messages_received: [AtomicU64; CLIENT_KINDS.len()],
+    message_bytes_received: [AtomicU64; CLIENT_KINDS.len()],
+    messages_sent: [AtomicU64; SERVER_KINDS.len()],
+    message_bytes_sent: [AtomicU64; SERVER_KINDS.len()],
+    decode_failures: [AtomicU64; DECODE_STAGES.len()],
+    sequence_checks: [AtomicU64; SEQUENCE_CHECKS.len()],
+    reconnects_succeeded: AtomicU64,
+    reconnects_failed: AtomicU64,
+    retransmissions: AtomicU64,
+    /// From a game message reaching the dispatcher to its relay being sent
+    fanout_latency: Histogram,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            messages_received: [const { AtomicU64::new(0) }; CLIENT_KINDS.len()],
+            message_bytes_received: [const { AtomicU64::new(0) }; CLIENT_KINDS.len()],
+            messages_sent: [const { AtomicU64::new(0) }; SERVER_KINDS.len()],
+            message_bytes_sent: [const { AtomicU64::new(0) }; SERVER_KINDS.len()],
+            decode_failures: [const { AtomicU64::new(0) }; DECODE_STAGES.len()],
+            sequence_checks: [const { AtomicU64::new(0) }; SEQUENCE_CHECKS.len()],
+            reconnects_succeeded: AtomicU64::new(0),
+            reconnects_failed: AtomicU64::new(0),
+            retransmissions: AtomicU64::new(0),
+            fanout_latency: Histogram::new(&FANOUT_BUCKETS),
+        }
+    }
+
+    /// Count a client message once it is decoded and unsealed
+    pub fn record_received(&self, payload: &Option<client_message::Payload>) {
+        let kind = client_kind(payload);
+        let bytes = payload.as_ref().map_or(0, |p| p.encoded_len());
+        self.messages_received[kind].fetch_add(1, Ordering::Relaxed);
+        self.message_bytes_received[kind].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a server message as it is built, before sealing
+    pub fn record_sent(&self, payload: &Option<server_message::Payload>) {
+        let kind = server_kind(payload);
+        let bytes = payload.as_ref().map_or(0, |p| p.encoded_len());
+        self.messages_sent[kind].fetch_add(1, Ordering::Relaxed);
+        self.message_bytes_sent[kind].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_decode_failure(&self, stage: DecodeStage) {
+        self.decode_failures[stage as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sequence_check(&self, check: &SequenceCheck) {
+        let index = match check {
+            SequenceCheck::Valid => 0,
+            SequenceCheck::Gap(_) => 1,
+            SequenceCheck::OutOfOrder => 2,
+            SequenceCheck::Duplicate => 3,
+            SequenceCheck::Invalid => 4,
+        };
+        self.sequence_checks[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self, succeeded: bool) {
+        let counter = if succeeded {
+            &self.reconnects_succeeded
+        } else {
+            &self.reconnects_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retransmissions(&self, count: usize) {
+        self.retransmissions
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn observe_fanout(&self, elapsed: Duration) {
+        self.fanout_latency.observe(elapsed);
+    }
+
+    /// Write every metric in Prometheus text format
+    pub fn encode(&self, out: &mut Exposition) {
+        out.counter(
+            "relay_messages_received_total",
+            "Client messages received, by type",
+            labelled("type", &CLIENT_KINDS, &self.messages_received),
+        );
+        out.counter(
+            "relay_message_bytes_received_total",
+            "Encoded payload bytes of client messages received, by type",
+            labelled("type", &CLIENT_KINDS, &self.message_bytes_received),
+        );
+        out.counter(
+            "relay_messages_sent_total",
+            "Server messages sent, by type, not counting retransmissions",
+            labelled("type", &SERVER_KINDS, &self.messages_sent),
+        );
+        out.counter(
+            "relay_message_bytes_sent_total",
+            "Encoded payload bytes of server messages sent, by type",
+            labelled("type", &SERVER_KINDS, &self.message_bytes_sent),
+        );
+        out.counter(
+            "relay_decode_failures_total",
+            "Datagrams that failed to decode, by receive stage",
+            labelled("stage", &DECODE_STAGES, &self.decode_failures),
+        );
+        out.counter(
+            "relay_sequence_checks_total",
+            "Sequence check outcomes of client messages",
+            labelled("outcome", &SEQUENCE_CHECKS, &self.sequence_checks),
+        );
+        out.counter(
+            "relay_reconnects_total",
+            "Reconnect attempts, by result",
+            [
+                (
+                    vec![("result", "success")],
+                    self.reconnects_succeeded.load(Ordering::Relaxed),
+                ),
+                (
+                    vec![("result", "failure")],
+                    self.reconnects_failed.load(Ordering::Relaxed),
+                ),
+            ],
+        );
+        out.counter(
+            "relay_retransmissions_total",
+            "Reliable messages sent again after their timeout",
+            [(vec![], self.retransmissions.load(Ordering::Relaxed))],
+        );
+
+        let latency = &self.fanout_latency;
+        let mut cumulative = 0;
+        let buckets: Vec<(f64, u64)> = latency
+            .bounds
+            .iter()
+            .zip(&latency.buckets)
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (*bound, cumulative)
+            })
+            .collect();
+        out.histogram(
+            "relay_fanout_latency_seconds",
+            "Time from a game message reaching the dispatcher to its relay being sent",
+            &buckets,
+            latency.count.load(Ordering::Relaxed),
+            latency.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        );
+    }
+}
+
+fn labelled<'a>(
+    label: &'a str,
+    names: &'a [&'a str],
+    values: &'a [AtomicU64],
+) -> impl Iterator<Item = (Vec<(&'a str, &'a str)>, u64)> {
+    names
+        .iter()
+        .zip(values)
+        .map(move |(name, value)| (vec![(label, *name)], value.load(Ordering::Relaxed)))
+}
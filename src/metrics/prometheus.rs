@@ -0,0 +1,93 @@
+//! Writer for the Prometheus text exposition format
+
+use std::fmt::Write;
+
+/// Content type of [`Exposition::finish`]'s output
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Labels of one sample, as name and value pairs
+pub type Labels<'a> = Vec<(&'a str, &'a str)>;
+
+/// Metric families written one after the other into a scrape response
+#[derive(Debug, Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    pub fn counter<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (Labels<'a>, u64)>,
+    ) {
+        self.family(name, help, "counter");
+        for (labels, value) in samples {
+            self.sample(name, &labels, value as f64);
+        }
+    }
+
+    pub fn gauge<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (Labels<'a>, u64)>,
+    ) {
+        self.family(name, help, "gauge");
+        for (labels, value) in samples {
+            self.sample(name, &labels, value as f64);
+        }
+    }
+
+    /// A histogram from cumulative `(upper bound, count)` buckets
+    pub fn histogram(
+        &mut self,
+        name: &str,
+        help: &str,
+        buckets: &[(f64, u64)],
+        count: u64,
+        sum: f64,
+    ) {
+        self.family(name, help, "histogram");
+        let bucket_name = format!("{}_bucket", name);
+        for (bound, cumulative) in buckets {
+            let bound = bound.to_string();
+            self.sample(&bucket_name, &[("le", bound.as_str())], *cumulative as f64);
+        }
+        self.sample(&bucket_name, &[("le", "+Inf")], count as f64);
+        self.sample(&format!("{}_sum", name), &[], sum);
+        self.sample(&format!("{}_count", name), &[], count as f64);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn family(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"{}\"", label, escape(value));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+}
+
+/// Escape a label value: backslash, double quote and newline
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,231 @@
+//! Batched datagram I/O for Linux: recvmmsg to drain the socket, sendmmsg to
+//! flush a tick's worth of datagrams, and UDP GSO to hand the kernel runs of
+//! equal sized datagrams to one peer as a single buffer.
+
+use crate::network::fragment::MAX_DATAGRAM_SIZE;
+use socket2::{SockAddr, SockAddrStorage};
+use std::borrow::Cow;
+use std::io::{Error, Result};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use std::ptr;
+
+/// Most datagrams read or written by one syscall
+pub const BATCH_SIZE: usize = 32;
+/// Most segments the kernel accepts in one GSO send
+const MAX_GSO_SEGMENTS: usize = 64;
+/// Room for one control message carrying the GSO segment size
+const CONTROL_LEN: usize = 32;
+
+/// One sendmmsg entry: a datagram, or several equal sized ones to the same
+/// peer when `segment_size` is set
+#[derive(Debug)]
+pub struct Transmit<'a> {
+    pub addr: SocketAddr,
+    pub contents: Cow<'a, [u8]>,
+    pub segment_size: Option<u16>,
+    /// Datagrams merged into this transmit
+    pub count: usize,
+}
+
+/// Turn queued datagrams into transmits. With `gso`, consecutive datagrams to
+/// the same peer are merged while they share a size, the last one of a run
+/// being allowed to be shorter.
+pub fn coalesce<'a>(datagrams: &'a [(Vec<u8>, SocketAddr)], gso: bool) -> Vec<Transmit<'a>> {
+    let mut transmits: Vec<Transmit<'a>> = Vec::with_capacity(datagrams.len());
+    let mut run_segments = 0;
+
+    for (data, addr) in datagrams {
+        if gso
+            && let Some(last) = transmits.last_mut()
+            && last.addr == *addr
+            && let Some(segment) = continue_run(last, data.len(), run_segments)
+        {
+            let contents = last.contents.to_mut();
+            contents.extend_from_slice(data);
+            last.segment_size = Some(segment);
+            last.count += 1;
+            run_segments += 1;
+            continue;
+        }
+
+        transmits.push(Transmit {
+            addr: *addr,
+            contents: Cow::Borrowed(data),
+            segment_size: None,
+            count: 1,
+        });
+        run_segments = 1;
+    }
+
+    transmits
+}
+
+/// Segment size to use if a datagram of `len` bytes can extend `last`
+fn continue_run(last: &Transmit, len: usize, run_segments: usize) -> Option<u16> {
+    let segment = match last.segment_size {
+        Some(segment) => segment as usize,
+        None => last.contents.len(),
+    };
+
+    // A run ends after its first short segment
+    let open = last.contents.len() == segment * run_segments;
+    let fits = last.contents.len() + len <= MAX_DATAGRAM_SIZE;
+
+    if open && fits && len <= segment && run_segments < MAX_GSO_SEGMENTS && segment > 0 {
+        u16::try_from(segment).ok()
+    } else {
+        None
+    }
+}
+
+/// Send as many of `transmits` as the socket takes in one sendmmsg call,
+/// returning how many went out
+pub fn send(fd: RawFd, transmits: &[Transmit]) -> Result<usize> {
+    let count = transmits.len().min(BATCH_SIZE);
+    let addrs: Vec<SockAddr> = transmits[..count]
+        .iter()
+        .map(|t| SockAddr::from(t.addr))
+        .collect();
+    let mut iovecs: Vec<libc::iovec> = transmits[..count]
+        .iter()
+        .map(|t| libc::iovec {
+            iov_base: t.contents.as_ptr() as *mut _,
+            iov_len: t.contents.len(),
+        })
+        .collect();
+    let mut controls = vec![[0u8; CONTROL_LEN]; count];
+    let mut headers: Vec<libc::mmsghdr> = Vec::with_capacity(count);
+
+    for i in 0..count {
+        // SAFETY: mmsghdr is plain old data, for which all zeros is valid
+        let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+        header.msg_hdr.msg_name = addrs[i].as_ptr() as *mut _;
+        header.msg_hdr.msg_namelen = addrs[i].len();
+        header.msg_hdr.msg_iov = &mut iovecs[i];
+        header.msg_hdr.msg_iovlen = 1;
+
+        if let Some(segment) = transmits[i].segment_size {
+            header.msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut _;
+            header.msg_hdr.msg_controllen = CONTROL_LEN as _;
+            // SAFETY: the control buffer is large enough for one u16 control
+            // message, and the header points at it
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&header.msg_hdr);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment);
+                header.msg_hdr.msg_controllen =
+                    libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+            }
+        }
+
+        headers.push(header);
+    }
+
+    // SAFETY: every header points at buffers that outlive the call
+    let sent = unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), count as u32, 0) };
+    if sent < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Whether the kernel supports UDP GSO on this socket
+pub fn gso_supported(fd: RawFd) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value and len are valid for writes of the sizes passed
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &mut value as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+    result == 0
+}
+
+/// Receive buffers for one socket, reused by every recvmmsg call
+pub struct RecvBuffers {
+    data: Vec<u8>,
+    addrs: Vec<SockAddrStorage>,
+}
+
+impl RecvBuffers {
+    pub fn new() -> Self {
+        Self {
+            data: vec![0; BATCH_SIZE * MAX_DATAGRAM_SIZE],
+            addrs: (0..BATCH_SIZE).map(|_| SockAddrStorage::zeroed()).collect(),
+        }
+    }
+}
+
+impl Default for RecvBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drain up to [`BATCH_SIZE`] datagrams waiting on the socket in one recvmmsg call
+pub fn recv(fd: RawFd, buffers: &mut RecvBuffers) -> Result<Vec<(Vec<u8>, SocketAddr)>> {
+    let mut iovecs: Vec<libc::iovec> = buffers
+        .data
+        .chunks_mut(MAX_DATAGRAM_SIZE)
+        .map(|chunk| libc::iovec {
+            iov_base: chunk.as_mut_ptr() as *mut _,
+            iov_len: chunk.len(),
+        })
+        .collect();
+
+    let mut headers: Vec<libc::mmsghdr> = Vec::with_capacity(BATCH_SIZE);
+    for (iovec, addr) in iovecs.iter_mut().zip(buffers.addrs.iter_mut()) {
+        // SAFETY: mmsghdr is plain old data, for which all zeros is valid
+        let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+        header.msg_hdr.msg_name = addr as *mut SockAddrStorage as *mut _;
+        header.msg_hdr.msg_namelen = addr.size_of();
+        header.msg_hdr.msg_iov = iovec;
+        header.msg_hdr.msg_iovlen = 1;
+        headers.push(header);
+    }
+
+    // SAFETY: every header points at buffers that outlive the call
+    let received = unsafe {
+        libc::recvmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            BATCH_SIZE as u32,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut datagrams = Vec::with_capacity(received as usize);
+    for (i, header) in headers.iter().take(received as usize).enumerate() {
+        let len = header.msg_len as usize;
+        let namelen = header.msg_hdr.msg_namelen;
+
+        let mut storage = SockAddrStorage::zeroed();
+        // SAFETY: sockaddr_storage is the type SockAddrStorage wraps, and the
+        // kernel filled in `namelen` bytes of it
+        let addr = unsafe {
+            *storage.view_as::<libc::sockaddr_storage>() =
+                *buffers.addrs[i].view_as::<libc::sockaddr_storage>();
+            SockAddr::new(storage, namelen)
+        };
+
+        if let Some(addr) = addr.as_socket() {
+            let start = i * MAX_DATAGRAM_SIZE;
+            datagrams.push((buffers.data[start..start + len].to_vec(), addr));
+        }
+    }
+
+    Ok(datagrams)
+}
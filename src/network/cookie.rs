@@ -0,0 +1,99 @@
+use crate::network::crypto::KEY_LEN;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SECRET_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// issued_at (8) + truncated tag
+const COOKIE_LEN: usize = 8 + TAG_LEN;
+
+/// A Hello must be at least this large, so answering it with a Challenge
+/// never amplifies traffic towards a spoofed source address
+pub const MIN_HELLO_SIZE: usize = 256;
+
+/// Issues and checks stateless handshake cookies. A cookie binds the client's
+/// address to an issue time, so echoing it proves the client can receive at
+/// that address without the server storing anything per client.
+#[derive(Clone)]
+pub struct CookieSigner {
+    secret: [u8; SECRET_LEN],
+    lifetime: Duration,
+}
+
+impl CookieSigner {
+    /// Signer with a fresh secret from the OS CSPRNG
+    pub fn new(lifetime: Duration) -> Self {
+        let mut secret = [0u8; SECRET_LEN];
+        getrandom::fill(&mut secret).expect("OS random number generator unavailable");
+        Self { secret, lifetime }
+    }
+
+    pub fn issue(&self, addr: &SocketAddr) -> Vec<u8> {
+        let issued_at = unix_seconds();
+
+        let mut cookie = Vec::with_capacity(COOKIE_LEN);
+        cookie.extend_from_slice(&issued_at.to_be_bytes());
+        let tag = self.mac(addr, issued_at).finalize().into_bytes();
+        cookie.extend_from_slice(&tag[..TAG_LEN]);
+        cookie
+    }
+
+    pub fn verify(&self, addr: &SocketAddr, cookie: &[u8]) -> bool {
+        if cookie.len() != COOKIE_LEN {
+            return false;
+        }
+
+        let issued_at = u64::from_be_bytes(cookie[..8].try_into().unwrap());
+        let age = unix_seconds().saturating_sub(issued_at);
+        if age > self.lifetime.as_secs() {
+            return false;
+        }
+
+        self.mac(addr, issued_at)
+            .verify_truncated_left(&cookie[8..])
+            .is_ok()
+    }
+
+    /// How much longer a cookie that passed [`verify`](Self::verify) stays valid
+    pub fn remaining_lifetime(&self, cookie: &[u8]) -> Duration {
+        let issued_at = u64::from_be_bytes(cookie[..8].try_into().unwrap());
+        let age = unix_seconds().saturating_sub(issued_at);
+        // Ages are whole seconds, so a cookie is still accepted during the
+        // second its lifetime runs out
+        Duration::from_secs(self.lifetime.as_secs().saturating_sub(age) + 1)
+    }
+
+    /// X25519 secret the server uses for the key exchange started by `cookie`.
+    /// Deriving it instead of storing it keeps the handshake stateless.
+    pub fn handshake_secret(&self, cookie: &[u8]) -> [u8; KEY_LEN] {
+        let mut mac = <HmacSha256 as KeyInit>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(b"handshake key");
+        mac.update(cookie);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn mac(&self, addr: &SocketAddr, issued_at: u64) -> HmacSha256 {
+        let mut mac = <HmacSha256 as KeyInit>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_be_bytes());
+        mac.update(&issued_at.to_be_bytes());
+        mac
+    }
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,124 @@
+use crate::protocol::common::Sealed;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const KEY_LEN: usize = 32;
+
+const CLIENT_TO_SERVER_INFO: &[u8] = b"rust-server client to server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"rust-server server to client";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CryptoError {
+    /// Wrong length, or a low order point that would give a predictable key
+    BadPublicKey,
+    /// Tag mismatch: tampered, replayed under another key, or not for us
+    Decrypt,
+}
+
+/// Which end of the session this cipher belongs to, so each direction uses its own key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Fresh X25519 secret from the OS CSPRNG
+pub fn generate_secret() -> [u8; KEY_LEN] {
+    let mut secret = [0u8; KEY_LEN];
+    getrandom::fill(&mut secret).expect("OS random number generator unavailable");
+    secret
+}
+
+pub fn public_key(secret: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+/// Sequence of the inner message a sealed datagram claims to carry
+pub fn nonce_sequence(nonce: u64) -> u32 {
+    (nonce >> 32) as u32
+}
+
+/// AEAD state for one session, keyed from an X25519 exchange bound to the handshake cookie
+#[derive(Clone)]
+pub struct SessionCipher {
+    seal_key: ChaCha20Poly1305,
+    open_key: ChaCha20Poly1305,
+    /// Datagrams sealed so far. Retransmits reuse a sequence, so this keeps nonces unique.
+    sealed_count: u32,
+}
+
+impl SessionCipher {
+    pub fn new(
+        role: Role,
+        secret: &[u8; KEY_LEN],
+        peer_public_key: &[u8],
+        cookie: &[u8],
+    ) -> Result<Self, CryptoError> {
+        let peer: [u8; KEY_LEN] = peer_public_key
+            .try_into()
+            .map_err(|_| CryptoError::BadPublicKey)?;
+
+        let shared = StaticSecret::from(*secret).diffie_hellman(&PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return Err(CryptoError::BadPublicKey);
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(cookie), shared.as_bytes());
+        let client_to_server = expand_key(&hkdf, CLIENT_TO_SERVER_INFO);
+        let server_to_client = expand_key(&hkdf, SERVER_TO_CLIENT_INFO);
+
+        let (seal_key, open_key) = match role {
+            Role::Client => (client_to_server, server_to_client),
+            Role::Server => (server_to_client, client_to_server),
+        };
+
+        Ok(Self {
+            seal_key,
+            open_key,
+            sealed_count: 0,
+        })
+    }
+
+    /// Seal the encoding of a message stamped with `sequence`
+    pub fn seal(&mut self, sequence: u32, plaintext: &[u8]) -> Sealed {
+        self.sealed_count = self.sealed_count.wrapping_add(1);
+        let nonce = (sequence as u64) << 32 | self.sealed_count as u64;
+
+        let ciphertext = self
+            .seal_key
+            .encrypt(&nonce_bytes(nonce), plaintext)
+            .expect("datagram sized plaintext is within ChaCha20-Poly1305 limits");
+
+        Sealed { nonce, ciphertext }
+    }
+
+    pub fn open(&self, sealed: &Sealed) -> Result<Vec<u8>, CryptoError> {
+        self.open_key
+            .decrypt(&nonce_bytes(sealed.nonce), sealed.ciphertext.as_slice())
+            .map_err(|_| CryptoError::Decrypt)
+    }
+}
+
+impl std::fmt::Debug for SessionCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionCipher")
+            .field("sealed_count", &self.sealed_count)
+            .finish_non_exhaustive()
+    }
+}
+
+fn expand_key(hkdf: &Hkdf<Sha256>, info: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = [0u8; KEY_LEN];
+    hkdf.expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(&Key::from(key))
+}
+
+fn nonce_bytes(nonce: u64) -> Nonce {
+    let mut bytes = [0u8; 12];
+    bytes[4..].copy_from_slice(&nonce.to_be_bytes());
+    Nonce::from(bytes)
+}
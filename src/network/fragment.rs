@@ -0,0 +1,230 @@
+use crate::protocol::common::Fragment;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Datagram size used when nothing better is known about the path
+pub const DEFAULT_MTU: usize = 1200;
+/// Largest datagram we are willing to receive
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+/// Upper bound on the bytes a fragment envelope adds around its data
+pub const FRAGMENT_OVERHEAD: usize = 32;
+/// Messages needing more fragments than this are rejected
+pub const MAX_FRAGMENTS: u32 = 256;
+
+const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_BYTES_PER_PEER: usize = 256 * 1024;
+const DEFAULT_MAX_MESSAGES_PER_PEER: usize = 16;
+/// Across every peer, so many peers each under their own cap cannot add up
+const DEFAULT_MAX_TOTAL_BYTES: usize = 16 * 1024 * 1024;
+/// What each fragment a message expects costs before its data arrives
+const SLOT_SIZE: usize = std::mem::size_of::<Option<Vec<u8>>>();
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FragmentError {
+    InvalidHeader,
+    /// Only the last fragment of a message may carry no data
+    EmptyFragment,
+    TooManyFragments,
+    /// The peer already has as many messages in reassembly as it may
+    TooManyMessages,
+    MemoryCapExceeded,
+}
+
+/// Split an encoded message into fragments that fit in `mtu` once wrapped
+pub fn split(data: &[u8], mtu: usize, message_id: u32) -> Vec<Fragment> {
+    let chunk_size = mtu.saturating_sub(FRAGMENT_OVERHEAD).max(1);
+    let chunks: Vec<&[u8]> = data.chunks(chunk_size).collect();
+    let count = chunks.len() as u32;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| Fragment {
+            message_id,
+            index: index as u32,
+            count,
+            data: chunk.to_vec(),
+        })
+        .collect()
+}
+
+/// A message whose fragments are still arriving
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u32,
+    /// Memory held: the slot table plus the data received so far
+    bytes: usize,
+    started: Instant,
+}
+
+/// Reassembles fragmented messages, bounded in time, in memory per peer and
+/// overall, and in how many messages each peer has in progress
+pub struct Reassembler {
+    partial: HashMap<SocketAddr, HashMap<u32, PartialMessage>>,
+    timeout: Duration,
+    max_bytes_per_peer: usize,
+    max_messages_per_peer: usize,
+    max_total_bytes: usize,
+    /// Bytes held by every partial message, of every peer
+    total_bytes: usize,
+    last_sweep: Instant,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_REASSEMBLY_TIMEOUT,
+            DEFAULT_MAX_BYTES_PER_PEER,
+            DEFAULT_MAX_MESSAGES_PER_PEER,
+            DEFAULT_MAX_TOTAL_BYTES,
+        )
+    }
+}
+
+impl Reassembler {
+    pub fn new(
+        timeout: Duration,
+        max_bytes_per_peer: usize,
+        max_messages_per_peer: usize,
+        max_total_bytes: usize,
+    ) -> Self {
+        Self {
+            partial: HashMap::new(),
+            timeout,
+            max_bytes_per_peer,
+            max_messages_per_peer,
+            max_total_bytes,
+            total_bytes: 0,
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Store a fragment, returning the full encoding once every piece arrived
+    pub fn insert(
+        &mut self,
+        addr: SocketAddr,
+        fragment: Fragment,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.expire(now);
+        }
+
+        if fragment.count == 0 || fragment.index >= fragment.count {
+            return Err(FragmentError::InvalidHeader);
+        }
+
+        if fragment.count > MAX_FRAGMENTS {
+            return Err(FragmentError::TooManyFragments);
+        }
+
+        if fragment.data.is_empty() && fragment.index + 1 != fragment.count {
+            return Err(FragmentError::EmptyFragment);
+        }
+
+        let peer = self.partial.entry(addr).or_default();
+        let buffered: usize = peer.values().map(|p| p.bytes).sum();
+        let existing = peer.get(&fragment.message_id);
+
+        // A fragment starting a message also pays for the slot table it needs
+        let cost = match existing {
+            Some(_) => fragment.data.len(),
+            None => fragment.count as usize * SLOT_SIZE + fragment.data.len(),
+        };
+
+        if existing.is_none() && peer.len() >= self.max_messages_per_peer {
+            tracing::warn!(
+                "Dropping fragment from {}: {} messages already in reassembly",
+                addr,
+                peer.len()
+            );
+            return Err(FragmentError::TooManyMessages);
+        }
+
+        if buffered + cost > self.max_bytes_per_peer
+            || self.total_bytes + cost > self.max_total_bytes
+        {
+            tracing::warn!(
+                "Dropping fragment from {}: {} bytes already buffered ({} across all peers)",
+                addr,
+                buffered,
+                self.total_bytes
+            );
+            if peer.is_empty() {
+                self.partial.remove(&addr);
+            }
+            return Err(FragmentError::MemoryCapExceeded);
+        }
+
+        if let Some(partial) = existing {
+            if partial.fragments.len() != fragment.count as usize {
+                return Err(FragmentError::InvalidHeader);
+            }
+            if partial.fragments[fragment.index as usize].is_some() {
+                return Ok(None);
+            }
+        }
+
+        let partial = peer
+            .entry(fragment.message_id)
+            .or_insert_with(|| PartialMessage {
+                fragments: vec![None; fragment.count as usize],
+                received: 0,
+                bytes: fragment.count as usize * SLOT_SIZE,
+                started: now,
+            });
+
+        partial.bytes += fragment.data.len();
+        partial.received += 1;
+        partial.fragments[fragment.index as usize] = Some(fragment.data);
+        self.total_bytes += cost;
+
+        if partial.received < fragment.count {
+            return Ok(None);
+        }
+
+        let partial = peer.remove(&fragment.message_id).unwrap();
+        if peer.is_empty() {
+            self.partial.remove(&addr);
+        }
+        self.total_bytes -= partial.bytes;
+
+        let mut data = Vec::with_capacity(partial.bytes - partial.fragments.len() * SLOT_SIZE);
+        for piece in partial.fragments.into_iter().flatten() {
+            data.extend_from_slice(&piece);
+        }
+        Ok(Some(data))
+    }
+
+    /// Drop messages that did not complete within the reassembly timeout
+    pub fn expire(&mut self, now: Instant) -> usize {
+        self.last_sweep = now;
+        let timeout = self.timeout;
+        let mut expired = 0;
+        let mut freed = 0;
+
+        self.partial.retain(|addr, messages| {
+            messages.retain(|message_id, partial| {
+                let keep = now.duration_since(partial.started) <= timeout;
+                if !keep {
+                    tracing::debug!(
+                        "Reassembly of message {} from {} timed out ({}/{} fragments)",
+                        message_id,
+                        addr,
+                        partial.received,
+                        partial.fragments.len()
+                    );
+                    expired += 1;
+                    freed += partial.bytes;
+                }
+                keep
+            });
+            !messages.is_empty()
+        });
+
+        self.total_bytes -= freed;
+        expired
+    }
+}
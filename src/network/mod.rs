@@ -1 +1,10 @@
-pub mod udp;
\ No newline at end of file
+#[cfg(target_os = "linux")]
+pub mod batch;
+pub mod cookie;
+pub mod crypto;
+pub mod fragment;
+pub mod mtu;
+pub mod ratelimit;
+pub mod reliable;
+pub mod stats;
+pub mod udp;
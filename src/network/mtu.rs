@@ -0,0 +1,154 @@
+use crate::network::fragment::DEFAULT_MTU;
+use crate::protocol::server::{MtuProbe, ServerMessage, server_message};
+use prost::Message;
+use std::time::{Duration, Instant};
+
+/// Smallest datagram every IPv4 path must carry
+pub const MIN_MTU: usize = 576;
+/// Ethernet MTU minus IPv4 and UDP headers
+pub const MAX_MTU: usize = 1472;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_PROBE_ATTEMPTS: u32 = 3;
+/// Stop searching once the bounds are this close
+const SEARCH_GRANULARITY: usize = 16;
+/// Paths can change, so a finished search is restarted after this long
+const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// A probe waiting for its `MtuProbeAck`
+#[derive(Debug, Clone)]
+struct Probe {
+    id: u32,
+    size: usize,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+/// Packetization-layer path MTU search for one session (RFC 8899 style)
+#[derive(Debug, Clone)]
+pub struct PathMtu {
+    /// Largest datagram size confirmed by an ack
+    confirmed: usize,
+    /// Smallest datagram size known to be dropped
+    failed: usize,
+    probe: Option<Probe>,
+    next_probe_id: u32,
+    completed_at: Option<Instant>,
+}
+
+impl Default for PathMtu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PathMtu {
+    pub fn new() -> Self {
+        Self {
+            confirmed: MIN_MTU,
+            failed: MAX_MTU + 1,
+            probe: None,
+            next_probe_id: 0,
+            completed_at: None,
+        }
+    }
+
+    /// Datagram size the send path should use. Until a probe at or below
+    /// `DEFAULT_MTU` fails, the default is assumed to work.
+    pub fn current(&self) -> usize {
+        if self.failed <= DEFAULT_MTU {
+            self.confirmed
+        } else {
+            self.confirmed.max(DEFAULT_MTU)
+        }
+    }
+
+    /// Decide whether a probe should go out now, returning its id and size
+    pub fn poll_probe(&mut self, now: Instant) -> Option<(u32, usize)> {
+        if let Some(completed_at) = self.completed_at {
+            if now.duration_since(completed_at) < REPROBE_INTERVAL {
+                return None;
+            }
+            // The path may have changed, search again from scratch
+            self.confirmed = MIN_MTU;
+            self.failed = MAX_MTU + 1;
+            self.completed_at = None;
+        }
+
+        if let Some(probe) = &mut self.probe {
+            if now.duration_since(probe.sent_at) < PROBE_TIMEOUT {
+                return None;
+            }
+
+            if probe.attempts < MAX_PROBE_ATTEMPTS {
+                probe.attempts += 1;
+                probe.sent_at = now;
+                return Some((probe.id, probe.size));
+            }
+
+            // Every attempt at this size was lost
+            self.failed = probe.size;
+            self.probe = None;
+            if self.search_finished() {
+                self.completed_at = Some(now);
+                return None;
+            }
+        }
+
+        // Try the maximum first since it usually works, then bisect
+        let size = if self.failed > MAX_MTU {
+            MAX_MTU
+        } else {
+            (self.confirmed + self.failed) / 2
+        };
+
+        self.next_probe_id += 1;
+        self.probe = Some(Probe {
+            id: self.next_probe_id,
+            size,
+            sent_at: now,
+            attempts: 1,
+        });
+        Some((self.next_probe_id, size))
+    }
+
+    /// Record an ack for a probe, returns the new confirmed size if it grew
+    pub fn acknowledge(&mut self, probe_id: u32, now: Instant) -> Option<usize> {
+        let probe = self.probe.take_if(|probe| probe.id == probe_id)?;
+
+        let grew = probe.size > self.confirmed;
+        self.confirmed = self.confirmed.max(probe.size);
+        if self.search_finished() {
+            self.completed_at = Some(now);
+        }
+
+        grew.then_some(self.confirmed)
+    }
+
+    fn search_finished(&self) -> bool {
+        self.confirmed >= MAX_MTU || self.failed - self.confirmed <= SEARCH_GRANULARITY
+    }
+}
+
+/// Build an unsequenced probe whose encoding is `size` bytes
+pub fn build_probe(probe_id: u32, size: usize) -> ServerMessage {
+    let wrap = |padding_len: usize| ServerMessage {
+        payload: Some(server_message::Payload::MtuProbe(MtuProbe {
+            probe_id,
+            padding: vec![0; padding_len],
+        })),
+        ..Default::default()
+    };
+
+    // Length prefixes are varints, so a few passes converge on the exact size
+    let mut padding_len = 0;
+    for _ in 0..4 {
+        let len = wrap(padding_len).encoded_len();
+        if len == size {
+            break;
+        }
+        padding_len = (padding_len + size).saturating_sub(len);
+    }
+
+    wrap(padding_len)
+}
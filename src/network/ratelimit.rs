@@ -0,0 +1,254 @@
+use crate::config::{ADDRESS_RATE_LIMIT_BURST, ADDRESS_RATE_LIMIT_PER_SECOND, RateLimitConfig};
+use crate::protocol::client::client_message::Payload;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Violations are forgotten once a session stays within budget this long
+const PENALTY_WINDOW: Duration = Duration::from_secs(10);
+/// Address buckets idle this long are full again and can be dropped
+const IDLE_BUCKET_TIMEOUT: Duration = Duration::from_secs(30);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Classic token bucket: `burst` tokens, refilled at `per_second`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_second: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            per_second,
+            burst,
+            tokens: burst,
+            last_refill: now,
+        }
+    }
+
+    /// Change the rate and size, keeping the tokens already earned up to the new burst
+    pub fn reconfigure(&mut self, per_second: f64, burst: f64) {
+        self.per_second = per_second;
+        self.burst = burst;
+        self.tokens = self.tokens.min(burst);
+    }
+
+    /// Take one token if available
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Which budget a client message is charged against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageClass {
+    Control,
+    Game,
+}
+
+impl MessageClass {
+    pub fn of(payload: &Option<Payload>) -> Self {
+        match payload {
+            Some(Payload::GameMessage(_)) => MessageClass::Game,
+            _ => MessageClass::Control,
+        }
+    }
+}
+
+/// What to do with a message after charging it to the session's budget
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateLimitVerdict {
+    Allow,
+    /// Over budget, drop silently
+    Drop,
+    /// Over budget for long enough that the client should be told
+    Warn,
+    /// Still flooding after the warning, disconnect the session
+    Disconnect,
+}
+
+/// Per-session budgets for control and game messages, with escalating penalties
+#[derive(Debug, Clone)]
+pub struct SessionRateLimits {
+    control: TokenBucket,
+    game: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>,
+    warn_after: u32,
+    disconnect_after: u32,
+}
+
+impl SessionRateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            control: TokenBucket::new(config.control_per_second, config.control_burst, now),
+            game: TokenBucket::new(config.game_per_second, config.game_burst, now),
+            violations: 0,
+            last_violation: None,
+            warn_after: config.warn_after,
+            disconnect_after: config.disconnect_after,
+        }
+    }
+
+    /// Apply new budgets without resetting the session's standing
+    pub fn reconfigure(&mut self, config: &RateLimitConfig) {
+        self.control
+            .reconfigure(config.control_per_second, config.control_burst);
+        self.game
+            .reconfigure(config.game_per_second, config.game_burst);
+        self.warn_after = config.warn_after;
+        self.disconnect_after = config.disconnect_after;
+    }
+
+    pub fn check(&mut self, class: MessageClass, now: Instant) -> RateLimitVerdict {
+        if let Some(last) = self.last_violation
+            && now.saturating_duration_since(last) > PENALTY_WINDOW
+        {
+            self.violations = 0;
+            self.last_violation = None;
+        }
+
+        let bucket = match class {
+            MessageClass::Control => &mut self.control,
+            MessageClass::Game => &mut self.game,
+        };
+        if bucket.try_take(now) {
+            return RateLimitVerdict::Allow;
+        }
+
+        self.violations += 1;
+        self.last_violation = Some(now);
+
+        if self.violations >= self.disconnect_after {
+            RateLimitVerdict::Disconnect
+        } else if self.violations == self.warn_after {
+            RateLimitVerdict::Warn
+        } else {
+            RateLimitVerdict::Drop
+        }
+    }
+}
+
+impl Default for SessionRateLimits {
+    fn default() -> Self {
+        Self::new(&RateLimitConfig::default())
+    }
+}
+
+/// Budget per source address, checked before a datagram is even decoded
+pub struct AddressRateLimiter {
+    buckets: HashMap<SocketAddr, TokenBucket>,
+    per_second: f64,
+    burst: f64,
+    last_sweep: Instant,
+}
+
+impl AddressRateLimiter {
+    pub fn new(per_second: f64, burst: f64) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            per_second,
+            burst,
+            last_sweep: Instant::now(),
+        }
+    }
+
+    pub fn reconfigure(&mut self, per_second: f64, burst: f64) {
+        self.per_second = per_second;
+        self.burst = burst;
+        for bucket in self.buckets.values_mut() {
+            bucket.reconfigure(per_second, burst);
+        }
+    }
+
+    pub fn allow(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if now.saturating_duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.buckets.retain(|_, bucket| {
+                now.saturating_duration_since(bucket.last_refill) < IDLE_BUCKET_TIMEOUT
+            });
+            self.last_sweep = now;
+        }
+
+        self.buckets
+            .entry(addr)
+            .or_insert_with(|| TokenBucket::new(self.per_second, self.burst, now))
+            .try_take(now)
+    }
+}
+
+impl Default for AddressRateLimiter {
+    fn default() -> Self {
+        Self::new(ADDRESS_RATE_LIMIT_PER_SECOND, ADDRESS_RATE_LIMIT_BURST)
+    }
+}
+
+/// Running totals of everything the rate limiter did, shared with whoever reports them
+#[derive(Debug, Default)]
+pub struct RateLimitCounters {
+    pub address_dropped: AtomicU64,
+    pub control_dropped: AtomicU64,
+    pub game_dropped: AtomicU64,
+    pub warnings: AtomicU64,
+    pub disconnects: AtomicU64,
+}
+
+/// Point in time copy of [`RateLimitCounters`]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimitSnapshot {
+    pub address_dropped: u64,
+    pub control_dropped: u64,
+    pub game_dropped: u64,
+    pub warnings: u64,
+    pub disconnects: u64,
+}
+
+impl RateLimitCounters {
+    /// Count a session verdict for a message of `class`
+    pub fn record(&self, class: MessageClass, verdict: &RateLimitVerdict) {
+        if *verdict == RateLimitVerdict::Allow {
+            return;
+        }
+
+        let dropped = match class {
+            MessageClass::Control => &self.control_dropped,
+            MessageClass::Game => &self.game_dropped,
+        };
+        dropped.fetch_add(1, Ordering::Relaxed);
+
+        match verdict {
+            RateLimitVerdict::Warn => {
+                self.warnings.fetch_add(1, Ordering::Relaxed);
+            }
+            RateLimitVerdict::Disconnect => {
+                self.disconnects.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> RateLimitSnapshot {
+        RateLimitSnapshot {
+            address_dropped: self.address_dropped.load(Ordering::Relaxed),
+            control_dropped: self.control_dropped.load(Ordering::Relaxed),
+            game_dropped: self.game_dropped.load(Ordering::Relaxed),
+            warnings: self.warnings.load(Ordering::Relaxed),
+            disconnects: self.disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::protocol::server::ServerMessage;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+const INITIAL_RTO: Duration = Duration::from_millis(250);
+const MIN_RTO: Duration = Duration::from_millis(50);
+const MAX_RTO: Duration = Duration::from_secs(2);
+const MAX_RETRANSMITS: u32 = 10;
+
+/// Number of sequences covered by `ack_bits`
+pub const ACK_WINDOW: u32 = 32;
+
+/// How far past the next expected ordering number a message may arrive and
+/// still be held for delivery
+const REORDER_WINDOW: u32 = 256;
+
+/// A reliable message waiting to be acknowledged by the client
+#[derive(Debug, Clone)]
+pub struct PendingMessage {
+    pub message: ServerMessage,
+    pub first_sent: Instant,
+    pub last_sent: Instant,
+    pub retransmits: u32,
+}
+
+/// Server-side state of the reliable channel for one session
+#[derive(Debug, Clone)]
+pub struct ReliableChannel {
+    /// Unacked messages, keyed by packet sequence
+    pending: BTreeMap<u32, PendingMessage>,
+    /// Last ordering number handed out to a reliable message
+    reliable_sequence: u32,
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+}
+
+impl Default for ReliableChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReliableChannel {
+    pub fn new() -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            reliable_sequence: 0,
+            smoothed_rtt: None,
+            rtt_variance: Duration::ZERO,
+        }
+    }
+
+    pub fn next_reliable_sequence(&mut self) -> u32 {
+        self.reliable_sequence += 1;
+        self.reliable_sequence
+    }
+
+    /// Start tracking a message that has just been sent
+    pub fn track(&mut self, message: ServerMessage, now: Instant) {
+        self.pending.insert(
+            message.sequence,
+            PendingMessage {
+                message,
+                first_sent: now,
+                last_sent: now,
+                retransmits: 0,
+            },
+        );
+    }
+
+    /// Drop every pending message covered by `ack`/`ack_bits`, returns how many were acked
+    pub fn acknowledge(&mut self, ack: u32, ack_bits: u32, now: Instant) -> usize {
+        if ack == 0 {
+            return 0;
+        }
+
+        let mut acked = 0;
+        for sequence in acked_sequences(ack, ack_bits) {
+            if let Some(pending) = self.pending.remove(&sequence) {
+                // Karn's algorithm: only sample RTT from messages sent once
+                if pending.retransmits == 0 {
+                    self.update_rtt(now.duration_since(pending.first_sent));
+                }
+                acked += 1;
+            }
+        }
+        acked
+    }
+
+    /// Retransmission timeout derived from the smoothed RTT (RFC 6298)
+    pub fn retransmission_timeout(&self) -> Duration {
+        match self.smoothed_rtt {
+            Some(srtt) => (srtt + self.rtt_variance * 4).clamp(MIN_RTO, MAX_RTO),
+            None => INITIAL_RTO,
+        }
+    }
+
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    /// Collect messages whose timeout elapsed, refreshing their piggybacked acks.
+    /// Messages that exhausted their retransmits are dropped and counted in the
+    /// second return value.
+    pub fn take_due(
+        &mut self,
+        now: Instant,
+        ack: u32,
+        ack_bits: u32,
+    ) -> (Vec<ServerMessage>, usize) {
+        let rto = self.retransmission_timeout();
+        let mut due = Vec::new();
+        let mut expired = Vec::new();
+
+        for (sequence, pending) in self.pending.iter_mut() {
+            let backoff = (rto * 2u32.pow(pending.retransmits.min(5))).min(MAX_RTO);
+            if now.duration_since(pending.last_sent) < backoff {
+                continue;
+            }
+
+            if pending.retransmits >= MAX_RETRANSMITS {
+                expired.push(*sequence);
+                continue;
+            }
+
+            pending.retransmits += 1;
+            pending.last_sent = now;
+            pending.message.ack = ack;
+            pending.message.ack_bits = ack_bits;
+            due.push(pending.message.clone());
+        }
+
+        for sequence in &expired {
+            self.pending.remove(sequence);
+        }
+
+        (due, expired.len())
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.smoothed_rtt {
+            None => {
+                self.smoothed_rtt = Some(sample);
+                self.rtt_variance = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rtt_variance = (self.rtt_variance * 3 + delta) / 4;
+                self.smoothed_rtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+    }
+}
+
+/// Receive side of ordered delivery: releases items in `reliable_sequence`
+/// order, holding back any that arrive ahead of a gap
+#[derive(Debug, Clone)]
+pub struct ReorderBuffer<T> {
+    /// Ordering number of the next item to release
+    next: u32,
+    held: BTreeMap<u32, T>,
+}
+
+impl<T> Default for ReorderBuffer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new() -> Self {
+        Self {
+            next: 1,
+            held: BTreeMap::new(),
+        }
+    }
+
+    /// Accept the item numbered `sequence` and return every item it makes
+    /// deliverable, in order. Items already released, and items too far
+    /// ahead to hold, are dropped.
+    pub fn insert(&mut self, sequence: u32, item: T) -> Vec<T> {
+        if sequence < self.next || sequence - self.next >= REORDER_WINDOW {
+            return Vec::new();
+        }
+
+        self.held.entry(sequence).or_insert(item);
+
+        let mut ready = Vec::new();
+        while let Some(item) = self.held.remove(&self.next) {
+            ready.push(item);
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+/// Every sequence acknowledged by an `ack`/`ack_bits` pair
+pub fn acked_sequences(ack: u32, ack_bits: u32) -> impl Iterator<Item = u32> {
+    std::iter::once(ack).chain((0..ACK_WINDOW).filter_map(move |bit| {
+        let sequence = ack.checked_sub(bit + 1)?;
+        (sequence != 0 && ack_bits & (1 << bit) != 0).then_some(sequence)
+    }))
+}
@@ -0,0 +1,162 @@
+use crate::network::reliable::{ACK_WINDOW, acked_sequences};
+use std::collections::VecDeque;
+
+/// Gain of the interarrival jitter estimator (RFC 3550)
+const JITTER_GAIN: f32 = 1.0 / 16.0;
+/// Weight of the newest period when smoothing loss percentages
+const LOSS_SMOOTHING: f32 = 0.25;
+/// Sent sequences are counted as lost once this many are waiting for an ack
+const MAX_OUTBOUND_TRACKED: usize = 1024;
+
+/// Round trip, jitter and packet loss estimates for one session
+#[derive(Debug, Clone, Default)]
+pub struct LinkStats {
+    smoothed_rtt_ms: Option<f32>,
+    rtt_variance_ms: f32,
+    jitter_ms: f32,
+    /// Receive time minus client send time of the previous ping
+    last_ping_transit_ms: Option<i64>,
+    inbound_loss_percent: f32,
+    outbound_loss_percent: f32,
+    /// Counters for the current period
+    inbound_received: u32,
+    inbound_lost: u32,
+    outbound_delivered: u32,
+    outbound_lost: u32,
+    /// Oldest sent sequence that has not been settled as delivered or lost
+    outbound_base: u32,
+    /// Whether each sequence from `outbound_base` onward was acked
+    outbound_acked: VecDeque<bool>,
+}
+
+impl LinkStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed an RTT sample, smoothed the same way as TCP (RFC 6298)
+    pub fn record_rtt(&mut self, rtt_ms: f32) {
+        match self.smoothed_rtt_ms {
+            None => {
+                self.smoothed_rtt_ms = Some(rtt_ms);
+                self.rtt_variance_ms = rtt_ms / 2.0;
+            }
+            Some(srtt) => {
+                self.rtt_variance_ms = 0.75 * self.rtt_variance_ms + 0.25 * (srtt - rtt_ms).abs();
+                self.smoothed_rtt_ms = Some(0.875 * srtt + 0.125 * rtt_ms);
+            }
+        }
+    }
+
+    /// Feed a ping's client send time and server receive time. Clocks do not
+    /// need to be in sync since only the change in transit time is used.
+    pub fn record_ping_transit(&mut self, sent_ms: u64, received_ms: u64) {
+        let transit = received_ms as i64 - sent_ms as i64;
+
+        if let Some(last) = self.last_ping_transit_ms {
+            let delta = (transit - last).abs() as f32;
+            self.jitter_ms += (delta - self.jitter_ms) * JITTER_GAIN;
+        }
+        self.last_ping_transit_ms = Some(transit);
+    }
+
+    pub fn record_received(&mut self) {
+        self.inbound_received += 1;
+    }
+
+    pub fn record_lost(&mut self, count: u32) {
+        self.inbound_lost += count;
+    }
+
+    /// A packet counted as lost arrived late after all
+    pub fn record_recovered(&mut self) {
+        self.inbound_lost = self.inbound_lost.saturating_sub(1);
+        self.inbound_received += 1;
+    }
+
+    pub fn record_sent(&mut self, sequence: u32) {
+        if self.outbound_acked.is_empty() {
+            self.outbound_base = sequence;
+        }
+        self.outbound_acked.push_back(false);
+
+        while self.outbound_acked.len() > MAX_OUTBOUND_TRACKED {
+            self.settle_oldest();
+        }
+    }
+
+    pub fn record_ack(&mut self, ack: u32, ack_bits: u32) {
+        if ack == 0 {
+            return;
+        }
+
+        for sequence in acked_sequences(ack, ack_bits) {
+            if let Some(offset) = sequence.checked_sub(self.outbound_base)
+                && let Some(acked) = self.outbound_acked.get_mut(offset as usize)
+            {
+                *acked = true;
+            }
+        }
+
+        // Anything older than the ack window can no longer be acked
+        let horizon = ack.saturating_sub(ACK_WINDOW);
+        while !self.outbound_acked.is_empty() && self.outbound_base < horizon {
+            self.settle_oldest();
+        }
+    }
+
+    /// Close the current period, folding its counters into the loss percentages
+    pub fn roll_period(&mut self) {
+        if let Some(loss) = loss_percent(self.inbound_lost, self.inbound_received) {
+            self.inbound_loss_percent = smooth(self.inbound_loss_percent, loss);
+        }
+        if let Some(loss) = loss_percent(self.outbound_lost, self.outbound_delivered) {
+            self.outbound_loss_percent = smooth(self.outbound_loss_percent, loss);
+        }
+
+        self.inbound_received = 0;
+        self.inbound_lost = 0;
+        self.outbound_delivered = 0;
+        self.outbound_lost = 0;
+    }
+
+    pub fn smoothed_rtt_ms(&self) -> Option<f32> {
+        self.smoothed_rtt_ms
+    }
+
+    pub fn rtt_variance_ms(&self) -> f32 {
+        self.rtt_variance_ms
+    }
+
+    pub fn jitter_ms(&self) -> f32 {
+        self.jitter_ms
+    }
+
+    pub fn inbound_loss_percent(&self) -> f32 {
+        self.inbound_loss_percent
+    }
+
+    pub fn outbound_loss_percent(&self) -> f32 {
+        self.outbound_loss_percent
+    }
+
+    fn settle_oldest(&mut self) {
+        if let Some(acked) = self.outbound_acked.pop_front() {
+            if acked {
+                self.outbound_delivered += 1;
+            } else {
+                self.outbound_lost += 1;
+            }
+            self.outbound_base += 1;
+        }
+    }
+}
+
+fn loss_percent(lost: u32, delivered: u32) -> Option<f32> {
+    let total = lost + delivered;
+    (total > 0).then(|| lost as f32 * 100.0 / total as f32)
+}
+
+fn smooth(previous: f32, sample: f32) -> f32 {
+    previous + (sample - previous) * LOSS_SMOOTHING
+}
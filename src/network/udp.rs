@@ -1,35 +1,391 @@
+use crate::network::fragment::{self, MAX_DATAGRAM_SIZE};
+use crate::protocol::server::{ServerMessage, server_message};
+use prost::Message;
 use tokio::net::UdpSocket;
 use std::net::SocketAddr;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+#[cfg(target_os = "linux")]
+use crate::network::batch;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
 
 pub struct UdpServer {
     socket: UdpSocket,
+    next_fragment_id: AtomicU32,
+    /// Use recvmmsg/sendmmsg instead of one syscall per datagram
+    batched: bool,
+    /// Coalesce runs of datagrams to one peer with UDP GSO. Cleared if the
+    /// kernel or NIC turns out not to support it.
+    gso: AtomicBool,
+    #[cfg(target_os = "linux")]
+    recv_buffers: std::sync::Mutex<batch::RecvBuffers>,
+    /// Receive buffer for one datagram at a time, reused by every recv
+    recv_buffer: std::sync::Mutex<Vec<u8>>,
+    io: IoCounters,
+}
+
+/// Datagrams and the syscalls it took to move them, to see what batching saves
+#[derive(Debug, Default)]
+struct IoCounters {
+    datagrams_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    send_calls: AtomicU64,
+    datagrams_received: AtomicU64,
+    bytes_received: AtomicU64,
+    recv_calls: AtomicU64,
+}
+
+/// Point in time copy of a socket's I/O counters
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IoSnapshot {
+    pub datagrams_sent: u64,
+    pub bytes_sent: u64,
+    pub send_calls: u64,
+    pub datagrams_received: u64,
+    pub bytes_received: u64,
+    pub recv_calls: u64,
+}
+
+/// Spare buffers an outbox keeps around after a flush
+const MAX_SPARE_BUFFERS: usize = 256;
+/// Capacity of a fresh datagram buffer, enough for a typical datagram
+const BUFFER_CAPACITY: usize = 1500;
+
+/// Datagrams queued while handling a tick, sent together by [`UdpServer::flush`].
+/// Buffers are recycled after each flush, so a long lived outbox stops
+/// allocating once it has seen its busiest tick.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    datagrams: Vec<(Vec<u8>, SocketAddr)>,
+    spare: Vec<Vec<u8>>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, data: Vec<u8>, addr: SocketAddr) {
+        self.datagrams.push((data, addr));
+    }
+
+    /// An empty buffer to encode a datagram into, reused when possible
+    pub fn buffer(&mut self) -> Vec<u8> {
+        self.spare
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(BUFFER_CAPACITY))
+    }
+
+    /// Hand back a buffer that ended up not being queued
+    pub fn recycle(&mut self, mut buffer: Vec<u8>) {
+        if self.spare.len() < MAX_SPARE_BUFFERS {
+            buffer.clear();
+            self.spare.push(buffer);
+        }
+    }
+
+    /// Forget the queued datagrams, keeping their buffers
+    fn clear(&mut self) {
+        let datagrams = std::mem::take(&mut self.datagrams);
+        for (buffer, _) in datagrams {
+            self.recycle(buffer);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
 }
 
 impl UdpServer {
-    pub async fn bind(addr: &str) -> Result<Self> {
+    /// Bind a socket to `addr`, using batched I/O when `batched` is set and
+    /// the platform has it
+    pub async fn bind(addr: &str, batched: bool) -> Result<Self> {
         let socket = UdpSocket::bind(addr).await?;
         tracing::info!("UDP server listening on {}", addr);
-        Ok(Self { socket })
+        Ok(Self::from_socket(socket, batched))
     }
 
+    /// Bind `count` sockets to `addr` with SO_REUSEPORT. The kernel spreads
+    /// clients across them by hashing their address, so each client keeps
+    /// landing on the same socket.
+    pub async fn bind_reuseport(addr: &str, count: usize, batched: bool) -> Result<Vec<Self>> {
+        if count <= 1 {
+            return Ok(vec![Self::bind(addr, batched).await?]);
+        }
+
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        let mut servers = Vec::with_capacity(count);
+        for _ in 0..count {
+            let socket = UdpSocket::from_std(reuseport_socket(addr)?)?;
+            servers.push(Self::from_socket(socket, batched));
+        }
+        tracing::info!("UDP server listening on {} with {} sockets", addr, count);
+        Ok(servers)
+    }
+
+    fn from_socket(socket: UdpSocket, batched: bool) -> Self {
+        #[cfg(target_os = "linux")]
+        let gso = batched && batch::gso_supported(socket.as_raw_fd());
+        #[cfg(not(target_os = "linux"))]
+        let (batched, gso) = {
+            let _ = batched;
+            (false, false)
+        };
+
+        Self {
+            socket,
+            next_fragment_id: AtomicU32::new(1),
+            batched,
+            gso: AtomicBool::new(gso),
+            #[cfg(target_os = "linux")]
+            recv_buffers: std::sync::Mutex::new(batch::RecvBuffers::new()),
+            recv_buffer: std::sync::Mutex::new(vec![0; MAX_DATAGRAM_SIZE]),
+            io: IoCounters::default(),
+        }
+    }
+
+    /// Wait for one datagram. It is received into the socket's own buffer and
+    /// copied out at its actual size.
     pub async fn recv(&self) -> Result<(Vec<u8>, SocketAddr)> {
-        let mut buf = vec![0u8; 1024];
-        let (len, addr) = self.socket.recv_from(&mut buf).await?;
-        buf.truncate(len);
-        Ok((buf, addr))
+        loop {
+            self.socket.readable().await?;
+            let received = {
+                let mut buf = self.recv_buffer.lock().unwrap();
+                self.socket
+                    .try_recv_from(&mut buf)
+                    .map(|(len, addr)| (buf[..len].to_vec(), addr))
+            };
+
+            match received {
+                Ok((data, addr)) => {
+                    self.io.recv_calls.fetch_add(1, Ordering::Relaxed);
+                    self.io.datagrams_received.fetch_add(1, Ordering::Relaxed);
+                    self.io
+                        .bytes_received
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    return Ok((data, addr));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Wait for datagrams and return every one already queued on the socket,
+    /// up to a batch. Falls back to [`UdpServer::recv`] without batched I/O.
+    pub async fn recv_batch(&self) -> Result<Vec<(Vec<u8>, SocketAddr)>> {
+        #[cfg(target_os = "linux")]
+        if self.batched {
+            loop {
+                self.socket.readable().await?;
+                let received = self.socket.try_io(tokio::io::Interest::READABLE, || {
+                    let mut buffers = self.recv_buffers.lock().unwrap();
+                    batch::recv(self.socket.as_raw_fd(), &mut buffers)
+                });
+
+                match received {
+                    Ok(datagrams) => {
+                        self.io.recv_calls.fetch_add(1, Ordering::Relaxed);
+                        self.io
+                            .datagrams_received
+                            .fetch_add(datagrams.len() as u64, Ordering::Relaxed);
+                        let bytes: usize = datagrams.iter().map(|(data, _)| data.len()).sum();
+                        self.io
+                            .bytes_received
+                            .fetch_add(bytes as u64, Ordering::Relaxed);
+                        return Ok(datagrams);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok(vec![self.recv().await?])
     }
 
     pub async fn send(&self, data: &[u8], addr: SocketAddr) -> Result<()> {
         self.socket.send_to(data, addr).await?;
+        self.io.send_calls.fetch_add(1, Ordering::Relaxed);
+        self.io.datagrams_sent.fetch_add(1, Ordering::Relaxed);
+        self.io
+            .bytes_sent
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
         Ok(())
     }
 
-    pub async fn send_to_many(&self, data: &[u8], addrs: &[SocketAddr]) {
-        for addr in addrs {
-            if let Err(e) = self.socket.send_to(data, addr).await {
+    /// Encode and send a message, fragmenting it when it does not fit in `mtu`
+    pub async fn send_message(
+        &self,
+        message: &ServerMessage,
+        addr: SocketAddr,
+        mtu: usize,
+    ) -> Result<()> {
+        let mut outbox = Outbox::default();
+        self.queue_message(&mut outbox, message, addr, mtu);
+
+        if outbox.len() == 1 {
+            let (data, addr) = outbox.datagrams.pop().unwrap();
+            return self.send(&data, addr).await;
+        }
+        self.flush(&mut outbox).await;
+        Ok(())
+    }
+
+    /// Encode a message into `outbox`, fragmenting it when it does not fit in `mtu`
+    pub fn queue_message(
+        &self,
+        outbox: &mut Outbox,
+        message: &ServerMessage,
+        addr: SocketAddr,
+        mtu: usize,
+    ) {
+        let mut data = outbox.buffer();
+        message
+            .encode(&mut data)
+            .expect("Vec<u8> grows to fit any message");
+        self.queue_encoded(outbox, data, addr, mtu);
+    }
+
+    /// Queue an already encoded message, fragmenting it when it does not fit in `mtu`
+    pub fn queue_encoded(&self, outbox: &mut Outbox, data: Vec<u8>, addr: SocketAddr, mtu: usize) {
+        if data.len() <= mtu {
+            outbox.push(data, addr);
+            return;
+        }
+
+        let message_id = self.next_fragment_id.fetch_add(1, Ordering::Relaxed);
+        let fragments = fragment::split(&data, mtu, message_id);
+        tracing::trace!(
+            "Sending {} byte message to {} as {} fragments",
+            data.len(),
+            addr,
+            fragments.len()
+        );
+
+        for fragment in fragments {
+            let envelope = ServerMessage {
+                payload: Some(server_message::Payload::Fragment(fragment)),
+                ..Default::default()
+            };
+            let mut buffer = outbox.buffer();
+            envelope
+                .encode(&mut buffer)
+                .expect("Vec<u8> grows to fit any message");
+            outbox.push(buffer, addr);
+        }
+        outbox.recycle(data);
+    }
+
+    /// Send everything queued in `outbox`, batched where the platform allows.
+    /// A datagram that cannot be sent is logged and skipped.
+    pub async fn flush(&self, outbox: &mut Outbox) {
+        if outbox.is_empty() {
+            return;
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.batched {
+            self.send_batched(&outbox.datagrams).await;
+            outbox.clear();
+            return;
+        }
+
+        for (data, addr) in &outbox.datagrams {
+            if let Err(e) = self.send(data, *addr).await {
                 tracing::warn!("Failed to send to {}: {}", addr, e);
             }
         }
+        outbox.clear();
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn send_batched(&self, datagrams: &[(Vec<u8>, SocketAddr)]) {
+        let mut transmits = batch::coalesce(datagrams, self.gso.load(Ordering::Relaxed));
+        let mut sent = 0;
+
+        while sent < transmits.len() {
+            if let Err(e) = self.socket.writable().await {
+                tracing::warn!("Socket unusable for sending: {}", e);
+                return;
+            }
+
+            let result = self.socket.try_io(tokio::io::Interest::WRITABLE, || {
+                batch::send(self.socket.as_raw_fd(), &transmits[sent..])
+            });
+
+            match result {
+                Ok(count) => {
+                    self.io.send_calls.fetch_add(1, Ordering::Relaxed);
+                    sent += count;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                // GSO is refused at send time when the device cannot offload
+                // checksums. Fall back to one datagram per peer from here on.
+                Err(e)
+                    if e.raw_os_error() == Some(libc::EIO)
+                        && self.gso.swap(false, Ordering::Relaxed) =>
+                {
+                    tracing::warn!("UDP GSO unavailable, sending datagrams individually");
+                    let done: usize = transmits[..sent].iter().map(|t| t.count).sum();
+                    transmits = batch::coalesce(&datagrams[done..], false);
+                    sent = 0;
+                }
+                // sendmmsg only fails outright on the first datagram, so skip it
+                Err(e) => {
+                    tracing::warn!("Failed to send to {}: {}", transmits[sent].addr, e);
+                    sent += 1;
+                }
+            }
+        }
+
+        self.io
+            .datagrams_sent
+            .fetch_add(datagrams.len() as u64, Ordering::Relaxed);
+        let bytes: usize = datagrams.iter().map(|(data, _)| data.len()).sum();
+        self.io
+            .bytes_sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
     }
-}
\ No newline at end of file
+
+    pub async fn send_to_many(&self, data: &[u8], addrs: &[SocketAddr]) {
+        let mut outbox = Outbox::default();
+        for addr in addrs {
+            outbox.push(data.to_vec(), *addr);
+        }
+        self.flush(&mut outbox).await;
+    }
+
+    pub fn io_stats(&self) -> IoSnapshot {
+        IoSnapshot {
+            datagrams_sent: self.io.datagrams_sent.load(Ordering::Relaxed),
+            bytes_sent: self.io.bytes_sent.load(Ordering::Relaxed),
+            send_calls: self.io.send_calls.load(Ordering::Relaxed),
+            datagrams_received: self.io.datagrams_received.load(Ordering::Relaxed),
+            bytes_received: self.io.bytes_received.load(Ordering::Relaxed),
+            recv_calls: self.io.recv_calls.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn reuseport_socket(addr: SocketAddr) -> Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+fn reuseport_socket(_addr: SocketAddr) -> Result<std::net::UdpSocket> {
+    Err(Error::new(ErrorKind::Unsupported, "SO_REUSEPORT is not available on this platform"))
+}
@@ -0,0 +1,732 @@
+use crate::clock::current_timestamp_ms;
+use crate::config::RoomConfig;
+use crate::metrics;
+use crate::network::udp::{Outbox, UdpServer};
+use crate::protocol::client::GameMessage;
+use crate::protocol::common::DeliveryMode;
+use crate::protocol::server::{
+    Error, GameMessage as ServerGameMessage, GameStarting, MatchmakingStatus, NetworkStats,
+    PlayerDisconnected, PlayerInfo, PlayerKicked, PlayerLeft, PlayerNetworkStats,
+    PlayerReconnected, RoomJoined, RoomUpdate, server_message,
+};
+use crate::room::lobby::{Lobby, RoomListing};
+use crate::room::{Room, RoomAccess, RoomError, RoomSettings, RoomState, RoomSummary};
+use crate::session::PlayerId;
+use crate::session::link::{self, SharedBody, SharedLink};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
+
+/// Commands waiting for a room task beyond this are refused
+pub const ROOM_QUEUE_CAPACITY: usize = 1024;
+/// Most queued commands handled before the room flushes what they produced
+const MAX_COMMANDS_PER_FLUSH: usize = 64;
+
+/// Work routed to a room task by the dispatcher
+#[derive(Debug)]
+pub enum RoomCommand {
+    Join {
+        player_id: PlayerId,
+        name: String,
+        /// Checked against the room's password, if it has one
+        password: String,
+        link: SharedLink,
+        reconnect_token: String,
+        /// Where to report a rejection, i.e. the worker that owns the player
+        events: mpsc::UnboundedSender<RoomEvent>,
+    },
+    Leave {
+        player_id: PlayerId,
+    },
+    Ready {
+        player_id: PlayerId,
+    },
+    Game {
+        player_id: PlayerId,
+        message: GameMessage,
+        /// When the dispatcher received it, to measure relay latency
+        received_at: Instant,
+    },
+    Disconnected {
+        player_id: PlayerId,
+        grace_period_seconds: u32,
+    },
+    Reconnected {
+        player_id: PlayerId,
+        reconnect_token: String,
+        /// The worker that owns the player now, which may have changed
+        events: mpsc::UnboundedSender<RoomEvent>,
+    },
+    /// The host removes `target` from the room
+    Kick {
+        player_id: PlayerId,
+        target: PlayerId,
+    },
+    /// The host locks or unlocks the room
+    SetLocked {
+        player_id: PlayerId,
+        locked: bool,
+    },
+    /// The host hands the role to `target`
+    TransferHost {
+        player_id: PlayerId,
+        target: PlayerId,
+    },
+    Describe {
+        reply: oneshot::Sender<RoomSummary>,
+    },
+    /// Start the game now, whether or not everyone is ready
+    ForceStart {
+        reply: oneshot::Sender<Result<(), RoomError>>,
+    },
+}
+
+impl RoomCommand {
+    /// Name of the command, as logged
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RoomCommand::Join { .. } => "join",
+            RoomCommand::Leave { .. } => "leave",
+            RoomCommand::Ready { .. } => "ready",
+            RoomCommand::Game { .. } => "game",
+            RoomCommand::Disconnected { .. } => "disconnected",
+            RoomCommand::Reconnected { .. } => "reconnected",
+            RoomCommand::Kick { .. } => "kick",
+            RoomCommand::SetLocked { .. } => "set_locked",
+            RoomCommand::TransferHost { .. } => "transfer_host",
+            RoomCommand::Describe { .. } => "describe",
+            RoomCommand::ForceStart { .. } => "force_start",
+        }
+    }
+
+    /// The player the command is about, if any
+    pub fn player_id(&self) -> Option<PlayerId> {
+        match self {
+            RoomCommand::Join { player_id, .. }
+            | RoomCommand::Leave { player_id }
+            | RoomCommand::Ready { player_id }
+            | RoomCommand::Game { player_id, .. }
+            | RoomCommand::Disconnected { player_id, .. }
+            | RoomCommand::Reconnected { player_id, .. }
+            | RoomCommand::Kick { player_id, .. }
+            | RoomCommand::SetLocked { player_id, .. }
+            | RoomCommand::TransferHost { player_id, .. } => Some(*player_id),
+            RoomCommand::Describe { .. } | RoomCommand::ForceStart { .. } => None,
+        }
+    }
+}
+
+/// What a room task or the matchmaker reports back to the dispatcher
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoomEvent {
+    /// The room refused a join the dispatcher had already routed to it
+    JoinRejected {
+        player_id: PlayerId,
+        room_code: String,
+    },
+    /// The host kicked the player out of the room
+    Kicked {
+        player_id: PlayerId,
+        room_code: String,
+    },
+    /// The player's place in the matchmaking queue changed, or they left it
+    MatchStatus {
+        player_id: PlayerId,
+        status: MatchmakingStatus,
+    },
+    /// The matchmaker put the player in a match and started its room, which
+    /// holds a place for them until they are joined to it or released
+    MatchFound {
+        player_id: PlayerId,
+        /// Found, with the room's code
+        status: MatchmakingStatus,
+        password: String,
+    },
+    /// The matchmaker would not queue the player
+    MatchRefused {
+        player_id: PlayerId,
+        error: RoomError,
+    },
+}
+
+/// Sending side of a room task. The task stops once every handle is dropped
+/// and its queue is drained.
+#[derive(Debug, Clone)]
+pub struct RoomHandle {
+    commands: mpsc::Sender<RoomCommand>,
+}
+
+impl RoomHandle {
+    pub fn spawn(
+        code: String,
+        config: RoomConfig,
+        settings: RoomSettings,
+        access: RoomAccess,
+        server: Arc<UdpServer>,
+        lobby: Arc<Lobby>,
+    ) -> Self {
+        let (commands, receiver) = mpsc::channel(ROOM_QUEUE_CAPACITY);
+        // A root span, not a child of whichever message created the room
+        let span = tracing::info_span!(parent: None, "room", room_code = %code);
+        let actor = RoomActor {
+            room: Room::new(code, settings, access),
+            config,
+            start_server_time: None,
+            links: HashMap::new(),
+            events: HashMap::new(),
+            disconnected: HashSet::new(),
+            server,
+            outbox: Outbox::default(),
+            relayed: Vec::new(),
+            lobby_instance: lobby.instance(),
+            lobby,
+            listing_stale: true,
+        };
+        tokio::spawn(actor.run(receiver).instrument(span));
+        Self { commands }
+    }
+
+    /// Queue a command, waiting for space. False if the task is gone.
+    pub async fn send(&self, command: RoomCommand) -> bool {
+        self.commands.send(command).await.is_ok()
+    }
+
+    /// Queue a command unless the room is backed up
+    pub fn try_send(&self, command: RoomCommand) -> bool {
+        self.commands.try_send(command).is_ok()
+    }
+}
+
+/// A room running as its own task. It owns the room state and the links of
+/// its players, so it can reply and broadcast without any shared lock.
+struct RoomActor {
+    room: Room,
+    config: RoomConfig,
+    /// When the game started or starts, told to players who join late
+    start_server_time: Option<u64>,
+    links: HashMap<PlayerId, SharedLink>,
+    /// Where to report what happens to each player, i.e. the worker that owns them
+    events: HashMap<PlayerId, mpsc::UnboundedSender<RoomEvent>>,
+    /// Players within their reconnect grace period
+    disconnected: HashSet<PlayerId>,
+    server: Arc<UdpServer>,
+    /// Datagrams queued while handling commands, flushed after each batch
+    outbox: Outbox,
+    /// Receive times of the game messages relayed since the last flush
+    relayed: Vec<Instant>,
+    lobby: Arc<Lobby>,
+    /// Tags this task's listings in the lobby
+    lobby_instance: u64,
+    /// Something the room browser shows may have changed since the last publish
+    listing_stale: bool,
+}
+
+impl RoomActor {
+    async fn run(mut self, mut commands: mpsc::Receiver<RoomCommand>) {
+        let mut stats_interval = tokio::time::interval(Duration::from_secs(
+            self.config.network_stats_interval_seconds,
+        ));
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => {
+                        self.handle(command);
+                        // Take whatever else is already queued, so the replies
+                        // to all of it go out in one batch
+                        for _ in 1..MAX_COMMANDS_PER_FLUSH {
+                            let Ok(command) = commands.try_recv() else {
+                                break;
+                            };
+                            self.handle(command);
+                        }
+                    }
+                    None => break,
+                },
+                _ = stats_interval.tick() => {
+                    self.report_network_stats();
+                    // Latency moves without any command
+                    self.listing_stale = true;
+                }
+            }
+
+            self.server.flush(&mut self.outbox).await;
+            for received_at in self.relayed.drain(..) {
+                metrics::global().observe_fanout(received_at.elapsed());
+            }
+            if self.listing_stale {
+                self.publish_listing();
+            }
+        }
+
+        self.lobby.remove(self.lobby_instance, &self.room.code);
+        tracing::debug!("Room {} task stopped", self.room.code);
+    }
+
+    fn handle(&mut self, command: RoomCommand) {
+        // Game messages are relayed without a span of their own, to keep the hot path cheap
+        let _span = match command {
+            RoomCommand::Game { .. } => None,
+            _ => Some(
+                tracing::info_span!(
+                    "command",
+                    kind = command.kind(),
+                    player_id = command.player_id()
+                )
+                .entered(),
+            ),
+        };
+
+        // Anything but a game message or a query may change what the room browser shows
+        if !matches!(
+            command,
+            RoomCommand::Game { .. } | RoomCommand::Describe { .. }
+        ) {
+            self.listing_stale = true;
+        }
+
+        match command {
+            RoomCommand::Join {
+                player_id,
+                name,
+                password,
+                link,
+                reconnect_token,
+                events,
+            } => {
+                self.join(player_id, name, &password, link, reconnect_token, events);
+            }
+            RoomCommand::Leave { player_id } => self.leave(player_id),
+            RoomCommand::Ready { player_id } => self.ready(player_id),
+            RoomCommand::Game {
+                player_id,
+                message,
+                received_at,
+            } => self.relay(player_id, message, received_at),
+            RoomCommand::Disconnected {
+                player_id,
+                grace_period_seconds,
+            } => {
+                self.disconnected.insert(player_id);
+                self.broadcast_except(
+                    player_id,
+                    server_message::Payload::PlayerDisconnected(PlayerDisconnected {
+                        player_id,
+                        grace_period_seconds,
+                    }),
+                );
+            }
+            RoomCommand::Reconnected {
+                player_id,
+                reconnect_token,
+                events,
+            } => {
+                self.reconnected(player_id, reconnect_token, events);
+            }
+            RoomCommand::Kick { player_id, target } => {
+                let result = self.room.kick(player_id, target);
+                if let Err(e) = result {
+                    self.refuse(player_id, "Kick failed", e);
+                    return;
+                }
+                self.kicked(target);
+            }
+            RoomCommand::SetLocked { player_id, locked } => {
+                if let Err(e) = self.room.set_locked(player_id, locked) {
+                    self.refuse(player_id, "Changing the lock failed", e);
+                    return;
+                }
+                tracing::info!(
+                    "Room {} {} by its host",
+                    self.room.code,
+                    if locked { "locked" } else { "unlocked" }
+                );
+                self.broadcast(self.room_update());
+            }
+            RoomCommand::TransferHost { player_id, target } => {
+                if let Err(e) = self.room.transfer_host(player_id, target) {
+                    self.refuse(player_id, "Host transfer failed", e);
+                    return;
+                }
+                tracing::info!(
+                    "Host of room {} passed from player {} to {}",
+                    self.room.code,
+                    player_id,
+                    target
+                );
+                self.broadcast(self.room_update());
+            }
+            RoomCommand::Describe { reply } => {
+                let _ = reply.send(self.summary());
+            }
+            RoomCommand::ForceStart { reply } => {
+                let result = if self.room.state == RoomState::Waiting {
+                    tracing::info!("Room {} force started", self.room.code);
+                    self.start_game();
+                    Ok(())
+                } else {
+                    Err(RoomError::GameInProgress)
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    fn summary(&self) -> RoomSummary {
+        let mut players: Vec<_> = self.room.players.values().cloned().collect();
+        players.sort_by_key(|player| player.player_id);
+        RoomSummary {
+            code: self.room.code.clone(),
+            state: self.room.state.clone(),
+            players,
+            settings: self.room.settings.clone(),
+            host: self.room.host,
+            locked: self.room.locked,
+            has_password: self.room.access.password.is_some(),
+            private: self.room.access.private,
+        }
+    }
+
+    fn join(
+        &mut self,
+        player_id: PlayerId,
+        name: String,
+        password: &str,
+        link: SharedLink,
+        reconnect_token: String,
+        events: mpsc::UnboundedSender<RoomEvent>,
+    ) {
+        if let Err(e) = self.room.add_player(player_id, name.clone(), password) {
+            link::queue_reliable(
+                &self.server,
+                &mut self.outbox,
+                &link,
+                server_message::Payload::Error(Error {
+                    message: format!("Failed to join room: {:?}", e),
+                    code: e.code() as i32,
+                }),
+            );
+            let _ = events.send(RoomEvent::JoinRejected {
+                player_id,
+                room_code: self.room.code.clone(),
+            });
+            return;
+        }
+
+        self.links.insert(player_id, link.clone());
+        self.events.insert(player_id, events);
+
+        tracing::debug!("Sending RoomJoined to player {}", player_id);
+        let joined = self.room_joined(player_id, reconnect_token);
+        link::queue_reliable(&self.server, &mut self.outbox, &link, joined);
+        if let Some(starting) = self.game_starting() {
+            link::queue_reliable(&self.server, &mut self.outbox, &link, starting);
+        }
+
+        self.broadcast_except(player_id, self.room_update());
+
+        tracing::info!(
+            "Player {} ({}) joined room '{}' ({} players)",
+            player_id,
+            name,
+            self.room.code,
+            self.room.player_count()
+        );
+    }
+
+    fn leave(&mut self, player_id: PlayerId) {
+        if self.room.remove_player(player_id).is_none() {
+            return;
+        }
+        self.forget(player_id);
+
+        self.broadcast_except(
+            player_id,
+            server_message::Payload::PlayerLeft(PlayerLeft { player_id }),
+        );
+
+        tracing::info!("Player {} left room {}", player_id, self.room.code);
+        self.elect_host();
+    }
+
+    /// Tell the room, the kicked player included, then let the player's worker
+    /// drop its route to the room
+    fn kicked(&mut self, player_id: PlayerId) {
+        self.broadcast(server_message::Payload::PlayerKicked(PlayerKicked {
+            player_id,
+        }));
+        if let Some(events) = self.events.get(&player_id) {
+            let _ = events.send(RoomEvent::Kicked {
+                player_id,
+                room_code: self.room.code.clone(),
+            });
+        }
+        self.forget(player_id);
+
+        tracing::info!(
+            "Player {} kicked from room {} by its host",
+            player_id,
+            self.room.code
+        );
+    }
+
+    /// Drop the state of a player no longer in the room
+    fn forget(&mut self, player_id: PlayerId) {
+        self.links.remove(&player_id);
+        self.events.remove(&player_id);
+        self.disconnected.remove(&player_id);
+    }
+
+    /// Pass the host role on once the host has left, preferring a connected player
+    fn elect_host(&mut self) {
+        let disconnected = &self.disconnected;
+        let Some(host) = self.room.elect_host(|id| !disconnected.contains(&id)) else {
+            return;
+        };
+        tracing::info!("Player {} is now host of room {}", host, self.room.code);
+        self.broadcast(self.room_update());
+    }
+
+    /// Update the room's listing in the room browser. Private rooms are never listed.
+    fn publish_listing(&mut self) {
+        self.listing_stale = false;
+        if self.room.access.private {
+            return;
+        }
+
+        let rtts: Vec<f32> = self
+            .links
+            .iter()
+            .filter(|(pid, _)| !self.disconnected.contains(pid))
+            .filter_map(|(_, link)| link.lock().unwrap().stats.smoothed_rtt_ms())
+            .collect();
+        let average_rtt_ms = (!rtts.is_empty())
+            .then(|| (rtts.iter().sum::<f32>() / rtts.len() as f32).round() as u32);
+        let host_name = self
+            .room
+            .host
+            .and_then(|host| self.room.players.get(&host))
+            .map(|host| host.name.clone())
+            .unwrap_or_default();
+
+        let settings = &self.room.settings;
+        self.lobby.publish(
+            self.lobby_instance,
+            RoomListing {
+                code: self.room.code.clone(),
+                name: settings.name.clone(),
+                game_mode: settings.game_mode.clone(),
+                state: self.room.state.clone(),
+                player_count: self.room.player_count(),
+                max_players: settings.max_players,
+                host_name,
+                average_rtt_ms,
+                has_password: self.room.access.password.is_some(),
+                locked: self.room.locked,
+            },
+        );
+    }
+
+    /// Reply to a host action the room refused
+    fn refuse(&mut self, player_id: PlayerId, action: &str, error: RoomError) {
+        if let Some(link) = self.links.get(&player_id) {
+            link::queue_reliable(
+                &self.server,
+                &mut self.outbox,
+                link,
+                server_message::Payload::Error(Error {
+                    message: format!("{}: {:?}", action, error),
+                    code: error.code() as i32,
+                }),
+            );
+        }
+    }
+
+    fn ready(&mut self, player_id: PlayerId) {
+        if self.room.set_ready(player_id, true).is_err() {
+            return;
+        }
+
+        tracing::info!(
+            "Player {} ready in room {} ({}/{})",
+            player_id,
+            self.room.code,
+            self.room.players.values().filter(|p| p.ready).count(),
+            self.room.player_count()
+        );
+
+        // Notify all players of updated ready status
+        self.broadcast(self.room_update());
+
+        // Check if game should start
+        if self.room.can_start() {
+            self.start_game();
+        }
+    }
+
+    fn start_game(&mut self) {
+        self.room.state = RoomState::Playing;
+
+        // Notify all players game is starting, at the same server time for everyone
+        let countdown_seconds = self.room.settings.countdown_seconds;
+        self.start_server_time = Some(current_timestamp_ms() + countdown_seconds as u64 * 1000);
+
+        if let Some(starting) = self.game_starting() {
+            self.broadcast(starting);
+        }
+
+        tracing::info!("Room {} starting game!", self.room.code);
+    }
+
+    /// GameStarting with the time left to the start, once the game has started
+    fn game_starting(&self) -> Option<server_message::Payload> {
+        let start_server_time = self.start_server_time?;
+        let remaining_ms = start_server_time.saturating_sub(current_timestamp_ms());
+        Some(server_message::Payload::GameStarting(GameStarting {
+            countdown_seconds: remaining_ms.div_ceil(1000) as u32,
+            start_server_time,
+        }))
+    }
+
+    fn relay(&mut self, player_id: PlayerId, message: GameMessage, received_at: Instant) {
+        if self.room.state != RoomState::Playing {
+            tracing::debug!("Ignoring GameMessage - room not playing");
+            return;
+        }
+
+        let delivery = message.delivery();
+        self.fan_out(
+            Some(player_id),
+            server_message::Payload::GameMessage(ServerGameMessage {
+                from_player_id: player_id,
+                payload: message.payload,
+                delivery: message.delivery,
+            }),
+            delivery,
+        );
+        self.relayed.push(received_at);
+
+        tracing::trace!(
+            "Relayed message from player {} to room {}",
+            player_id,
+            self.room.code
+        );
+    }
+
+    fn reconnected(
+        &mut self,
+        player_id: PlayerId,
+        reconnect_token: String,
+        events: mpsc::UnboundedSender<RoomEvent>,
+    ) {
+        self.disconnected.remove(&player_id);
+        let Some(link) = self.links.get(&player_id).cloned() else {
+            return;
+        };
+        self.events.insert(player_id, events);
+
+        let joined = self.room_joined(player_id, reconnect_token);
+        link::queue_reliable(&self.server, &mut self.outbox, &link, joined);
+
+        self.broadcast_except(
+            player_id,
+            server_message::Payload::PlayerReconnected(PlayerReconnected { player_id }),
+        );
+
+        tracing::info!(
+            "Player {} reconnected to room {}",
+            player_id,
+            self.room.code
+        );
+    }
+
+    /// Report everyone's connection quality to the connected players
+    fn report_network_stats(&mut self) {
+        if self.links.is_empty() {
+            return;
+        }
+
+        let players: Vec<PlayerNetworkStats> = self
+            .links
+            .values()
+            .map(|link| {
+                let mut link = link.lock().unwrap();
+                link.stats.roll_period();
+                link.network_stats()
+            })
+            .collect();
+
+        for (pid, link) in &self.links {
+            if self.disconnected.contains(pid) {
+                continue;
+            }
+
+            link::queue_unreliable(
+                &self.server,
+                &mut self.outbox,
+                link,
+                server_message::Payload::NetworkStats(NetworkStats {
+                    players: players.clone(),
+                }),
+            );
+        }
+    }
+
+    fn room_joined(&self, player_id: PlayerId, reconnect_token: String) -> server_message::Payload {
+        server_message::Payload::RoomJoined(RoomJoined {
+            player_id,
+            room_code: self.room.code.clone(),
+            players: self.player_infos(),
+            reconnect_token,
+            host_id: self.room.host.unwrap_or_default(),
+            locked: self.room.locked,
+            settings: Some(self.room.settings.to_proto()),
+        })
+    }
+
+    fn room_update(&self) -> server_message::Payload {
+        server_message::Payload::RoomUpdate(RoomUpdate {
+            players: self.player_infos(),
+            host_id: self.room.host.unwrap_or_default(),
+            locked: self.room.locked,
+            settings: Some(self.room.settings.to_proto()),
+        })
+    }
+
+    fn player_infos(&self) -> Vec<PlayerInfo> {
+        self.room
+            .players
+            .values()
+            .map(|p| PlayerInfo {
+                player_id: p.player_id,
+                name: p.name.clone(),
+                ready: p.ready,
+            })
+            .collect()
+    }
+
+    fn broadcast(&mut self, payload: server_message::Payload) {
+        self.fan_out(None, payload, DeliveryMode::ReliableOrdered);
+    }
+
+    fn broadcast_except(&mut self, player_id: PlayerId, payload: server_message::Payload) {
+        self.fan_out(Some(player_id), payload, DeliveryMode::ReliableOrdered);
+    }
+
+    /// Queue `payload` for every member but `except`, encoding it only once
+    fn fan_out(
+        &mut self,
+        except: Option<PlayerId>,
+        payload: server_message::Payload,
+        delivery: DeliveryMode,
+    ) {
+        let body = SharedBody::new(payload);
+        for (pid, link) in &self.links {
+            if Some(*pid) != except {
+                link::queue_shared(&self.server, &mut self.outbox, link, &body, delivery);
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Public rooms as shown in the room browser. Each room task publishes its own
+//! listing, so a ListRooms is answered without asking every room.
+
+use crate::protocol::client::ListRooms;
+use crate::protocol::server::{self, RoomList};
+use crate::room::RoomState;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Rooms per page when a ListRooms does not ask for a size
+pub const DEFAULT_PAGE_SIZE: usize = 10;
+/// Most rooms per page, whatever a ListRooms asks for
+pub const MAX_PAGE_SIZE: usize = 25;
+/// Subscribers get at most one RoomList per interval, however often rooms change
+pub const PUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A public room as listed to players
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomListing {
+    pub code: String,
+    pub name: String,
+    pub game_mode: String,
+    pub state: RoomState,
+    pub player_count: usize,
+    pub max_players: usize,
+    pub host_name: String,
+    /// Mean smoothed RTT of the connected players that have one
+    pub average_rtt_ms: Option<u32>,
+    pub has_password: bool,
+    pub locked: bool,
+}
+
+impl RoomListing {
+    fn to_proto(&self) -> server::RoomListing {
+        server::RoomListing {
+            room_code: self.code.clone(),
+            name: self.name.clone(),
+            game_mode: self.game_mode.clone(),
+            state: match self.state {
+                RoomState::Waiting => server::RoomState::Waiting,
+                RoomState::Playing => server::RoomState::Playing,
+                RoomState::Ended => server::RoomState::Ended,
+            } as i32,
+            player_count: self.player_count as u32,
+            max_players: self.max_players as u32,
+            host_name: self.host_name.clone(),
+            average_rtt_ms: self.average_rtt_ms.unwrap_or_default(),
+            has_password: self.has_password,
+            locked: self.locked,
+        }
+    }
+}
+
+/// A room browser page a player asked to be kept up to date on
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub query: ListRooms,
+    /// What the player was last sent, so an unchanged page is not sent again
+    pub last_sent: RoomList,
+}
+
+/// Listings of every public room, shared by all room tasks and workers
+#[derive(Debug)]
+pub struct Lobby {
+    /// Listings by room code, each tagged with the room task that published it
+    rooms: Mutex<BTreeMap<String, (u64, RoomListing)>>,
+    next_instance: AtomicU64,
+    /// Bumped when a room is listed or delisted, or its listing changes in
+    /// more than latency
+    changes: watch::Sender<u64>,
+}
+
+impl Default for Lobby {
+    fn default() -> Self {
+        Self {
+            rooms: Mutex::new(BTreeMap::new()),
+            next_instance: AtomicU64::new(1),
+            changes: watch::Sender::new(0),
+        }
+    }
+}
+
+impl Lobby {
+    /// Tag for a new room task's listings. A room closing while another opens
+    /// under its code cannot overwrite or delist the newer one.
+    pub fn instance(&self) -> u64 {
+        self.next_instance.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// List a room, or update its listing
+    pub fn publish(&self, instance: u64, listing: RoomListing) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let changed = match rooms.get(&listing.code) {
+            Some((current, _)) if *current > instance => return,
+            Some((_, old)) => {
+                *old != RoomListing {
+                    average_rtt_ms: old.average_rtt_ms,
+                    ..listing.clone()
+                }
+            }
+            None => true,
+        };
+        rooms.insert(listing.code.clone(), (instance, listing));
+        drop(rooms);
+
+        if changed {
+            self.changes.send_modify(|version| *version += 1);
+        }
+    }
+
+    /// Delist a room, if the listing is still the one `instance` published
+    pub fn remove(&self, instance: u64, code: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms
+            .get(code)
+            .is_some_and(|(current, _)| *current == instance)
+        {
+            rooms.remove(code);
+            drop(rooms);
+            self.changes.send_modify(|version| *version += 1);
+        }
+    }
+
+    /// Receiver that sees every change to the listings
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// The page of listings `query` asks for
+    pub fn list(&self, query: &ListRooms) -> RoomList {
+        let filter = query.filter.clone().unwrap_or_default();
+        let page_size = match query.page_size as usize {
+            0 => DEFAULT_PAGE_SIZE,
+            n => n.min(MAX_PAGE_SIZE),
+        };
+
+        let rooms = self.rooms.lock().unwrap();
+        let matching: Vec<&RoomListing> = rooms
+            .values()
+            .map(|(_, listing)| listing)
+            .filter(|listing| filter.game_mode.is_empty() || listing.game_mode == filter.game_mode)
+            .filter(|listing| !filter.has_free_slot || listing.player_count < listing.max_players)
+            .filter(|listing| !filter.not_started || listing.state == RoomState::Waiting)
+            .collect();
+
+        // Rooms are ordered by code, so the token is the last code of the
+        // previous page and stays valid as rooms come and go
+        let page: Vec<&RoomListing> = matching
+            .iter()
+            .filter(|listing| listing.code.as_str() > query.page_token.as_str())
+            .take(page_size + 1)
+            .copied()
+            .collect();
+        let next_page_token = if page.len() > page_size {
+            page[page_size - 1].code.clone()
+        } else {
+            String::new()
+        };
+
+        RoomList {
+            rooms: page
+                .iter()
+                .take(page_size)
+                .map(|listing| listing.to_proto())
+                .collect(),
+            next_page_token,
+            total: matching.len() as u32,
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! Quick Play queue. Players queue with a game mode, a rating and optionally a
+//! party, and one task shared by every worker periodically groups them: by a
+//! rating window that widens the longer they wait, and by how close their
+//! round trip times are. Each match gets a private room, started here and held
+//! for its players until their workers move them in.
+
+use super::{
+    RoomAccess, RoomEntry, RoomError, RoomRegistry, RoomSettings, check_game_mode,
+    generate_room_code,
+};
+use crate::config::{MatchmakingConfig, RoomConfig};
+use crate::network::udp::UdpServer;
+use crate::protocol::client::FindMatch;
+use crate::protocol::common;
+use crate::protocol::server::{MatchmakingState, MatchmakingStatus};
+use crate::room::actor::{RoomEvent, RoomHandle};
+use crate::room::lobby::Lobby;
+use crate::room::password::PasswordHash;
+use crate::session::PlayerId;
+use crate::session::link::SharedLink;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Waits of this many recent matches of a game mode go into its wait estimate
+const WAIT_SAMPLES: usize = 16;
+/// Random bytes in the password of a match's room, sent hex encoded
+const MATCH_PASSWORD_LEN: usize = 16;
+
+pub enum MatchmakerCommand {
+    Enqueue {
+        player_id: PlayerId,
+        request: FindMatch,
+        link: SharedLink,
+        /// Where the player's worker hears about their queue status and match
+        events: mpsc::UnboundedSender<RoomEvent>,
+        /// Socket of the player's worker
+        server: Arc<UdpServer>,
+    },
+    /// Take the player, and their party, out of the queue
+    Cancel { player_id: PlayerId },
+    Reconfigure {
+        config: MatchmakingConfig,
+        /// Settings for the rooms of matches made from now on
+        room: RoomConfig,
+    },
+}
+
+/// Sending side of the matchmaker task. The task stops once every handle is
+/// dropped.
+#[derive(Debug, Clone)]
+pub struct MatchmakerHandle {
+    commands: mpsc::UnboundedSender<MatchmakerCommand>,
+}
+
+impl MatchmakerHandle {
+    /// Start the matchmaker. Rooms for matches are registered in `rooms`.
+    pub(super) fn spawn(
+        config: MatchmakingConfig,
+        room_config: RoomConfig,
+        rooms: RoomRegistry,
+        lobby: Arc<Lobby>,
+    ) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let matchmaker = Matchmaker {
+            config,
+            room_config,
+            rooms,
+            lobby,
+            tickets: Vec::new(),
+            recent_waits: HashMap::new(),
+        };
+        tokio::spawn(matchmaker.run(receiver));
+        Self { commands }
+    }
+
+    pub fn send(&self, command: MatchmakerCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+}
+
+/// A queued player
+struct Ticket {
+    player_id: PlayerId,
+    game_mode: String,
+    rating: u32,
+    /// Empty for a player queued alone
+    party_code: String,
+    party_size: usize,
+    link: SharedLink,
+    events: mpsc::UnboundedSender<RoomEvent>,
+    server: Arc<UdpServer>,
+    enqueued_at: Instant,
+}
+
+/// Tickets matched as one: a whole party, or a player queued alone
+struct Group {
+    /// Indices into the queue
+    tickets: Vec<usize>,
+    game_mode: String,
+    /// Mean rating of the members
+    rating: u32,
+    /// Mean smoothed RTT of the members that have one
+    rtt_ms: Option<f32>,
+    /// Accepted rating difference, from how long the longest waiting member has
+    window: u32,
+}
+
+struct Matchmaker {
+    config: MatchmakingConfig,
+    room_config: RoomConfig,
+    rooms: RoomRegistry,
+    lobby: Arc<Lobby>,
+    /// Queued players, longest waiting first
+    tickets: Vec<Ticket>,
+    /// Waits of the latest matches, by game mode
+    recent_waits: HashMap<String, VecDeque<Duration>>,
+}
+
+impl Matchmaker {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<MatchmakerCommand>) {
+        let mut interval = tokio::time::interval(self.config.interval());
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => {
+                    let Some(command) = command else { break };
+                    self.handle(command);
+                    if interval.period() != self.config.interval() {
+                        interval = tokio::time::interval(self.config.interval());
+                    }
+                }
+                _ = interval.tick() => self.tick(),
+            }
+        }
+    }
+
+    fn handle(&mut self, command: MatchmakerCommand) {
+        match command {
+            MatchmakerCommand::Enqueue {
+                player_id,
+                request,
+                link,
+                events,
+                server,
+            } => {
+                if let Err(error) = self.enqueue(player_id, request, link, events.clone(), server) {
+                    let _ = events.send(RoomEvent::MatchRefused { player_id, error });
+                }
+            }
+            MatchmakerCommand::Cancel { player_id } => self.cancel(player_id),
+            MatchmakerCommand::Reconfigure { config, room } => {
+                self.config = config;
+                self.room_config = room;
+            }
+        }
+    }
+
+    fn enqueue(
+        &mut self,
+        player_id: PlayerId,
+        request: FindMatch,
+        link: SharedLink,
+        events: mpsc::UnboundedSender<RoomEvent>,
+        server: Arc<UdpServer>,
+    ) -> Result<(), RoomError> {
+        // Queueing again replaces the player's ticket, not their party's
+        self.tickets.retain(|ticket| ticket.player_id != player_id);
+
+        // The match's room would refuse it once every player was matched
+        check_game_mode(&request.game_mode)?;
+
+        let party_size = if request.party_code.is_empty() {
+            1
+        } else {
+            request.party_size as usize
+        };
+        if party_size == 0 || party_size > self.config.players_per_match {
+            return Err(RoomError::InvalidParty);
+        }
+
+        if !request.party_code.is_empty() {
+            let members: Vec<&Ticket> = self
+                .tickets
+                .iter()
+                .filter(|ticket| ticket.party_code == request.party_code)
+                .collect();
+            if members.iter().any(|ticket| {
+                ticket.game_mode != request.game_mode || ticket.party_size != party_size
+            }) {
+                return Err(RoomError::PartyMismatch);
+            }
+            if members.len() >= party_size {
+                return Err(RoomError::PartyFull);
+            }
+        }
+
+        tracing::debug!(
+            "Player {} queued for {:?} at rating {}",
+            player_id,
+            request.game_mode,
+            request.rating
+        );
+        self.tickets.push(Ticket {
+            player_id,
+            game_mode: request.game_mode,
+            rating: request.rating,
+            party_code: request.party_code,
+            party_size,
+            link,
+            events,
+            server,
+            enqueued_at: Instant::now(),
+        });
+
+        let ticket = self.tickets.last().unwrap();
+        let status = self.searching(ticket, self.queued(&ticket.game_mode), Instant::now());
+        let _ = ticket
+            .events
+            .send(RoomEvent::MatchStatus { player_id, status });
+        Ok(())
+    }
+
+    fn cancel(&mut self, player_id: PlayerId) {
+        let Some(ticket) = self
+            .tickets
+            .iter()
+            .find(|ticket| ticket.player_id == player_id)
+        else {
+            return;
+        };
+        let party_code = ticket.party_code.clone();
+
+        let (cancelled, kept) = std::mem::take(&mut self.tickets)
+            .into_iter()
+            .partition(|ticket| {
+                ticket.player_id == player_id
+                    || (!party_code.is_empty() && ticket.party_code == party_code)
+            });
+        self.tickets = kept;
+
+        for ticket in cancelled {
+            tracing::debug!("Player {} left the matchmaking queue", ticket.player_id);
+            let _ = ticket.events.send(RoomEvent::MatchStatus {
+                player_id: ticket.player_id,
+                status: MatchmakingStatus {
+                    state: MatchmakingState::Cancelled as i32,
+                    game_mode: ticket.game_mode,
+                    ..Default::default()
+                },
+            });
+        }
+    }
+
+    /// Start a room for every match that can be made, and tell everyone still
+    /// queued how it is going
+    fn tick(&mut self) {
+        let now = Instant::now();
+        for tickets in self.take_matches(now) {
+            self.start_match(tickets, now);
+        }
+
+        let mut queued: HashMap<&str, usize> = HashMap::new();
+        for ticket in &self.tickets {
+            *queued.entry(ticket.game_mode.as_str()).or_default() += 1;
+        }
+        for ticket in &self.tickets {
+            let status = self.searching(ticket, queued[ticket.game_mode.as_str()], now);
+            let _ = ticket.events.send(RoomEvent::MatchStatus {
+                player_id: ticket.player_id,
+                status,
+            });
+        }
+    }
+
+    /// Take the players of every match that can be made out of the queue
+    fn take_matches(&mut self, now: Instant) -> Vec<Vec<Ticket>> {
+        let matches = self.find_matches(now);
+        let mut match_of = vec![None; self.tickets.len()];
+        for (number, indices) in matches.iter().enumerate() {
+            for &index in indices {
+                match_of[index] = Some(number);
+            }
+        }
+
+        let mut matched: Vec<Vec<Ticket>> = matches.iter().map(|_| Vec::new()).collect();
+        let mut kept = Vec::new();
+        for (ticket, number) in std::mem::take(&mut self.tickets).into_iter().zip(match_of) {
+            match number {
+                Some(number) => matched[number].push(ticket),
+                None => kept.push(ticket),
+            }
+        }
+        self.tickets = kept;
+        matched
+    }
+
+    /// Groups of tickets to put in a room together, as queue indices. Longest
+    /// waiting first, each group takes the closest rated groups of its game
+    /// mode that are within either one's window and close enough in RTT, and
+    /// is matched if they add up to exactly a room.
+    fn find_matches(&self, now: Instant) -> Vec<Vec<usize>> {
+        let groups = self.groups(now);
+        let target = self.config.players_per_match;
+        let max_rtt_difference = self.config.max_rtt_difference_ms as f32;
+        let mut matched = vec![false; groups.len()];
+        let mut matches = Vec::new();
+
+        for (anchor_index, anchor) in groups.iter().enumerate() {
+            if matched[anchor_index] {
+                continue;
+            }
+
+            let mut candidates: Vec<usize> = (0..groups.len())
+                .filter(|&index| index != anchor_index && !matched[index])
+                .filter(|&index| {
+                    let group = &groups[index];
+                    group.game_mode == anchor.game_mode
+                        && group.rating.abs_diff(anchor.rating) <= group.window.max(anchor.window)
+                        // A player without an RTT yet is close enough to anyone
+                        && match (group.rtt_ms, anchor.rtt_ms) {
+                            (Some(a), Some(b)) => (a - b).abs() <= max_rtt_difference,
+                            _ => true,
+                        }
+                })
+                .collect();
+            candidates.sort_by_key(|&index| groups[index].rating.abs_diff(anchor.rating));
+
+            let mut chosen = vec![anchor_index];
+            let mut size = anchor.tickets.len();
+            for index in candidates {
+                if size == target {
+                    break;
+                }
+                if size + groups[index].tickets.len() <= target {
+                    chosen.push(index);
+                    size += groups[index].tickets.len();
+                }
+            }
+
+            if size == target {
+                for &index in &chosen {
+                    matched[index] = true;
+                }
+                matches.push(
+                    chosen
+                        .iter()
+                        .flat_map(|&index| groups[index].tickets.iter().copied())
+                        .collect(),
+                );
+            }
+        }
+        matches
+    }
+
+    /// Queued players in the units they are matched in. Parties still waiting
+    /// for members to queue are left out.
+    fn groups(&self, now: Instant) -> Vec<Group> {
+        let mut members: Vec<Vec<usize>> = Vec::new();
+        let mut parties: HashMap<&str, usize> = HashMap::new();
+        for (index, ticket) in self.tickets.iter().enumerate() {
+            if ticket.party_code.is_empty() {
+                members.push(vec![index]);
+            } else if let Some(&group) = parties.get(ticket.party_code.as_str()) {
+                members[group].push(index);
+            } else {
+                parties.insert(&ticket.party_code, members.len());
+                members.push(vec![index]);
+            }
+        }
+
+        members
+            .into_iter()
+            .filter(|tickets| tickets.len() == self.tickets[tickets[0]].party_size)
+            .map(|tickets| {
+                let first = &self.tickets[tickets[0]];
+                let rating = tickets
+                    .iter()
+                    .map(|&index| self.tickets[index].rating as u64)
+                    .sum::<u64>()
+                    / tickets.len() as u64;
+                let rtts: Vec<f32> = tickets
+                    .iter()
+                    .filter_map(|&index| {
+                        self.tickets[index]
+                            .link
+                            .lock()
+                            .unwrap()
+                            .stats
+                            .smoothed_rtt_ms()
+                    })
+                    .collect();
+                let rtt_ms =
+                    (!rtts.is_empty()).then(|| rtts.iter().sum::<f32>() / rtts.len() as f32);
+
+                Group {
+                    game_mode: first.game_mode.clone(),
+                    rating: rating as u32,
+                    rtt_ms,
+                    // Tickets are in queue order, so the first has waited longest
+                    window: self.config.rating_window(now - first.enqueued_at),
+                    tickets,
+                }
+            })
+            .collect()
+    }
+
+    /// Start the match's room, held for its players, and send each of them
+    /// its code and password
+    fn start_match(&mut self, tickets: Vec<Ticket>, now: Instant) {
+        let game_mode = tickets[0].game_mode.clone();
+        let requested = common::RoomSettings {
+            max_players: self.config.players_per_match as u32,
+            game_mode: game_mode.clone(),
+            ..Default::default()
+        };
+        let settings = match RoomSettings::requested(requested, &self.room_config) {
+            Ok(settings) => settings,
+            Err(error) => {
+                tracing::warn!(
+                    "Cannot start a room for a {:?} match: {:?}",
+                    game_mode,
+                    error
+                );
+                for ticket in tickets {
+                    let _ = ticket.events.send(RoomEvent::MatchRefused {
+                        player_id: ticket.player_id,
+                        error: error.clone(),
+                    });
+                }
+                return;
+            }
+        };
+
+        // The password keeps anyone but the matched players out of the room
+        let mut password = [0u8; MATCH_PASSWORD_LEN];
+        getrandom::fill(&mut password).expect("OS random number generator unavailable");
+        let password = hex::encode(password);
+        let access = RoomAccess {
+            password: Some(PasswordHash::new(&password)),
+            private: true,
+        };
+
+        // The room sends through the socket of the longest waiting player's
+        // worker, so rooms of matches spread over the workers like any other
+        let server = tickets[0].server.clone();
+
+        // The players are members from the start, so the room stays up until
+        // each of them has joined and left, or been released by their worker
+        let room_code = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let code = generate_room_code(&rooms);
+            let handle = RoomHandle::spawn(
+                code.clone(),
+                self.room_config.clone(),
+                settings,
+                access,
+                server,
+                self.lobby.clone(),
+            );
+            rooms.insert(
+                code.clone(),
+                RoomEntry {
+                    handle,
+                    members: tickets.iter().map(|ticket| ticket.player_id).collect(),
+                },
+            );
+            code
+        };
+
+        tracing::info!(
+            "Matched {} players for {:?} into room {}",
+            tickets.len(),
+            game_mode,
+            room_code
+        );
+
+        let waits = self.recent_waits.entry(game_mode.clone()).or_default();
+        for ticket in tickets {
+            let waited = now - ticket.enqueued_at;
+            if waits.len() == WAIT_SAMPLES {
+                waits.pop_front();
+            }
+            waits.push_back(waited);
+
+            let _ = ticket.events.send(RoomEvent::MatchFound {
+                player_id: ticket.player_id,
+                status: MatchmakingStatus {
+                    state: MatchmakingState::Found as i32,
+                    game_mode: game_mode.clone(),
+                    waited_seconds: waited.as_secs() as u32,
+                    room_code: room_code.clone(),
+                    ..Default::default()
+                },
+                password: password.clone(),
+            });
+        }
+    }
+
+    fn queued(&self, game_mode: &str) -> usize {
+        self.tickets
+            .iter()
+            .filter(|ticket| ticket.game_mode == game_mode)
+            .count()
+    }
+
+    fn searching(&self, ticket: &Ticket, queued: usize, now: Instant) -> MatchmakingStatus {
+        let waited = now - ticket.enqueued_at;
+        let estimated = self
+            .recent_waits
+            .get(&ticket.game_mode)
+            .filter(|waits| !waits.is_empty())
+            .map(|waits| waits.iter().sum::<Duration>() / waits.len() as u32)
+            .map_or(0, |mean| {
+                mean.saturating_sub(waited).as_secs_f32().ceil() as u32
+            });
+
+        MatchmakingStatus {
+            state: MatchmakingState::Searching as i32,
+            game_mode: ticket.game_mode.clone(),
+            players_in_queue: queued as u32,
+            waited_seconds: waited.as_secs() as u32,
+            estimated_wait_seconds: estimated,
+            rating_window: self.config.rating_window(waited),
+            room_code: String::new(),
+        }
+    }
+}
@@ -1,8 +1,35 @@
-use std::collections::HashMap;
+pub mod actor;
+pub mod lobby;
+pub mod matchmaking;
+pub mod password;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use crate::config::{MAX_COUNTDOWN_SECONDS, MatchmakingConfig, RoomConfig};
+use crate::network::udp::UdpServer;
+use crate::protocol::client::{FindMatch, JoinIntent, JoinRoom};
+use crate::protocol::common;
+use crate::protocol::server::ErrorCode;
 use crate::session::PlayerId;
+use crate::session::link::SharedLink;
+use actor::{RoomCommand, RoomEvent, RoomHandle};
+use lobby::Lobby;
+use matchmaking::{MatchmakerCommand, MatchmakerHandle};
+use password::PasswordHash;
+
+/// Longest name a room may be created with, in bytes
+const MAX_ROOM_NAME_LEN: usize = 32;
+/// Longest game mode a room may be created with, in bytes
+const MAX_GAME_MODE_LEN: usize = 32;
+const MAX_METADATA_ENTRIES: usize = 16;
+const MAX_METADATA_KEY_LEN: usize = 32;
+const MAX_METADATA_VALUE_LEN: usize = 128;
 
 /// Possible states for a room
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RoomState {
     Waiting,
     Playing,
@@ -10,38 +37,208 @@ pub enum RoomState {
 }
 
 /// A player in a room
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct RoomPlayer {
     pub player_id: PlayerId,
     pub name: String,
     pub ready: bool,
 }
 
+/// Point in time view of a room, as reported by its task
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomSummary {
+    pub code: String,
+    pub state: RoomState,
+    pub players: Vec<RoomPlayer>,
+    #[serde(flatten)]
+    pub settings: RoomSettings,
+    pub host: Option<PlayerId>,
+    pub locked: bool,
+    pub has_password: bool,
+    pub private: bool,
+}
+
+/// How a room is set up, fixed when it is created
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoomSettings {
+    /// Shown in the room browser
+    pub name: String,
+    pub max_players: usize,
+    /// Ready players needed before the game starts
+    pub min_players_to_start: usize,
+    pub countdown_seconds: u32,
+    /// Let players join once the game has started
+    pub allow_late_join: bool,
+    /// Opaque to the server
+    pub game_mode: String,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl RoomSettings {
+    /// The server's defaults, for rooms created without settings of their own
+    pub fn from_config(config: &RoomConfig) -> Self {
+        Self {
+            name: String::new(),
+            max_players: config.max_players,
+            min_players_to_start: config.min_players_to_start,
+            countdown_seconds: config.countdown_seconds,
+            allow_late_join: false,
+            game_mode: String::new(),
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// The settings a client asked for, with the server's defaults for those
+    /// left unset, if they are within the server's limits
+    pub fn requested(
+        requested: common::RoomSettings,
+        config: &RoomConfig,
+    ) -> Result<Self, RoomError> {
+        let defaults = Self::from_config(config);
+        let max_players = match requested.max_players {
+            0 => defaults.max_players,
+            n => n as usize,
+        };
+        if max_players < 2 || max_players > config.max_players_limit {
+            return Err(RoomError::InvalidSettings(format!(
+                "max_players must be between 2 and {}",
+                config.max_players_limit
+            )));
+        }
+
+        let min_players_to_start = match requested.min_players_to_start {
+            0 => defaults.min_players_to_start.min(max_players),
+            n => n as usize,
+        };
+        if min_players_to_start > max_players {
+            return Err(RoomError::InvalidSettings(
+                "min_players_to_start must be at most max_players".to_string(),
+            ));
+        }
+
+        let countdown_seconds = requested
+            .countdown_seconds
+            .unwrap_or(defaults.countdown_seconds);
+        if countdown_seconds > MAX_COUNTDOWN_SECONDS {
+            return Err(RoomError::InvalidSettings(format!(
+                "countdown_seconds must be at most {}",
+                MAX_COUNTDOWN_SECONDS
+            )));
+        }
+
+        if requested.name.len() > MAX_ROOM_NAME_LEN {
+            return Err(RoomError::InvalidSettings(format!(
+                "name must be at most {} bytes",
+                MAX_ROOM_NAME_LEN
+            )));
+        }
+        check_game_mode(&requested.game_mode)?;
+        if requested.metadata.len() > MAX_METADATA_ENTRIES {
+            return Err(RoomError::InvalidSettings(format!(
+                "metadata must have at most {} entries",
+                MAX_METADATA_ENTRIES
+            )));
+        }
+        let oversized = requested.metadata.iter().any(|(key, value)| {
+            key.is_empty()
+                || key.len() > MAX_METADATA_KEY_LEN
+                || value.len() > MAX_METADATA_VALUE_LEN
+        });
+        if oversized {
+            return Err(RoomError::InvalidSettings(format!(
+                "metadata keys must be 1 to {} bytes and values at most {}",
+                MAX_METADATA_KEY_LEN, MAX_METADATA_VALUE_LEN
+            )));
+        }
+
+        Ok(Self {
+            name: requested.name,
+            max_players,
+            min_players_to_start,
+            countdown_seconds,
+            allow_late_join: requested.allow_late_join,
+            game_mode: requested.game_mode,
+            metadata: requested.metadata.into_iter().collect(),
+        })
+    }
+
+    /// As sent to clients in RoomJoined and RoomUpdate
+    pub fn to_proto(&self) -> common::RoomSettings {
+        common::RoomSettings {
+            name: self.name.clone(),
+            max_players: self.max_players as u32,
+            min_players_to_start: self.min_players_to_start as u32,
+            countdown_seconds: Some(self.countdown_seconds),
+            allow_late_join: self.allow_late_join,
+            game_mode: self.game_mode.clone(),
+            metadata: self.metadata.clone().into_iter().collect(),
+        }
+    }
+}
+
+/// Who may join a room, fixed when it is created
+#[derive(Debug, Clone, Default)]
+pub struct RoomAccess {
+    /// Required of every player joining, if set
+    pub password: Option<PasswordHash>,
+    /// Left out of public listings, so only players given the code can join
+    pub private: bool,
+}
+
 /// A game room
 #[derive(Debug)]
 pub struct Room {
     pub code: String,
     pub players: HashMap<PlayerId, RoomPlayer>,
     pub state: RoomState,
-    pub max_players: usize,
+    pub settings: RoomSettings,
+    /// The player who may kick, lock and transfer the role, the first to join
+    /// until they leave
+    pub host: Option<PlayerId>,
+    /// Refuse new joins. Players already in the room can still reconnect.
+    pub locked: bool,
+    pub access: RoomAccess,
+    /// Players in the order they joined, to pick the next host
+    join_order: Vec<PlayerId>,
 }
 
 impl Room {
-    pub fn new(code: String, max_players: usize) -> Self {
+    pub fn new(code: String, settings: RoomSettings, access: RoomAccess) -> Self {
         Self {
             code,
             players: HashMap::new(),
             state: RoomState::Waiting,
-            max_players,
+            settings,
+            host: None,
+            locked: false,
+            access,
+            join_order: Vec::new(),
         }
     }
 
-    pub fn add_player(&mut self, player_id: PlayerId, name: String) -> Result<(), RoomError> {
-        if self.state != RoomState::Waiting {
+    /// Add a player who gave `password`, ignored unless the room has one
+    pub fn add_player(
+        &mut self,
+        player_id: PlayerId,
+        name: String,
+        password: &str,
+    ) -> Result<(), RoomError> {
+        if let Some(hash) = &self.access.password
+            && !hash.verify(password)
+        {
+            return Err(RoomError::WrongPassword);
+        }
+
+        let late_join = self.state == RoomState::Playing && self.settings.allow_late_join;
+        if self.state != RoomState::Waiting && !late_join {
             return Err(RoomError::GameInProgress);
         }
 
-        if self.players.len() >= self.max_players {
+        if self.locked {
+            return Err(RoomError::RoomLocked);
+        }
+
+        if self.players.len() >= self.settings.max_players {
             return Err(RoomError::RoomFull);
         }
 
@@ -54,12 +251,71 @@ impl Room {
             name,
             ready: false,
         });
+        self.join_order.push(player_id);
+        self.host.get_or_insert(player_id);
 
         Ok(())
     }
 
+    /// Remove a player. If they were host the room is left without one, see
+    /// [`Room::elect_host`].
     pub fn remove_player(&mut self, player_id: PlayerId) -> Option<RoomPlayer> {
-        self.players.remove(&player_id)
+        let player = self.players.remove(&player_id)?;
+        self.join_order.retain(|id| *id != player_id);
+        if self.host == Some(player_id) {
+            self.host = None;
+        }
+        Some(player)
+    }
+
+    /// Give a room without a host the player who joined first, preferring one
+    /// that `connected` accepts. Returns the new host.
+    pub fn elect_host(&mut self, connected: impl Fn(PlayerId) -> bool) -> Option<PlayerId> {
+        if self.host.is_some() {
+            return None;
+        }
+        self.host = self
+            .join_order
+            .iter()
+            .copied()
+            .find(|id| connected(*id))
+            .or_else(|| self.join_order.first().copied());
+        self.host
+    }
+
+    /// Check that `player_id` is in the room and its host
+    pub fn check_host(&self, player_id: PlayerId) -> Result<(), RoomError> {
+        if !self.players.contains_key(&player_id) {
+            return Err(RoomError::NotInRoom);
+        }
+        if self.host != Some(player_id) {
+            return Err(RoomError::NotHost);
+        }
+        Ok(())
+    }
+
+    /// Remove `target` on behalf of the host
+    pub fn kick(&mut self, host: PlayerId, target: PlayerId) -> Result<RoomPlayer, RoomError> {
+        self.check_host(host)?;
+        if target == host {
+            return Err(RoomError::CannotKickSelf);
+        }
+        self.remove_player(target).ok_or(RoomError::PlayerNotFound)
+    }
+
+    pub fn set_locked(&mut self, host: PlayerId, locked: bool) -> Result<(), RoomError> {
+        self.check_host(host)?;
+        self.locked = locked;
+        Ok(())
+    }
+
+    pub fn transfer_host(&mut self, host: PlayerId, target: PlayerId) -> Result<(), RoomError> {
+        self.check_host(host)?;
+        if !self.players.contains_key(&target) {
+            return Err(RoomError::PlayerNotFound);
+        }
+        self.host = Some(target);
+        Ok(())
     }
 
     pub fn set_ready(&mut self, player_id: PlayerId, ready: bool) -> Result<(), RoomError> {
@@ -72,6 +328,11 @@ impl Room {
         !self.players.is_empty() && self.players.values().all(|p| p.ready)
     }
 
+    /// Whether everyone is ready and there are enough of them to start
+    pub fn can_start(&self) -> bool {
+        self.all_ready() && self.player_count() >= self.settings.min_players_to_start
+    }
+
     pub fn player_count(&self) -> usize {
         self.players.len()
     }
@@ -93,142 +354,425 @@ pub enum RoomError {
     AlreadyInRoom,
     NotInRoom,
     RoomNotFound,
+    RoomLocked,
+    /// Only the host may do this
+    NotHost,
+    /// The player acted on is not in the room
+    PlayerNotFound,
+    CannotKickSelf,
+    WrongPassword,
+    /// A room was to be created under a code already in use
+    RoomExists,
+    /// Settings a room was to be created with are out of the server's limits
+    InvalidSettings(String),
+    /// A party must have a code and fit in a match
+    InvalidParty,
+    /// Every member of the party has already queued
+    PartyFull,
+    /// A member of the party queued for another game mode or party size
+    PartyMismatch,
+}
+
+impl RoomError {
+    /// The code sent to clients along with the error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RoomError::RoomFull => ErrorCode::RoomFull,
+            RoomError::GameInProgress => ErrorCode::GameInProgress,
+            RoomError::AlreadyInRoom => ErrorCode::AlreadyInRoom,
+            RoomError::NotInRoom => ErrorCode::NotInRoom,
+            RoomError::RoomNotFound => ErrorCode::RoomNotFound,
+            RoomError::RoomLocked => ErrorCode::RoomLocked,
+            RoomError::NotHost => ErrorCode::NotHost,
+            RoomError::PlayerNotFound => ErrorCode::PlayerNotFound,
+            RoomError::CannotKickSelf => ErrorCode::CannotKickSelf,
+            RoomError::WrongPassword => ErrorCode::WrongPassword,
+            RoomError::RoomExists => ErrorCode::RoomExists,
+            RoomError::InvalidSettings(_) => ErrorCode::InvalidSettings,
+            RoomError::InvalidParty => ErrorCode::InvalidParty,
+            RoomError::PartyFull => ErrorCode::PartyFull,
+            RoomError::PartyMismatch => ErrorCode::PartyMismatch,
+        }
+    }
 }
 
-/// Manages all rooms
-pub struct RoomManager {
-    rooms: HashMap<String, Room>,
-    player_room: HashMap<PlayerId, String>,
-    max_players_per_room: usize,
+/// Rooms by code, shared by the directories of every receive worker. The lock
+/// is only taken to look up, spawn or close a room, never across an await.
+type RoomRegistry = Arc<Mutex<HashMap<String, RoomEntry>>>;
+
+/// Routes a worker's players to room tasks. Rooms are shared between workers,
+/// but each player is routed by the worker that owns their session, so the
+/// hot path only reads this worker's own routes.
+pub struct RoomDirectory {
+    rooms: RoomRegistry,
+    player_room: HashMap<PlayerId, PlayerRoute>,
+    config: RoomConfig,
+    server: Arc<UdpServer>,
+    events: mpsc::UnboundedSender<RoomEvent>,
+    lobby: Arc<Lobby>,
+    matchmaker: MatchmakerHandle,
 }
 
-impl RoomManager {
-    pub fn new(max_players_per_room: usize) -> Self {
+struct RoomEntry {
+    handle: RoomHandle,
+    /// Players routed to the room and not yet left or rejected. The task is
+    /// stopped once this is empty.
+    members: HashSet<PlayerId>,
+}
+
+/// The room a player was routed to, handed between workers with the session
+#[derive(Debug, Clone)]
+pub struct PlayerRoute {
+    pub room_code: String,
+    handle: RoomHandle,
+}
+
+impl RoomDirectory {
+    pub fn new(
+        config: RoomConfig,
+        matchmaking: MatchmakingConfig,
+        server: Arc<UdpServer>,
+        events: mpsc::UnboundedSender<RoomEvent>,
+    ) -> Self {
+        let rooms: RoomRegistry = Arc::new(Mutex::new(HashMap::new()));
+        let lobby = Arc::new(Lobby::default());
+        let matchmaker =
+            MatchmakerHandle::spawn(matchmaking, config.clone(), rooms.clone(), lobby.clone());
         Self {
-            rooms: HashMap::new(),
+            rooms,
             player_room: HashMap::new(),
-            max_players_per_room,
+            config,
+            server,
+            events,
+            lobby,
+            matchmaker,
         }
     }
 
-    /// Create a new room with a random code
-    pub fn create_room(&mut self) -> &Room {
-        let code = self.generate_room_code();
-        let room = Room::new(code.clone(), self.max_players_per_room);
-        self.rooms.insert(code.clone(), room);
+    /// Directory for another worker, sharing the same rooms and matchmaker.
+    /// Rooms it starts send through `server`, the worker's own socket.
+    /// Rejections of joins it routes, and matchmaking updates of players it
+    /// queues, are reported to `events`.
+    pub fn sibling(
+        &self,
+        server: Arc<UdpServer>,
+        events: mpsc::UnboundedSender<RoomEvent>,
+    ) -> Self {
+        Self {
+            rooms: self.rooms.clone(),
+            player_room: HashMap::new(),
+            config: self.config.clone(),
+            server,
+            events,
+            lobby: self.lobby.clone(),
+            matchmaker: self.matchmaker.clone(),
+        }
+    }
+
+    /// Settings for rooms created from now on. Running rooms keep theirs.
+    pub fn reconfigure(&mut self, config: RoomConfig) {
+        self.config = config;
+    }
+
+    /// Settings for the matchmaker, shared with every sibling. Rooms of
+    /// matches get this directory's room settings.
+    pub fn reconfigure_matchmaking(&self, config: MatchmakingConfig) {
+        self.matchmaker.send(MatchmakerCommand::Reconfigure {
+            config,
+            room: self.config.clone(),
+        });
+    }
+
+    /// Queue the player for a match. Their status and match are reported to
+    /// this directory's events.
+    pub fn find_match(&self, player_id: PlayerId, request: FindMatch, link: SharedLink) {
+        self.matchmaker.send(MatchmakerCommand::Enqueue {
+            player_id,
+            request,
+            link,
+            events: self.events.clone(),
+            server: self.server.clone(),
+        });
+    }
+
+    /// Take the player, and their party, out of the matchmaking queue
+    pub fn cancel_match(&self, player_id: PlayerId) {
+        self.matchmaker.send(MatchmakerCommand::Cancel { player_id });
+    }
+
+    /// Start a room task under a new random code
+    pub fn create_room(&self, settings: RoomSettings, access: RoomAccess) -> String {
+        let mut rooms = self.rooms.lock().unwrap();
+        let code = generate_room_code(&rooms);
+        self.spawn_room(&mut rooms, &code, settings, access);
+        code
+    }
+
+    fn spawn_room(
+        &self,
+        rooms: &mut HashMap<String, RoomEntry>,
+        code: &str,
+        settings: RoomSettings,
+        access: RoomAccess,
+    ) -> RoomHandle {
+        let handle = RoomHandle::spawn(
+            code.to_string(),
+            self.config.clone(),
+            settings,
+            access,
+            self.server.clone(),
+            self.lobby.clone(),
+        );
+        rooms.insert(code.to_string(), RoomEntry {
+            handle: handle.clone(),
+            members: HashSet::new(),
+        });
         tracing::info!("Room created: {}", code);
-        self.rooms.get(&code).unwrap()
+        handle
     }
 
-    /// Join an existing room or create new one if code is empty
-    pub fn join_room(
+    /// Route a player to the room `join` asks for, creating it as its intent
+    /// allows. A room created here gets the password and privacy of `join`,
+    /// and `settings` or the server's defaults. Past that, the room task itself
+    /// decides whether the join succeeds.
+    pub async fn join_room(
         &mut self,
-        room_code: &str,
+        join: JoinRoom,
+        settings: Option<common::RoomSettings>,
         player_id: PlayerId,
-        player_name: String,
-    ) -> Result<&Room, RoomError> {
-        if let Some(old_code) = self.player_room.get(&player_id).cloned() {
-            self.leave_room(player_id);
-            tracing::debug!("Player {} left room {} to join {}", player_id, old_code, room_code);
+        link: SharedLink,
+        reconnect_token: String,
+    ) -> Result<String, RoomError> {
+        let settings = match settings {
+            Some(requested) => RoomSettings::requested(requested, &self.config)?,
+            None => RoomSettings::from_config(&self.config),
+        };
+        let intent = join.intent();
+        let exists = !join.room_code.is_empty() && self.room(&join.room_code).is_some();
+        match intent {
+            JoinIntent::Join if !exists => return Err(RoomError::RoomNotFound),
+            JoinIntent::Create if exists => return Err(RoomError::RoomExists),
+            _ => {}
         }
 
-        let code = if room_code.is_empty() || !self.rooms.contains_key(room_code) {
-            if room_code.is_empty() {
-                let room = self.create_room();
-                room.code.clone()
+        if let Some(old_code) = self.leave_room(player_id).await {
+            tracing::debug!(
+                "Player {} left room {} to join {}",
+                player_id,
+                old_code,
+                join.room_code
+            );
+        }
+
+        let (code, handle) = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let code = if join.room_code.is_empty() {
+                generate_room_code(&rooms)
             } else {
-                let room = Room::new(room_code.to_string(), self.max_players_per_room);
-                self.rooms.insert(room_code.to_string(), room);
-                tracing::info!("Room created: {}", room_code);
-                room_code.to_string()
-            }
-        } else {
-            room_code.to_string()
+                join.room_code.clone()
+            };
+
+            // Checked again under the lock the room is created under, as another
+            // worker may have created or closed it since
+            let handle = match rooms.get(&code) {
+                Some(_) if intent == JoinIntent::Create => return Err(RoomError::RoomExists),
+                Some(entry) => entry.handle.clone(),
+                None if intent == JoinIntent::Join => return Err(RoomError::RoomNotFound),
+                None => {
+                    let access = RoomAccess {
+                        password: (!join.password.is_empty())
+                            .then(|| PasswordHash::new(&join.password)),
+                        private: join.private,
+                    };
+                    self.spawn_room(&mut rooms, &code, settings, access)
+                }
+            };
+            rooms.get_mut(&code).unwrap().members.insert(player_id);
+            (code, handle)
         };
 
-        let room = self.rooms.get_mut(&code).unwrap();
-        room.add_player(player_id, player_name)?;
-        self.player_room.insert(player_id, code.clone());
+        self.player_room.insert(player_id, PlayerRoute {
+            room_code: code.clone(),
+            handle: handle.clone(),
+        });
+
+        let delivered = handle.send(RoomCommand::Join {
+            player_id,
+            name: join.player_name,
+            password: join.password,
+            link,
+            reconnect_token,
+            events: self.events.clone(),
+        }).await;
+        if !delivered {
+            tracing::warn!("Room {} task is gone, dropping join of player {}", code, player_id);
+        }
 
-        tracing::info!("Player {} joined room {}", player_id, code);
-        Ok(self.rooms.get(&code).unwrap())
+        Ok(code)
     }
 
     /// Remove player from their current room
-    pub fn leave_room(&mut self, player_id: PlayerId) -> Option<String> {
-        let room_code = self.player_room.remove(&player_id)?;
+    pub async fn leave_room(&mut self, player_id: PlayerId) -> Option<String> {
+        let route = self.player_room.remove(&player_id)?;
 
-        if let Some(room) = self.rooms.get_mut(&room_code) {
-            room.remove_player(player_id);
-            tracing::info!("Player {} left room {}", player_id, room_code);
+        self.remove_member(player_id, &route.room_code);
+        route.handle.send(RoomCommand::Leave { player_id }).await;
 
-            // Clean up empty rooms
-            if room.is_empty() {
-                self.rooms.remove(&room_code);
-                tracing::info!("Room {} removed (empty)", room_code);
-            }
+        Some(route.room_code)
+    }
+
+    /// Forget a player the room refused or removed, unless they have moved on since
+    pub fn drop_route(&mut self, player_id: PlayerId, room_code: &str) {
+        if self.get_player_room_code(player_id) != Some(room_code) {
+            return;
         }
+        self.player_room.remove(&player_id);
+        self.remove_member(player_id, room_code);
+    }
 
-        Some(room_code)
+    /// Give up the place a match's room holds for a player who was not joined
+    /// to it, so the room closes once nobody else is in it
+    pub fn release_reservation(&self, player_id: PlayerId, room_code: &str) {
+        if self.get_player_room_code(player_id) != Some(room_code) {
+            self.remove_member(player_id, room_code);
+        }
     }
 
-    /// Set player ready status
-    pub fn set_ready(&mut self, player_id: PlayerId, ready: bool) -> Result<&Room, RoomError> {
-        let room_code = self.player_room.get(&player_id).ok_or(RoomError::NotInRoom)?;
-        let room = self.rooms.get_mut(room_code).ok_or(RoomError::RoomNotFound)?;
-        room.set_ready(player_id, ready)?;
-        Ok(self.rooms.get(room_code).unwrap())
+    /// Drop the room's handle once its last member is gone, so its task
+    /// finishes its queue and stops
+    fn remove_member(&self, player_id: PlayerId, room_code: &str) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(entry) = rooms.get_mut(room_code) else {
+            return;
+        };
+
+        entry.members.remove(&player_id);
+        if entry.members.is_empty() {
+            rooms.remove(room_code);
+            tracing::info!("Room {} removed (empty)", room_code);
+        }
     }
 
-    /// Get room by code
-    pub fn get_room(&self, code: &str) -> Option<&Room> {
-        self.rooms.get(code)
+    /// Stop routing a player whose session is moving to another worker
+    pub fn detach(&mut self, player_id: PlayerId) -> Option<PlayerRoute> {
+        // Matchmaking updates would still go to this worker
+        self.cancel_match(player_id);
+        self.player_room.remove(&player_id)
     }
 
-    /// Get room by player ID
-    pub fn get_player_room(&self, player_id: PlayerId) -> Option<&Room> {
-        let code = self.player_room.get(&player_id)?;
-        self.rooms.get(code)
+    /// Route a player whose session moved here from another worker
+    pub fn attach(&mut self, player_id: PlayerId, route: PlayerRoute) {
+        self.player_room.insert(player_id, route);
     }
 
-    /// Get mutable room by player ID
-    pub fn get_player_room_mut(&mut self, player_id: PlayerId) -> Option<&mut Room> {
-        let code = self.player_room.get(&player_id)?.clone();
-        self.rooms.get_mut(&code)
+    /// Queue a command for the player's room, waiting if it is backed up
+    pub async fn send(&self, player_id: PlayerId, command: RoomCommand) -> bool {
+        match self.player_room.get(&player_id) {
+            Some(route) => route.handle.send(command).await,
+            None => false,
+        }
     }
 
-    /// Generate a random 4-character room code
-    fn generate_room_code(&self) -> String {
-        use std::time::{SystemTime, UNIX_EPOCH};
+    /// Tell the player's room they are back, and that this worker owns them now
+    pub async fn reconnected(&self, player_id: PlayerId, reconnect_token: String) -> bool {
+        self.send(player_id, RoomCommand::Reconnected {
+            player_id,
+            reconnect_token,
+            events: self.events.clone(),
+        }).await
+    }
 
-        let chars: Vec<char> = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789".chars().collect();
-        let mut code = String::with_capacity(4);
+    /// Queue a command for the player's room, dropping it if the room is backed up
+    pub fn try_send(&self, player_id: PlayerId, command: RoomCommand) -> bool {
+        self.player_room
+            .get(&player_id)
+            .is_some_and(|route| route.handle.try_send(command))
+    }
 
-        // Simple random based on time (not cryptographically secure, but fine for room codes)
-        let seed = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
+    /// Players this directory routes to the room
+    pub fn players_in(&self, room_code: &str) -> Vec<PlayerId> {
+        self.player_room
+            .iter()
+            .filter(|(_, route)| route.room_code == room_code)
+            .map(|(player_id, _)| *player_id)
+            .collect()
+    }
 
-        let mut n = seed;
-        for _ in 0..4 {
-            code.push(chars[(n % chars.len() as u128) as usize]);
-            n /= chars.len() as u128;
-        }
+    /// Handle of a running room, whichever worker routes its players
+    pub fn room(&self, room_code: &str) -> Option<RoomHandle> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(room_code).map(|entry| entry.handle.clone())
+    }
+
+    /// Listings of the public rooms
+    pub fn lobby(&self) -> &Lobby {
+        &self.lobby
+    }
+
+    /// Code of the room the player was routed to
+    pub fn get_player_room_code(&self, player_id: PlayerId) -> Option<&str> {
+        self.player_room.get(&player_id).map(|route| route.room_code.as_str())
+    }
+
+    /// Ask every room task for a summary. Rooms that close meanwhile are left out.
+    pub async fn describe_rooms(&self) -> Vec<RoomSummary> {
+        let handles: Vec<RoomHandle> = {
+            let rooms = self.rooms.lock().unwrap();
+            rooms.values().map(|entry| entry.handle.clone()).collect()
+        };
 
-        // If code already exists, try again
-        if self.rooms.contains_key(&code) {
-            return self.generate_room_code();
+        let mut summaries = Vec::with_capacity(handles.len());
+        for handle in handles {
+            let (reply, summary) = oneshot::channel();
+            if handle.send(RoomCommand::Describe { reply }).await
+                && let Ok(summary) = summary.await
+            {
+                summaries.push(summary);
+            }
         }
+        summaries
+    }
 
-        code
+    pub fn room_count(&self) -> usize {
+        self.rooms.lock().unwrap().len()
     }
+}
 
-    /// Get all players in a room (for broadcasting)
-    pub fn get_room_player_ids(&self, room_code: &str) -> Vec<PlayerId> {
-        self.rooms
-            .get(room_code)
-            .map(|r| r.get_player_ids())
-            .unwrap_or_default()
+/// Refuse a game mode longer than rooms accept, whether asked for directly or
+/// through the matchmaking queue
+fn check_game_mode(game_mode: &str) -> Result<(), RoomError> {
+    if game_mode.len() > MAX_GAME_MODE_LEN {
+        return Err(RoomError::InvalidSettings(format!(
+            "game_mode must be at most {} bytes",
+            MAX_GAME_MODE_LEN
+        )));
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+/// Generate a random 4-character room code not used by any of `rooms`
+fn generate_room_code(rooms: &HashMap<String, RoomEntry>) -> String {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let chars: Vec<char> = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789".chars().collect();
+    let mut code = String::with_capacity(4);
+
+    // Simple random based on time (not cryptographically secure, but fine for room codes)
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+
+    let mut n = seed;
+    for _ in 0..4 {
+        code.push(chars[(n % chars.len() as u128) as usize]);
+        n /= chars.len() as u128;
+    }
+
+    // If code already exists, try again
+    if rooms.contains_key(&code) {
+        return generate_room_code(rooms);
+    }
+
+    code
+}
@@ -0,0 +1,44 @@
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SALT_LEN: usize = 16;
+const DIGEST_LEN: usize = 32;
+
+/// A room password as stored by the room: an HMAC-SHA256 of it keyed by a
+/// random salt, so the plaintext is dropped once the room is created
+#[derive(Clone)]
+pub struct PasswordHash {
+    salt: [u8; SALT_LEN],
+    digest: [u8; DIGEST_LEN],
+}
+
+impl PasswordHash {
+    /// Hash `password` under a fresh salt from the OS CSPRNG
+    pub fn new(password: &str) -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        getrandom::fill(&mut salt).expect("OS random number generator unavailable");
+
+        let digest = mac(&salt, password).finalize().into_bytes().into();
+        Self { salt, digest }
+    }
+
+    /// Whether `password` is the one hashed, compared in constant time
+    pub fn verify(&self, password: &str) -> bool {
+        mac(&self.salt, password).verify_slice(&self.digest).is_ok()
+    }
+}
+
+impl std::fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PasswordHash(..)")
+    }
+}
+
+fn mac(salt: &[u8], password: &str) -> HmacSha256 {
+    let mut mac =
+        <HmacSha256 as KeyInit>::new_from_slice(salt).expect("HMAC accepts keys of any length");
+    mac.update(password.as_bytes());
+    mac
+}
@@ -0,0 +1,303 @@
+use crate::metrics;
+use crate::network::crypto::SessionCipher;
+use crate::network::mtu::PathMtu;
+use crate::network::reliable::{ACK_WINDOW, ReliableChannel, ReorderBuffer};
+use crate::network::stats::LinkStats;
+use crate::network::udp::{Outbox, UdpServer};
+use crate::protocol::client::GameMessage;
+use crate::protocol::common::DeliveryMode;
+use crate::protocol::server::{PlayerNetworkStats, ServerMessage, server_message};
+use crate::session::{PlayerId, SequenceCheck};
+use prost::Message;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A link shared between the dispatcher, which receives for the session, and
+/// the room task, which sends to it. The lock is never held across an await.
+pub type SharedLink = Arc<Mutex<Link>>;
+
+/// Transport state of one session: sequencing, acks, retransmission, path MTU,
+/// connection quality and encryption
+#[derive(Debug)]
+pub struct Link {
+    pub player_id: PlayerId,
+    /// Where the player currently is, updated in place when they reconnect
+    pub addr: SocketAddr,
+    pub last_recv_sequence: u32,
+    /// Which of the 32 sequences before `last_recv_sequence` were received
+    pub recv_ack_bits: u32,
+    pub send_sequence: u32,
+    pub reliable: ReliableChannel,
+    /// Reliable-ordered game messages from the client, held until in sequence
+    pub ordered_recv: ReorderBuffer<GameMessage>,
+    pub path_mtu: PathMtu,
+    pub stats: LinkStats,
+    /// Keys for sealing traffic, when the session negotiated encryption
+    pub cipher: Option<SessionCipher>,
+}
+
+impl Link {
+    pub fn new(player_id: PlayerId, addr: SocketAddr, cipher: Option<SessionCipher>) -> Self {
+        Self {
+            player_id,
+            addr,
+            last_recv_sequence: 0,
+            recv_ack_bits: 0,
+            send_sequence: 0,
+            reliable: ReliableChannel::new(),
+            ordered_recv: ReorderBuffer::new(),
+            path_mtu: PathMtu::new(),
+            stats: LinkStats::new(),
+            cipher,
+        }
+    }
+
+    pub fn shared(self) -> SharedLink {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Build an unreliable message stamped with the next sequence and our acks
+    pub fn next_message(&mut self, payload: server_message::Payload) -> ServerMessage {
+        self.send_sequence += 1;
+        self.stats.record_sent(self.send_sequence);
+        let payload = Some(payload);
+        metrics::global().record_sent(&payload);
+        ServerMessage {
+            payload,
+            sequence: self.send_sequence,
+            ack: self.last_recv_sequence,
+            ack_bits: self.recv_ack_bits,
+            reliable_sequence: 0,
+        }
+    }
+
+    /// Build a message honoring the requested delivery mode. Reliable modes are
+    /// tracked for retransmission, and only ordered ones consume a
+    /// `reliable_sequence`. Sequenced messages rely on the client dropping
+    /// anything older than the newest `sequence` it has seen.
+    pub fn next_message_with_delivery(
+        &mut self,
+        payload: server_message::Payload,
+        delivery: DeliveryMode,
+    ) -> ServerMessage {
+        let mut message = self.next_message(payload);
+
+        let (tracked, ordered) = match delivery {
+            DeliveryMode::Unreliable | DeliveryMode::UnreliableSequenced => (false, false),
+            DeliveryMode::ReliableUnordered => (true, false),
+            DeliveryMode::ReliableOrdered => (true, true),
+        };
+
+        if tracked {
+            if ordered {
+                message.reliable_sequence = self.reliable.next_reliable_sequence();
+            }
+            self.reliable.track(message.clone(), Instant::now());
+        }
+
+        message
+    }
+
+    /// Wrap `message` in a Sealed envelope if the session is encrypted
+    pub fn seal(&mut self, message: ServerMessage) -> ServerMessage {
+        seal_message(self.cipher.as_mut(), message)
+    }
+
+    /// Apply the acks piggybacked on a client message to the reliable channel
+    pub fn process_acks(&mut self, ack: u32, ack_bits: u32, now: Instant) {
+        self.reliable.acknowledge(ack, ack_bits, now);
+        self.stats.record_ack(ack, ack_bits);
+    }
+
+    /// Reliable messages whose retransmission timeout elapsed, sealed and ready to send
+    pub fn take_retransmissions(&mut self, now: Instant) -> Vec<ServerMessage> {
+        let (due, expired) =
+            self.reliable
+                .take_due(now, self.last_recv_sequence, self.recv_ack_bits);
+
+        if expired > 0 {
+            tracing::warn!(
+                "Gave up on {} reliable messages to player {}",
+                expired,
+                self.player_id
+            );
+        }
+
+        due.into_iter().map(|message| self.seal(message)).collect()
+    }
+
+    pub fn check_sequence(&mut self, incoming: u32) -> SequenceCheck {
+        if incoming == 0 {
+            return SequenceCheck::Valid;
+        }
+
+        let latest = self.last_recv_sequence;
+
+        if incoming > latest {
+            let advance = incoming - latest;
+            self.recv_ack_bits = if latest == 0 || advance > ACK_WINDOW {
+                0
+            } else {
+                self.recv_ack_bits.checked_shl(advance).unwrap_or(0) | (1 << (advance - 1))
+            };
+            self.last_recv_sequence = incoming;
+
+            self.stats.record_received();
+
+            if advance == 1 || latest == 0 {
+                SequenceCheck::Valid
+            } else {
+                let gap = advance - 1;
+                self.stats.record_lost(gap);
+                tracing::warn!(
+                    "Packet loss detected from player {}: {} packets missing",
+                    self.player_id,
+                    gap
+                );
+                SequenceCheck::Gap(gap)
+            }
+        } else {
+            let age = latest - incoming;
+            let bit = 1u32.checked_shl(age.wrapping_sub(1)).unwrap_or(0);
+
+            if age == 0 || age > ACK_WINDOW || self.recv_ack_bits & bit != 0 {
+                tracing::debug!(
+                    "Duplicate packet from player {} (seq={})",
+                    self.player_id,
+                    incoming
+                );
+                SequenceCheck::Duplicate
+            } else {
+                self.recv_ack_bits |= bit;
+                self.stats.record_recovered();
+                tracing::debug!(
+                    "Out of order packet from player {} (seq={}, latest={})",
+                    self.player_id,
+                    incoming,
+                    latest
+                );
+                SequenceCheck::OutOfOrder
+            }
+        }
+    }
+
+    /// Connection quality of this player, as reported to their room
+    pub fn network_stats(&self) -> PlayerNetworkStats {
+        let stats = &self.stats;
+
+        PlayerNetworkStats {
+            player_id: self.player_id,
+            rtt_ms: stats
+                .smoothed_rtt_ms()
+                .map(|rtt| rtt.round() as u32)
+                .unwrap_or(0),
+            rtt_variance_ms: stats.rtt_variance_ms().round() as u32,
+            jitter_ms: stats.jitter_ms().round() as u32,
+            inbound_loss_percent: stats.inbound_loss_percent(),
+            outbound_loss_percent: stats.outbound_loss_percent(),
+        }
+    }
+}
+
+pub(crate) fn seal_message(
+    cipher: Option<&mut SessionCipher>,
+    message: ServerMessage,
+) -> ServerMessage {
+    let Some(cipher) = cipher else {
+        return message;
+    };
+
+    let sealed = cipher.seal(message.sequence, &message.encode_to_vec());
+    ServerMessage {
+        payload: Some(server_message::Payload::Sealed(sealed)),
+        ..Default::default()
+    }
+}
+
+/// A payload encoded once for a whole room. Protobuf merges concatenated
+/// messages, so each recipient's datagram is their own sequence and ack header
+/// followed by these bytes, and the payload is never encoded twice.
+#[derive(Debug, Clone)]
+pub struct SharedBody {
+    payload: server_message::Payload,
+    encoded: Vec<u8>,
+}
+
+impl SharedBody {
+    pub fn new(payload: server_message::Payload) -> Self {
+        let mut encoded = Vec::with_capacity(payload.encoded_len());
+        payload.encode(&mut encoded);
+        Self { payload, encoded }
+    }
+}
+
+/// Queue a shared body over `link` using `delivery`. Only the header is encoded
+/// per recipient; reliable modes still track the message for retransmission,
+/// which for game payloads shares rather than copies the bytes.
+pub fn queue_shared(
+    server: &UdpServer,
+    outbox: &mut Outbox,
+    link: &SharedLink,
+    body: &SharedBody,
+    delivery: DeliveryMode,
+) {
+    let mut link = link.lock().unwrap();
+    let mut header = link.next_message_with_delivery(body.payload.clone(), delivery);
+    header.payload = None;
+
+    let mut datagram = outbox.buffer();
+    header
+        .encode(&mut datagram)
+        .expect("Vec<u8> grows to fit any message");
+    datagram.extend_from_slice(&body.encoded);
+
+    if let Some(cipher) = link.cipher.as_mut() {
+        let envelope = ServerMessage {
+            payload: Some(server_message::Payload::Sealed(
+                cipher.seal(header.sequence, &datagram),
+            )),
+            ..Default::default()
+        };
+        datagram.clear();
+        envelope
+            .encode(&mut datagram)
+            .expect("Vec<u8> grows to fit any message");
+    }
+
+    server.queue_encoded(outbox, datagram, link.addr, link.path_mtu.current());
+}
+
+/// Queue a message over `link` using `delivery`, to go out with the next flush
+pub fn queue_with_delivery(
+    server: &UdpServer,
+    outbox: &mut Outbox,
+    link: &SharedLink,
+    payload: server_message::Payload,
+    delivery: DeliveryMode,
+) {
+    let mut link = link.lock().unwrap();
+    let message = link.next_message_with_delivery(payload, delivery);
+    let message = link.seal(message);
+    server.queue_message(outbox, &message, link.addr, link.path_mtu.current());
+}
+
+/// Queue a lobby or lifecycle message on the reliable channel
+pub fn queue_reliable(
+    server: &UdpServer,
+    outbox: &mut Outbox,
+    link: &SharedLink,
+    payload: server_message::Payload,
+) {
+    queue_with_delivery(server, outbox, link, payload, DeliveryMode::ReliableOrdered);
+}
+
+/// Queue a message without retransmission
+pub fn queue_unreliable(
+    server: &UdpServer,
+    outbox: &mut Outbox,
+    link: &SharedLink,
+    payload: server_message::Payload,
+) {
+    queue_with_delivery(server, outbox, link, payload, DeliveryMode::Unreliable);
+}
@@ -1,7 +1,24 @@
-use crate::config::GRACE_PLAYER_TIME_SECONDS;
+pub mod link;
+pub mod token;
+
+use crate::config::{RateLimitConfig, SessionConfig};
+use crate::metrics;
+use crate::network::crypto::SessionCipher;
+use crate::network::fragment::DEFAULT_MTU;
+use crate::network::mtu::build_probe;
+use crate::network::ratelimit::{MessageClass, RateLimitVerdict, SessionRateLimits};
+use crate::protocol::client::Ping;
+use crate::protocol::common::DeliveryMode;
+use crate::protocol::server::{ServerMessage, server_message};
+use crate::room::lobby::Subscription;
+use link::{Link, SharedLink, seal_message};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::time::{Duration, Instant};
+use token::TokenSigner;
 
 pub type PlayerId = u32;
 
@@ -9,11 +26,14 @@ pub type PlayerId = u32;
 pub enum SequenceCheck {
     Valid,
     Gap(u32),
+    /// Older than the latest sequence but not seen before
+    OutOfOrder,
     Duplicate,
     Invalid,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ConnectionState {
     Connected,
     Disconnected,
@@ -31,9 +51,47 @@ pub struct Session {
     pub ping_count: u32,
     pub connection_state: ConnectionState,
     pub reconnect_token: String,
+    /// Generation encoded in `reconnect_token`, bumped each time it is used
+    pub reconnect_generation: u32,
     pub disconnected_at: Option<Instant>,
-    pub last_recv_sequence: u32,
-    pub send_sequence: u32,
+    pub rate_limits: SessionRateLimits,
+    /// Transport state, shared with the room task the player is in
+    pub link: SharedLink,
+    /// Room browser page pushed to the player as rooms change
+    pub room_browser: Option<Subscription>,
+}
+
+/// Number of sessions in each [`ConnectionState`]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SessionCounts {
+    pub connected: usize,
+    pub disconnected: usize,
+}
+
+/// Point in time view of a session, as listed by the admin API
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionSummary {
+    pub player_id: PlayerId,
+    pub player_name: String,
+    pub addr: SocketAddr,
+    pub room_code: Option<String>,
+    pub state: ConnectionState,
+    /// Smoothed round trip time of the link, or the last ping's if none yet
+    pub rtt_ms: Option<u32>,
+    /// Time since the player was last heard from
+    pub last_seen_ms: u64,
+}
+
+/// The cipher of a sealed handshake from an address without a session. It is
+/// kept, counter and all, for as long as the handshake could be used again, so
+/// replies to retried joins never reuse a nonce under the same key.
+struct StagedCipher {
+    /// Cookie and client public key the key was derived from
+    handshake: Vec<u8>,
+    /// None once a session took the cipher over; the handshake may not start
+    /// another
+    cipher: Option<SessionCipher>,
+    expires_at: Instant,
 }
 
 /// Manages all connected player sessions
@@ -42,25 +100,59 @@ pub struct SessionManager {
     sessions_by_addr: HashMap<SocketAddr, Session>,
     /// Map from player ID to socket address (for reverse lookup)
     addr_by_player_id: HashMap<PlayerId, SocketAddr>,
-    /// Map from player ID to token
-    token_to_player_id: HashMap<String, PlayerId>,
-    /// Next player ID to assign
-    next_player_id: PlayerId,
+    /// Signs reconnect tokens with the server secret, shared by every worker
+    token_signer: Arc<TokenSigner>,
+    /// Ciphers from sealed joins, taken up when the join creates or moves a session
+    staged_ciphers: HashMap<SocketAddr, StagedCipher>,
+    /// Next player ID to assign, shared so IDs are unique across workers
+    next_player_id: Arc<AtomicU32>,
     /// How long before a session is considered timed out
     timeout_duration: Duration,
     /// How long to wait before removing player
     grace_period: Duration,
+    /// Budgets given to each new session
+    rate_limits: RateLimitConfig,
 }
 
 impl SessionManager {
-    pub fn new(timeout_seconds: u64) -> Self {
+    pub fn new(config: &SessionConfig, rate_limits: RateLimitConfig) -> Self {
         Self {
             sessions_by_addr: HashMap::new(),
             addr_by_player_id: HashMap::new(),
-            token_to_player_id: HashMap::new(),
-            next_player_id: 1,
-            timeout_duration: Duration::from_secs(timeout_seconds),
-            grace_period: Duration::from_secs(GRACE_PLAYER_TIME_SECONDS as u64),
+            token_signer: Arc::new(TokenSigner::new(Duration::from_secs(
+                config.reconnect_token_lifetime_seconds,
+            ))),
+            staged_ciphers: HashMap::new(),
+            next_player_id: Arc::new(AtomicU32::new(1)),
+            timeout_duration: config.timeout(),
+            grace_period: config.grace_period(),
+            rate_limits,
+        }
+    }
+
+    /// Empty manager for another receive worker. It shares the token secret and
+    /// player IDs with this one, so sessions can move between the two.
+    pub fn sibling(&self) -> Self {
+        Self {
+            sessions_by_addr: HashMap::new(),
+            addr_by_player_id: HashMap::new(),
+            token_signer: self.token_signer.clone(),
+            staged_ciphers: HashMap::new(),
+            next_player_id: self.next_player_id.clone(),
+            timeout_duration: self.timeout_duration,
+            grace_period: self.grace_period,
+            rate_limits: self.rate_limits.clone(),
+        }
+    }
+
+    /// Apply reloaded timeouts and rate limits. Sessions already disconnected
+    /// are held to the new grace period.
+    pub fn reconfigure(&mut self, config: &SessionConfig, rate_limits: &RateLimitConfig) {
+        self.timeout_duration = config.timeout();
+        self.grace_period = config.grace_period();
+        self.rate_limits = rate_limits.clone();
+        for session in self.sessions_by_addr.values_mut() {
+            session.rate_limits.reconfigure(rate_limits);
         }
     }
 
@@ -71,12 +163,9 @@ impl SessionManager {
             return self.sessions_by_addr.get(&addr).unwrap();
         }
 
-        let player_id = self.next_player_id;
-
-        // TODO: Generate unique player ID
-        self.next_player_id += 1;
+        let player_id = self.next_player_id.fetch_add(1, Ordering::Relaxed);
 
-        let reconnect_token = Self::generate_reconnect_token();
+        let reconnect_token = self.token_signer.issue(player_id, 0);
         let session = Session {
             player_id,
             player_name,
@@ -87,25 +176,38 @@ impl SessionManager {
             latency_ms: None,
             ping_count: 0,
             connection_state: ConnectionState::Connected,
-            reconnect_token: reconnect_token.clone(),
+            reconnect_token,
+            reconnect_generation: 0,
             disconnected_at: None,
-            last_recv_sequence: 0,
-            send_sequence: 0,
+            rate_limits: SessionRateLimits::new(&self.rate_limits),
+            link: Link::new(player_id, addr, self.take_staged_cipher(&addr)).shared(),
+            room_browser: None,
         };
 
         self.sessions_by_addr.insert(addr, session);
         self.addr_by_player_id.insert(player_id, addr);
-        self.token_to_player_id.insert(reconnect_token, player_id);
 
         tracing::info!("New player registered: id={}, addr={}", player_id, addr);
 
         self.sessions_by_addr.get(&addr).unwrap()
     }
-    pub fn ping(&mut self, addr: &SocketAddr) {
+    /// Record a ping received at `now_ms`, updating jitter and, when the
+    /// client echoed our last pong, the round trip time
+    pub fn ping(&mut self, addr: &SocketAddr, ping: &Ping, now_ms: u64) {
         if let Some(session) = self.sessions_by_addr.get_mut(addr) {
             session.last_ping = Some(Instant::now());
             session.last_seen = Instant::now();
             session.ping_count += 1;
+
+            let mut link = session.link.lock().unwrap();
+            link.stats.record_ping_transit(ping.timestamp, now_ms);
+
+            if ping.pong_server_time != 0 && ping.pong_server_time <= now_ms {
+                let rtt_ms =
+                    (now_ms - ping.pong_server_time).saturating_sub(ping.pong_delay_ms as u64);
+                link.stats.record_rtt(rtt_ms as f32);
+                session.latency_ms = link.stats.smoothed_rtt_ms().map(|rtt| rtt.round() as u32);
+            }
         }
     }
 
@@ -117,48 +219,275 @@ impl SessionManager {
 
     pub fn next_send_sequence(&mut self, addr: &SocketAddr) -> u32 {
         if let Some(session) = self.sessions_by_addr.get_mut(addr) {
-            session.send_sequence += 1;
-            session.send_sequence
+            let mut link = session.link.lock().unwrap();
+            link.send_sequence += 1;
+            link.send_sequence
         } else {
             tracing::warn!("Failed to get session by address. Returning 0 as sequence");
             0
         }
     }
 
-    pub fn check_sequence(&mut self, addr: &SocketAddr, incoming: u32) -> SequenceCheck {
-        if incoming == 0 {
-            return SequenceCheck::Valid;
+    /// Build an unreliable message for `addr`, stamped with the next sequence and our acks
+    pub fn next_message(
+        &mut self,
+        addr: &SocketAddr,
+        payload: server_message::Payload,
+    ) -> ServerMessage {
+        self.next_message_with_delivery(addr, payload, DeliveryMode::Unreliable)
+    }
+
+    /// Build a message for `addr` on the reliable channel. It is retransmitted
+    /// until the client acks it.
+    pub fn next_reliable_message(
+        &mut self,
+        addr: &SocketAddr,
+        payload: server_message::Payload,
+    ) -> ServerMessage {
+        self.next_message_with_delivery(addr, payload, DeliveryMode::ReliableOrdered)
+    }
+
+    /// Build a message for `addr` honoring the requested delivery mode
+    pub fn next_message_with_delivery(
+        &mut self,
+        addr: &SocketAddr,
+        payload: server_message::Payload,
+        delivery: DeliveryMode,
+    ) -> ServerMessage {
+        let Some(session) = self.sessions_by_addr.get(addr) else {
+            tracing::debug!("No session for {addr}, sending unsequenced message");
+            let payload = Some(payload);
+            metrics::global().record_sent(&payload);
+            return ServerMessage {
+                payload,
+                ..Default::default()
+            };
+        };
+
+        session
+            .link
+            .lock()
+            .unwrap()
+            .next_message_with_delivery(payload, delivery)
+    }
+
+    /// Apply the acks piggybacked on a client message to the reliable channel
+    pub fn process_acks(&mut self, addr: &SocketAddr, ack: u32, ack_bits: u32) {
+        if let Some(session) = self.sessions_by_addr.get(addr) {
+            let mut link = session.link.lock().unwrap();
+            link.process_acks(ack, ack_bits, Instant::now());
         }
+    }
 
-        if let Some(session) = self.sessions_by_addr.get_mut(addr) {
-            let expected = session.last_recv_sequence.wrapping_add(1);
-
-            if incoming == expected {
-                session.last_recv_sequence = incoming;
-                SequenceCheck::Valid
-            } else if incoming > expected {
-                let gap = incoming - expected;
-                session.last_recv_sequence = incoming;
+    /// Reliable messages whose retransmission timeout elapsed, for connected sessions,
+    /// along with the datagram size to send them with
+    pub fn collect_retransmissions(&mut self) -> Vec<(SocketAddr, usize, ServerMessage)> {
+        let now = Instant::now();
+        let mut retransmissions = Vec::new();
+
+        for (addr, session) in self.sessions_by_addr.iter() {
+            if session.connection_state != ConnectionState::Connected {
+                continue;
+            }
+
+            let mut link = session.link.lock().unwrap();
+            let mtu = link.path_mtu.current();
+            for message in link.take_retransmissions(now) {
+                retransmissions.push((*addr, mtu, message));
+            }
+        }
+
+        retransmissions
+    }
+
+    pub fn counts(&self) -> SessionCounts {
+        let connected = self
+            .sessions_by_addr
+            .values()
+            .filter(|session| session.connection_state == ConnectionState::Connected)
+            .count();
+        SessionCounts {
+            connected,
+            disconnected: self.sessions_by_addr.len() - connected,
+        }
+    }
+
+    /// Summaries of every session, connected or within its grace period
+    pub fn summaries(&self) -> Vec<SessionSummary> {
+        self.sessions_by_addr
+            .values()
+            .map(|session| {
+                let link_rtt = session.link.lock().unwrap().stats.smoothed_rtt_ms();
+                SessionSummary {
+                    player_id: session.player_id,
+                    player_name: session.player_name.clone(),
+                    addr: session.addr,
+                    room_code: session.room_code.clone(),
+                    state: session.connection_state.clone(),
+                    rtt_ms: link_rtt
+                        .map(|rtt| rtt.round() as u32)
+                        .or(session.latency_ms),
+                    last_seen_ms: session.last_seen.elapsed().as_millis() as u64,
+                }
+            })
+            .collect()
+    }
+
+    /// Addresses of every connected session
+    pub fn connected_addrs(&self) -> Vec<SocketAddr> {
+        self.sessions_by_addr
+            .iter()
+            .filter(|(_, session)| session.connection_state == ConnectionState::Connected)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Connected sessions subscribed to the room browser
+    pub fn room_browsers(&mut self) -> impl Iterator<Item = &mut Session> {
+        self.sessions_by_addr.values_mut().filter(|session| {
+            session.connection_state == ConnectionState::Connected && session.room_browser.is_some()
+        })
+    }
+
+    /// Charge a message of `class` from `addr` to its session's budget
+    pub fn rate_limit(&mut self, addr: &SocketAddr, class: MessageClass) -> RateLimitVerdict {
+        match self.sessions_by_addr.get_mut(addr) {
+            Some(session) => session.rate_limits.check(class, Instant::now()),
+            None => RateLimitVerdict::Allow,
+        }
+    }
+
+    /// Hold the cipher negotiated by a sealed join from `addr` until the join
+    /// registers or reconnects a session there. `handshake` identifies the
+    /// cookie and client key it was derived from, and is valid for `lifetime`.
+    /// A handshake already staged keeps its cipher; one a session took over is
+    /// refused, and false returned.
+    pub fn stage_cipher(
+        &mut self,
+        addr: SocketAddr,
+        handshake: Vec<u8>,
+        cipher: SessionCipher,
+        lifetime: Duration,
+    ) -> bool {
+        if let Some(staged) = self.staged_ciphers.get(&addr)
+            && staged.handshake == handshake
+        {
+            return staged.cipher.is_some();
+        }
+
+        self.staged_ciphers.insert(
+            addr,
+            StagedCipher {
+                handshake,
+                cipher: Some(cipher),
+                expires_at: Instant::now() + lifetime,
+            },
+        );
+        true
+    }
+
+    /// Forget staged handshakes that can no longer be used
+    pub fn expire_staged_ciphers(&mut self) {
+        let now = Instant::now();
+        self.staged_ciphers
+            .retain(|_, staged| staged.expires_at > now);
+    }
+
+    /// Hand the cipher staged for `addr` to the session created there,
+    /// remembering the handshake as used
+    fn take_staged_cipher(&mut self, addr: &SocketAddr) -> Option<SessionCipher> {
+        self.staged_ciphers
+            .get_mut(addr)
+            .and_then(|staged| staged.cipher.take())
+    }
+
+    /// Wrap `message` in a Sealed envelope if the session at `addr` is encrypted.
+    /// Replies to a sealed join that created no session use its staged cipher.
+    pub fn seal(&mut self, addr: &SocketAddr, message: ServerMessage) -> ServerMessage {
+        match self.sessions_by_addr.get(addr) {
+            Some(session) => session.link.lock().unwrap().seal(message),
+            None => seal_message(
+                self.staged_ciphers
+                    .get_mut(addr)
+                    .and_then(|staged| staged.cipher.as_mut()),
+                message,
+            ),
+        }
+    }
+
+    /// Datagram size to use when sending to `addr`
+    pub fn mtu(&self, addr: &SocketAddr) -> usize {
+        self.sessions_by_addr
+            .get(addr)
+            .map(|s| s.link.lock().unwrap().path_mtu.current())
+            .unwrap_or(DEFAULT_MTU)
+    }
+
+    /// Path MTU probes that are due, for connected sessions
+    pub fn collect_mtu_probes(&mut self) -> Vec<(SocketAddr, ServerMessage)> {
+        let now = Instant::now();
+        let mut probes = Vec::new();
+
+        for (addr, session) in self.sessions_by_addr.iter() {
+            if session.connection_state != ConnectionState::Connected {
+                continue;
+            }
+
+            let mut link = session.link.lock().unwrap();
+            let before = link.path_mtu.current();
+            let probe = link.path_mtu.poll_probe(now);
+            let after = link.path_mtu.current();
+
+            if after < before {
                 tracing::warn!(
-                    "Packet loss detected from player {}: {} packets missing",
-                    session.player_id,
-                    gap
-                );
-                SequenceCheck::Gap(gap)
-            } else {
-                tracing::debug!(
-                    "Duplicate packet from player {} (seq={})",
+                    "Path MTU for player {} lowered to {} bytes: larger probes are being dropped",
                     session.player_id,
-                    incoming
+                    after
                 );
-                SequenceCheck::Duplicate
             }
+
+            if let Some((probe_id, size)) = probe {
+                probes.push((*addr, build_probe(probe_id, size)));
+            }
+        }
+
+        probes
+    }
+
+    pub fn acknowledge_mtu_probe(&mut self, addr: &SocketAddr, probe_id: u32) {
+        if let Some(session) = self.sessions_by_addr.get(addr)
+            && let Some(mtu) = session
+                .link
+                .lock()
+                .unwrap()
+                .path_mtu
+                .acknowledge(probe_id, Instant::now())
+        {
+            tracing::info!(
+                "Path MTU for player {} is now {} bytes",
+                session.player_id,
+                mtu
+            );
+        }
+    }
+
+    pub fn check_sequence(&mut self, addr: &SocketAddr, incoming: u32) -> SequenceCheck {
+        if incoming == 0 {
+            return SequenceCheck::Valid;
+        }
+
+        if let Some(session) = self.sessions_by_addr.get(addr) {
+            session.link.lock().unwrap().check_sequence(incoming)
         } else {
             tracing::warn!("Failed to get session by address. Sequence check is invalid");
             SequenceCheck::Invalid
         }
     }
 
+    pub fn link(&self, addr: &SocketAddr) -> Option<SharedLink> {
+        self.sessions_by_addr.get(addr).map(|s| s.link.clone())
+    }
+
     pub fn get_by_addr(&self, addr: &SocketAddr) -> Option<&Session> {
         self.sessions_by_addr.get(addr)
     }
@@ -226,18 +555,39 @@ impl SessionManager {
         None
     }
 
+    /// Move the token's disconnected session to `new_addr`. A different
+    /// session already registered there, say from a ListRooms sent before
+    /// reconnecting, is removed and returned alongside so its room, match and
+    /// subscription can be cleaned up.
     pub fn reconnected_by_token(
         &mut self,
         token: &str,
         new_addr: SocketAddr,
         player_name: String,
-    ) -> Option<&Session> {
-        let player_id = *self.token_to_player_id.get(token)?;
+    ) -> Option<(&Session, Option<Session>)> {
+        let claims = match self.token_signer.verify(token) {
+            Ok(claims) => claims,
+            Err(e) => {
+                tracing::warn!("Reconnect rejected from {new_addr}: {e:?} token");
+                return None;
+            }
+        };
+        let player_id = claims.player_id;
         let old_addr = *self.addr_by_player_id.get(&player_id)?;
 
         {
             let session = self.sessions_by_addr.get(&old_addr)?;
 
+            // Only the latest token is accepted, so a token works exactly once
+            if session.reconnect_generation != claims.generation || session.reconnect_token != token
+            {
+                tracing::warn!(
+                    "Reconnect rejected for player {}: token already used or superseded",
+                    player_id
+                );
+                return None;
+            }
+
             if session.connection_state != ConnectionState::Disconnected {
                 tracing::debug!(
                     "Reconnect rejected for player {}: not in disconnected state",
@@ -246,31 +596,84 @@ impl SessionManager {
                 return None;
             }
 
-            if let Some(disconnected_at) = session.disconnected_at {
-                if disconnected_at.elapsed() > self.grace_period {
-                    tracing::info!(
-                        "Reconnect rejected for player {}: grace period expired",
-                        player_id
-                    );
-                    return None;
-                }
+            if let Some(disconnected_at) = session.disconnected_at
+                && disconnected_at.elapsed() > self.grace_period
+            {
+                tracing::info!(
+                    "Reconnect rejected for player {}: grace period expired",
+                    player_id
+                );
+                return None;
             }
         }
 
         let mut session = self.sessions_by_addr.remove(&old_addr).unwrap();
+        let displaced = if old_addr != new_addr {
+            self.remove_player(&new_addr)
+        } else {
+            None
+        };
 
         session.addr = new_addr;
         session.player_name = player_name;
         session.connection_state = ConnectionState::Connected;
         session.disconnected_at = None;
         session.last_seen = Instant::now();
+        {
+            let mut link = session.link.lock().unwrap();
+            link.addr = new_addr;
+            // Keys the reconnect was sealed with: those of a fresh handshake,
+            // else those of the session it displaces at new_addr, else the
+            // session's own when the client reconnects from where it already was
+            if let Some(cipher) = self.take_staged_cipher(&new_addr).or_else(|| {
+                displaced
+                    .as_ref()
+                    .and_then(|d| d.link.lock().unwrap().cipher.take())
+            }) {
+                link.cipher = Some(cipher);
+            }
+        }
+        session.reconnect_generation += 1;
+        session.reconnect_token = self
+            .token_signer
+            .issue(player_id, session.reconnect_generation);
 
         self.addr_by_player_id.insert(player_id, new_addr);
         self.sessions_by_addr.insert(new_addr, session);
 
         tracing::info!("Player {player_id} reconnected from new address {new_addr}");
 
-        self.sessions_by_addr.get(&new_addr)
+        self.sessions_by_addr
+            .get(&new_addr)
+            .map(|session| (session, displaced))
+    }
+
+    /// Player a validly signed reconnect token was issued to. Whether it is
+    /// still the latest token is only checked by the manager holding the session.
+    pub fn token_player(&self, token: &str) -> Option<PlayerId> {
+        self.token_signer
+            .verify(token)
+            .ok()
+            .map(|claims| claims.player_id)
+    }
+
+    /// Give up a disconnected session so the worker its player reconnected
+    /// through can take it over
+    pub fn release(&mut self, player_id: PlayerId) -> Option<Session> {
+        let addr = *self.addr_by_player_id.get(&player_id)?;
+        if self.sessions_by_addr.get(&addr)?.connection_state != ConnectionState::Disconnected {
+            return None;
+        }
+
+        self.addr_by_player_id.remove(&player_id);
+        self.sessions_by_addr.remove(&addr)
+    }
+
+    /// Take over a session released by another worker
+    pub fn adopt(&mut self, session: Session) {
+        self.addr_by_player_id
+            .insert(session.player_id, session.addr);
+        self.sessions_by_addr.insert(session.addr, session);
     }
 
     pub fn grace_period_seconds(&self) -> u32 {
@@ -284,10 +687,10 @@ impl SessionManager {
             .sessions_by_addr
             .iter()
             .filter(|(_, session)| {
-                if session.connection_state == ConnectionState::Disconnected {
-                    if let Some(disconnected_at) = session.disconnected_at {
-                        return now.duration_since(disconnected_at) > self.grace_period;
-                    }
+                if session.connection_state == ConnectionState::Disconnected
+                    && let Some(disconnected_at) = session.disconnected_at
+                {
+                    return now.duration_since(disconnected_at) > self.grace_period;
                 }
                 false
             })
@@ -309,7 +712,6 @@ impl SessionManager {
     pub fn remove_player(&mut self, addr: &SocketAddr) -> Option<Session> {
         if let Some(session) = self.sessions_by_addr.remove(addr) {
             self.addr_by_player_id.remove(&session.player_id);
-            self.token_to_player_id.remove(&session.reconnect_token);
             tracing::info!(
                 "Player disconnected: id={}, addr={}",
                 session.player_id,
@@ -321,22 +723,4 @@ impl SessionManager {
             None
         }
     }
-
-    // TODO:
-    // 1. Move to helper file
-    // 2. This is not a safe implementation. Have to be cryptographically secure
-    pub fn generate_reconnect_token() -> String {
-        use std::time::{SystemTime, UNIX_EPOCH};
-
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-
-        format!(
-            "{:x}{:x}",
-            timestamp,
-            timestamp.wrapping_mul(6364136223846793005)
-        )
-    }
 }
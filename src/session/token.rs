@@ -0,0 +1,106 @@
+use crate::session::PlayerId;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SECRET_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 32;
+/// player id (4) + expiry (8) + generation (4) + nonce
+const PAYLOAD_LEN: usize = 4 + 8 + 4 + NONCE_LEN;
+
+/// What a valid reconnect token vouches for
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenClaims {
+    pub player_id: PlayerId,
+    /// Unix time in seconds after which the token is refused
+    pub expires_at: u64,
+    /// Bumped on every reconnect so older tokens stop working
+    pub generation: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+/// Issues and verifies HMAC-SHA256 signed reconnect tokens
+pub struct TokenSigner {
+    secret: [u8; SECRET_LEN],
+    lifetime: Duration,
+}
+
+impl TokenSigner {
+    /// Signer with a fresh secret from the OS CSPRNG
+    pub fn new(lifetime: Duration) -> Self {
+        let mut secret = [0u8; SECRET_LEN];
+        getrandom::fill(&mut secret).expect("OS random number generator unavailable");
+        Self::with_secret(secret, lifetime)
+    }
+
+    pub fn with_secret(secret: [u8; SECRET_LEN], lifetime: Duration) -> Self {
+        Self { secret, lifetime }
+    }
+
+    /// Issue a token for `player_id` at `generation`, expiring after the signer's lifetime
+    pub fn issue(&self, player_id: PlayerId, generation: u32) -> String {
+        let expires_at = unix_seconds() + self.lifetime.as_secs();
+
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::fill(&mut nonce).expect("OS random number generator unavailable");
+
+        let mut token = Vec::with_capacity(PAYLOAD_LEN + TAG_LEN);
+        token.extend_from_slice(&player_id.to_be_bytes());
+        token.extend_from_slice(&expires_at.to_be_bytes());
+        token.extend_from_slice(&generation.to_be_bytes());
+        token.extend_from_slice(&nonce);
+
+        let tag = self.mac(&token).finalize().into_bytes();
+        token.extend_from_slice(&tag);
+
+        hex::encode(token)
+    }
+
+    /// Check the signature and expiry of a token and return its claims
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, TokenError> {
+        let bytes = hex::decode(token).map_err(|_| TokenError::Malformed)?;
+        if bytes.len() != PAYLOAD_LEN + TAG_LEN {
+            return Err(TokenError::Malformed);
+        }
+
+        let (payload, tag) = bytes.split_at(PAYLOAD_LEN);
+        self.mac(payload)
+            .verify_slice(tag)
+            .map_err(|_| TokenError::BadSignature)?;
+
+        let claims = TokenClaims {
+            player_id: PlayerId::from_be_bytes(payload[0..4].try_into().unwrap()),
+            expires_at: u64::from_be_bytes(payload[4..12].try_into().unwrap()),
+            generation: u32::from_be_bytes(payload[12..16].try_into().unwrap()),
+        };
+
+        if unix_seconds() > claims.expires_at {
+            return Err(TokenError::Expired);
+        }
+
+        Ok(claims)
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as KeyInit>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        mac.update(payload);
+        mac
+    }
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
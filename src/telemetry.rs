@@ -0,0 +1,95 @@
+//! Log output and span export, set up once at startup. The log filter can be
+//! swapped while running; the output format and exporter need a restart.
+
+use crate::config::{LogFormat, ServerConfig};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{EnvFilter, Layer, Registry, fmt, reload};
+
+/// Replaces the log filter of the running subscriber
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// The installed subscriber's parts that outlive setup
+pub struct Telemetry {
+    pub log_filter: LogFilterHandle,
+    #[cfg(feature = "otel")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+/// Install the global subscriber: the log filter, stdout in the configured
+/// format and, if an endpoint is set, an OTLP exporter of the spans the filter
+/// lets through
+pub fn init(config: &ServerConfig) -> std::io::Result<Telemetry> {
+    let (filter, log_filter) = reload::Layer::new(EnvFilter::new(&config.log_filter));
+    let output = match config.log_format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().flatten_event(true).boxed(),
+    };
+    let subscriber = tracing_subscriber::registry().with(filter).with(output);
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider;
+
+        let tracer_provider = otlp::tracer_provider(&config.telemetry)?;
+        let exporter = tracer_provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("rust-server"))
+        });
+        subscriber.with(exporter).init();
+
+        Ok(Telemetry {
+            log_filter,
+            tracer_provider,
+        })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        subscriber.init();
+        Ok(Telemetry { log_filter })
+    }
+}
+
+impl Telemetry {
+    /// Export the spans still buffered. Call before the process exits.
+    pub fn shutdown(&self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = &self.tracer_provider
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to flush exported spans: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otlp {
+    use crate::config::TelemetryConfig;
+    use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+
+    /// Provider batching spans to the configured endpoint, if there is one
+    pub fn tracer_provider(config: &TelemetryConfig) -> std::io::Result<Option<SdkTracerProvider>> {
+        if config.otlp_endpoint.is_empty() {
+            return Ok(None);
+        }
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .with_endpoint(&config.otlp_endpoint)
+            .build()
+            .map_err(std::io::Error::other)?;
+
+        let resource = Resource::builder_empty()
+            .with_service_name(config.service_name.clone())
+            .build();
+
+        Ok(Some(
+            SdkTracerProvider::builder()
+                .with_resource(resource)
+                .with_batch_exporter(exporter)
+                .build(),
+        ))
+    }
+}